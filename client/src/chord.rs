@@ -0,0 +1,111 @@
+/// 组合键, 按键码与 deal_with_events 中发送的 fltk 按键码(取低8位)一致
+pub const CTRL: u8 = 227;
+pub const ALT: u8 = 233;
+pub const SHIFT: u8 = 225;
+pub const META: u8 = 231;
+pub const TAB: u8 = 9;
+pub const ESC: u8 = 27;
+pub const DELETE: u8 = 255;
+pub const F1: u8 = 190;
+
+/// 预定义的组合键 (菜单名, 按键)
+pub const PRESETS: &[(&str, &[u8])] = &[
+    ("Ctrl+Alt+Del", &[CTRL, ALT, DELETE]),
+    ("Alt+Tab", &[ALT, TAB]),
+    ("Alt+Shift+Tab", &[ALT, SHIFT, TAB]),
+    ("Alt+F4", &[ALT, F1 + 3]),
+    ("Win", &[META]),
+    ("Win+D", &[META, b'd']),
+    ("Win+L", &[META, b'l']),
+    ("Win+R", &[META, b'r']),
+    ("Ctrl+Esc", &[CTRL, ESC]),
+    ("Ctrl+Shift+Esc", &[CTRL, SHIFT, ESC]),
+];
+
+/// 按键名转换为按键码, 不区分大小写
+fn key_code(name: &str) -> Option<u8> {
+    let name = name.trim().to_ascii_lowercase();
+    let code = match name.as_str() {
+        "ctrl" | "control" => CTRL,
+        "alt" => ALT,
+        "shift" => SHIFT,
+        "win" | "super" | "meta" | "cmd" => META,
+        "tab" => TAB,
+        "esc" | "escape" => ESC,
+        "del" | "delete" => DELETE,
+        "enter" | "return" => 13,
+        "space" => 32,
+        "backspace" => 8,
+        "capslock" => 229,
+        "home" => 80,
+        "end" => 87,
+        "pageup" => 85,
+        "pagedown" => 86,
+        "left" => 81,
+        "up" => 82,
+        "right" => 83,
+        "down" => 84,
+        f if f.len() >= 2 && f.starts_with('f') => {
+            let n = f[1..].parse::<u8>().ok()?;
+            if !(1..=12).contains(&n) {
+                return None;
+            }
+            F1 + n - 1
+        }
+        c if c.len() == 1 => {
+            let c = c.as_bytes()[0];
+            match c {
+                b'a'..=b'z' | b'0'..=b'9' => c,
+                b'`' | b'-' | b'=' | b'[' | b']' | b'\\' | b';' | b'\'' | b',' | b'.' | b'/' => c,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// 解析用户自定义的组合键, 例如 "Ctrl+Shift+Esc"
+pub fn parse(chord: &str) -> Option<Vec<u8>> {
+    let mut keys = Vec::new();
+    // "+" 本身不能作为按键, 所以可以直接切分
+    for name in chord.split('+') {
+        let code = key_code(name)?;
+        if !keys.contains(&code) {
+            keys.push(code);
+        }
+    }
+    if keys.is_empty() || keys.len() > communication::MAX_COMBO_KEYS {
+        return None;
+    }
+    Some(keys)
+}
+
+/// 编码组合键指令: KEY_COMBO n k1 .. kn
+pub fn encode(keys: &[u8]) -> Vec<u8> {
+    let mut cmd = Vec::with_capacity(keys.len() + 2);
+    cmd.push(communication::KEY_COMBO);
+    cmd.push(keys.len() as u8);
+    cmd.extend_from_slice(keys);
+    cmd
+}
+
+#[test]
+fn test() {
+    assert_eq!(parse("Ctrl+Alt+Del"), Some(vec![CTRL, ALT, DELETE]));
+    assert_eq!(parse(" win + d "), Some(vec![META, b'd']));
+    assert_eq!(parse("Alt+F4"), Some(vec![ALT, 193]));
+    assert_eq!(parse("Ctrl+Ctrl+c"), Some(vec![CTRL, b'c']));
+    assert_eq!(parse("Ctrl+F13"), None);
+    assert_eq!(parse("Ctrl+Hyper"), None);
+    assert_eq!(parse(""), None);
+    assert_eq!(parse("a+b+c+d+e+f+g+h+i"), None);
+    for (name, keys) in PRESETS {
+        assert_eq!(parse(name).as_deref(), Some(*keys));
+    }
+
+    assert_eq!(
+        encode(&[ALT, TAB]),
+        vec![communication::KEY_COMBO, 2, ALT, TAB]
+    );
+}
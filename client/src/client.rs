@@ -1,32 +1,83 @@
-use flate2::write::DeflateDecoder;
+use communication::clipboard;
+use communication::clipboard::ClipboardSync;
+use communication::clipboard::Policy;
+use communication::clipboard::Side;
+use communication::clipboard::SystemClipboard;
+use communication::convert::ColorSpace;
+use communication::convert::Matrix;
+use communication::convert::PixelFormat;
+use communication::convert::Range;
+use communication::cursor::CursorPos;
+use communication::cursor::CursorShape;
+use communication::decode::Decoded;
+use communication::decode::Decoder;
+use communication::display;
+use communication::display::DisplayInfo;
+use communication::file::FileMsg;
+use communication::frame;
+use communication::heartbeat;
+use communication::mux;
+use communication::mux::MuxReader;
+use communication::mux::MuxSender;
+use communication::record;
+use communication::snapshot;
 use fltk::button::Button;
+use fltk::dialog;
 use fltk::draw;
 use fltk::enums::Color;
+use fltk::enums::Shortcut;
 use fltk::frame::Frame;
 use fltk::input::Input;
 use fltk::input::SecretInput;
+use fltk::menu::MenuBar;
+use fltk::menu::MenuFlag;
 use fltk::prelude::InputExt;
+use fltk::prelude::MenuExt;
+use fltk::prelude::WindowExt;
 use fltk::window::Window;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::Hasher;
-use std::io::Read;
-use std::io::Result;
-use std::io::Write;
-use std::net::TcpStream;
+use std::io;
+use std::net::Shutdown;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
 
 use fltk::app;
 use fltk::enums;
 use fltk::enums::Event;
+use fltk::enums::Key;
 use fltk::image;
 use fltk::prelude::GroupExt;
 use fltk::prelude::ImageExt;
 use fltk::prelude::WidgetBase;
 use fltk::prelude::WidgetExt;
-use rayon::prelude::*;
 
 use crate::bitmap;
+use crate::chord;
+use crate::cursor::RemoteCursor;
+use crate::error::Error;
+use crate::error::Result;
+use crate::files;
+use crate::files::RemoteBrowser;
+use crate::grab;
+use crate::session;
+use crate::session::Conn;
+use crate::transfer::Transfers;
+
+// 菜单栏高度
+const MENU_HEIGHT: i32 = 25;
+// 剪贴板轮询间隔
+const CLIPBOARD_INTERVAL: Duration = Duration::from_millis(500);
+// 登录时的连接尝试次数
+pub const LOGIN_ATTEMPTS: u32 = 3;
+// 断线后的重连尝试次数
+const RECONNECT_ATTEMPTS: u32 = 10;
+// 适应窗口时, 窗口大小稳定这么久后才请求新的画面尺寸
+const RESIZE_DELAY: Duration = Duration::from_millis(300);
 
 /// client的主控制函数，绘制窗口
 pub fn run() {
@@ -53,83 +104,228 @@ pub fn run() {
     // 按下登陆键
     login_btn.set_callback(move |_| {
         wind.hide();
-        log_in_and_run(host_ipt.value(), pwd_ipt.value());
+        // 出错时回到登录窗口, 保留已输入的内容
+        if let Err(e) = log_in_and_run(host_ipt.value(), pwd_ipt.value()) {
+            dialog::alert_default(&e.to_string());
+            wind.show();
+        }
     });
-    app.run().unwrap();
+    if let Err(e) = app.run() {
+        eprintln!("{}", e);
+    }
 }
 
-enum Msg {
+pub enum Msg {
     Draw,
+    // 远程目录列表
+    Files(FileMsg),
+    // 远程显示器列表
+    Displays(Vec<DisplayInfo>),
+    // 连接断开且无法恢复
+    Closed(Error),
+    // 截图保存的位置或失败的原因
+    Screenshot(io::Result<PathBuf>),
+}
+
+/// drop 时标记连接已断开
+struct AliveGuard(Arc<AtomicBool>);
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
 }
 
-// 解包
-#[inline]
-fn depack(buffer: &[u8]) -> usize {
-    ((buffer[0] as usize) << 16) | ((buffer[1] as usize) << 8) | (buffer[2] as usize)
+/// 解码后的远程画面, 分辨率变化时重新分配
+struct RemoteScreen {
+    w: i32,
+    h: i32,
+    rgb: Vec<u8>,
+}
+
+impl RemoteScreen {
+    /// 尺寸由server发来, 在 usize 中计算长度, 溢出时返回 None
+    fn new(w: i32, h: i32) -> Option<Self> {
+        let len = usize::try_from(w)
+            .ok()?
+            .checked_mul(usize::try_from(h).ok()?)?
+            .checked_mul(3)?;
+        Some(RemoteScreen {
+            w,
+            h,
+            rgb: vec![0u8; len],
+        })
+    }
 }
 
-/// 运行客户端
-fn log_in_and_run(host: String, pwd: String) {
-    // 与服务器建立链接
-    let mut conn = TcpStream::connect(host).unwrap();
-    let _ = validate_password(&mut conn, &pwd);
+/// 运行客户端, 窗口关闭时返回, 连接失败时返回错误
+fn log_in_and_run(host: String, pwd: String) -> Result<()> {
+    // 与服务器建立链接, server短暂不可达时重试
+    let session = session::connect_with_backoff(&host, &pwd, None, LOGIN_ATTEMPTS, |_, _| {})?;
 
     // 开始绘制wind2窗口
     let (sw, sh) = app::screen_size();
+    let (ww, wh) = ((sw / 2.0) as i32, (sh / 2.0) as i32);
     let mut wind_screen = Window::default()
-        .with_size((sw / 2.0) as i32, (sh / 2.0) as i32)
+        .with_size(ww, wh + MENU_HEIGHT)
         .with_label("简易版远程控制");
-    let mut frame = Frame::default().size_of(&wind_screen);
-    wind_screen.make_resizable(true);
+    let mut menu = MenuBar::new(0, 0, ww, MENU_HEIGHT, None);
+    let mut frame = Frame::new(0, MENU_HEIGHT, ww, wh, None);
+    wind_screen.resizable(&frame);
     wind_screen.end();
     wind_screen.show();
 
-    // 接收meta信息
-    let mut meta = [0u8; 4];
-    if let Err(_) = conn.read_exact(&mut meta) {
-        return;
-    }
-    let w = (((meta[0] as u16) << 8) | meta[1] as u16) as i32;
-    let h = (((meta[2] as u16) << 8) | meta[3] as u16) as i32;
+    let screen = RemoteScreen::new(session.w, session.h)
+        .ok_or_else(|| Error::Protocol(format!("bad screen size {}x{}", session.w, session.h)))?;
+    let work_buf = Arc::new(RwLock::new(screen));
+    let draw_work_buf = work_buf.clone();
 
-    let dlen = (w * h * 3) as usize;
+    let (tx, rx) = app::channel::<Msg>();
 
-    let work_buf = Arc::new(RwLock::new(vec![0u8; dlen]));
-    let draw_work_buf = work_buf.clone();
+    // 之后所有发送给server的消息都通过 mux 发送
+    // 重连后替换为新连接的 mux
+    // 设置了录像目录时记录收到的画面和发送的键鼠指令
+    let shared_conn = Conn::new(
+        MuxSender::start(session.stream.try_clone()?),
+        record::start("client", |s| println!("{}", s)),
+    );
+    let recv_conn = shared_conn.clone();
+    build_key_menu(&mut menu, &wind_screen, shared_conn.clone());
 
-    let conn_clone = conn.try_clone().unwrap();
-    deal_with_events(w, h, &mut frame, conn_clone);
+    // 文件传输
+    let _transfer_str = Arc::new(RwLock::new(String::new()));
+    let _transfer_strc = _transfer_str.clone();
+    let transfers = Transfers::start(shared_conn.clone(), tx, _transfer_str);
+    let recv_transfers = transfers.clone();
+    let mut browser = RemoteBrowser::new(shared_conn.clone(), transfers.clone());
+    build_file_menu(&mut menu, browser.clone(), transfers);
+    deal_with_events(
+        work_buf.clone(),
+        &mut frame,
+        shared_conn.clone(),
+        browser.clone(),
+    );
+
+    // 剪贴板同步
+    let clipboard = Arc::new(Mutex::new(ClipboardSync::new(
+        SystemClipboard::new(),
+        Side::Client,
+        Policy::Both,
+        clipboard::DEFAULT_MAX_SIZE,
+    )));
+    build_clipboard_menu(&mut menu, shared_conn.clone(), clipboard.clone());
+    // 选择的显示器, None 为server默认的主显示器
+    let selected: Arc<Mutex<Option<u8>>> = Arc::new(Mutex::new(None));
+    let recv_selected = selected.clone();
+    // 请求的画面尺寸, None 为原始分辨率; fit 为适应窗口
+    let resolution: Arc<Mutex<Option<(u16, u16)>>> = Arc::new(Mutex::new(None));
+    let recv_resolution = resolution.clone();
+    let fit = Arc::new(AtomicBool::new(false));
+    // 按需请求画面, 每解码完一帧再请求下一帧
+    let pull = Arc::new(AtomicBool::new(false));
+    let recv_pull = pull.clone();
+    build_quality_menu(
+        &mut menu,
+        shared_conn.clone(),
+        resolution.clone(),
+        fit.clone(),
+    );
+    build_frame_mode_menu(&mut menu, shared_conn.clone(), pull);
+    // 请求的颜色空间, None 为server默认
+    let color: Arc<Mutex<Option<ColorSpace>>> = Arc::new(Mutex::new(None));
+    let recv_color = color.clone();
+    build_color_menu(&mut menu, shared_conn.clone(), color);
+    // 无损的 RGB 画面
+    let lossless = Arc::new(AtomicBool::new(false));
+    let recv_lossless = lossless.clone();
+    build_lossless_menu(&mut menu, shared_conn.clone(), lossless);
+    build_screenshot_menu(&mut menu, shared_conn.clone(), work_buf.clone(), tx);
+    // 画面出错时手动请求关键帧
+    let refresh_conn = shared_conn.clone();
+    menu.add("Refresh", Shortcut::None, MenuFlag::Normal, move |_| {
+        let _ = refresh_conn.send(mux::CONTROL, &[communication::REFRESH]);
+    });
+    let recv_clipboard = clipboard.clone();
+    let alive = Arc::new(AtomicBool::new(true));
+    let recv_alive = alive.clone();
+    let ping_alive = alive.clone();
+    let timeout = heartbeat::timeout();
 
     let _tool_str = Arc::new(RwLock::new(String::new()));
     let _tool_strc = _tool_str.clone();
+    // 断线重连的提示, 为空时不显示
+    let _conn_str = Arc::new(RwLock::new(String::new()));
+    let _conn_strc = _conn_str.clone();
+    let remote_cursor = Arc::new(RwLock::new(RemoteCursor::default()));
+    let draw_cursor = remote_cursor.clone();
 
     // 重画
     frame.draw(move |frame| {
         if let Ok(_buf) = draw_work_buf.read() {
+            let (w, h) = (_buf.w, _buf.h);
             unsafe {
                 if let Ok(mut image) =
-                    image::RgbImage::from_data2(&_buf, w, h, enums::ColorDepth::Rgb8 as i32, 0)
+                    image::RgbImage::from_data2(&_buf.rgb, w, h, enums::ColorDepth::Rgb8 as i32, 0)
                 {
                     image.scale(frame.width(), frame.height(), false, true);
                     image.draw(frame.x(), frame.y(), frame.width(), frame.height());
+                    if let Ok(c) = draw_cursor.read() {
+                        c.draw(frame.x(), frame.y(), frame.width(), frame.height(), w, h);
+                    }
                     draw::set_color_rgb(0, 0, 0);
                     if let Ok(a) = _tool_strc.read() {
-                        draw::draw_text(&a, frame.x() + frame.width() - 180, 20);
+                        draw::draw_text(&a, frame.x() + frame.width() - 260, frame.y() + 20);
+                    }
+                    if let Ok(a) = _transfer_strc.read() {
+                        draw::draw_text(&a, frame.x() + 10, frame.y() + frame.height() - 10);
+                    }
+                    if let Ok(a) = _conn_strc.read() {
+                        if !a.is_empty() {
+                            draw::draw_text2(
+                                &a,
+                                frame.x(),
+                                frame.y(),
+                                frame.width(),
+                                frame.height(),
+                                enums::Align::Center,
+                            );
+                        }
                     }
                 }
             }
         }
     });
 
-    let (tx, rx) = app::channel::<Msg>();
+    // 本地剪贴板变化时发送给server
+    let clipboard_conn = shared_conn.clone();
+    std::thread::spawn(move || {
+        while alive.load(Ordering::Relaxed) {
+            std::thread::sleep(CLIPBOARD_INTERVAL);
+            let data = clipboard.lock().unwrap().poll();
+            if let Some(data) = data {
+                // 断线期间的变化被丢弃
+                let _ = clipboard_conn.send(mux::CLIPBOARD, &data);
+            }
+        }
+    });
 
-    // 用来接收图像信息，并通知主线程重画
+    // 定时发送 PING, 用于测量延迟, 也让server知道连接正常
+    let ping_conn = shared_conn.clone();
     std::thread::spawn(move || {
-        let u = (w * h) as usize;
-        let v = u + u / 4;
-        let mut yuv = Vec::<u8>::new();
-        let mut _yuv = Vec::<u8>::new();
-        let mut buf = Vec::<u8>::new();
+        while ping_alive.load(Ordering::Relaxed) {
+            std::thread::sleep(heartbeat::INTERVAL);
+            // 断线期间发送失败, 重连后继续
+            let _ = ping_conn.send(mux::CONTROL, &heartbeat::ping(heartbeat::now()));
+        }
+    });
+
+    // 用来接收server消息，并通知主线程重画
+    std::thread::spawn(move || {
+        let _guard = AliveGuard(recv_alive);
+        let (mut w, mut h) = (session.w, session.h);
+        let max_frame = frame::max_frame();
+        // 颜色空间, 像素格式和尺寸由server在 VIDEO_DISPLAY 中告知
+        let mut decoder = Decoder::new(w as usize, h as usize, max_frame);
 
         // FPS
         let mut last = std::time::Instant::now();
@@ -138,143 +334,710 @@ fn log_in_and_run(host: String, pwd: String) {
         // 流速
         let mut _length_all = 0usize;
         let mut _length_sum = 0usize;
-        // 接收第一帧数据
-        let mut header = [0u8; 3];
-        if let Err(_) = conn.read_exact(&mut header) {
-            return;
-        }
-        let recv_len = depack(&header);
-        _length_sum += recv_len;
-
-        if buf.capacity() < recv_len {
-            buf.resize(recv_len, 0u8);
-        }
-        if let Err(e) = conn.read_exact(&mut buf) {
-            println!("error {}", e);
-            return;
-        }
-        unsafe {
-            yuv.set_len(0);
-        }
-        let mut d = DeflateDecoder::new(yuv);
-        d.write_all(&buf).unwrap();
-        yuv = d.reset(Vec::new()).unwrap();
-
-        if let Ok(mut _buf) = work_buf.write() {
-            communication::convert::i420_to_rgb(
-                w as usize,
-                h as usize,
-                &yuv[..u],
-                &yuv[u..v],
-                &yuv[v..],
-                &mut _buf,
-            );
-        }
-        (_yuv, yuv) = (yuv, _yuv);
-        tx.send(Msg::Draw);
-
+        // 往返时间
+        let mut rtt = Duration::ZERO;
+        let mut stream = session.stream;
+        let mut token = session.token;
         loop {
-            if let Err(_) = conn.read_exact(&mut header) {
-                return;
-            }
-            let recv_len = depack(&header);
-            _length_sum += recv_len;
+            // server每次收到 PING 都会回复, 超时说明连接已断开
+            let _ = stream.set_read_timeout(Some(timeout));
+            let reader_stream = match stream.try_clone() {
+                Ok(reader_stream) => reader_stream,
+                Err(e) => {
+                    tx.send(Msg::Closed(e.into()));
+                    return;
+                }
+            };
+            let max_message = mux::MAX_MESSAGE.max(frame::max_message(max_frame));
+            let mut reader = MuxReader::new(reader_stream, max_message);
+            // 连接断开时结束
+            while let Ok((channel, buf)) = reader.recv() {
+                _length_sum += buf.len();
+                if channel == mux::VIDEO {
+                    recv_conn.record(channel, &buf);
+                }
+                match channel {
+                    mux::VIDEO => match buf.first() {
+                        // 图像帧, 在下面解码
+                        Some(&communication::VIDEO_FRAME) => {}
+                        // 请求的完整画面, 在另一个线程保存, 不阻塞接收
+                        Some(&communication::VIDEO_SNAPSHOT) => {
+                            let data = buf.to_vec();
+                            std::thread::spawn(move || {
+                                tx.send(Msg::Screenshot(save_snapshot(&data, max_frame)));
+                            });
+                            continue;
+                        }
+                        Some(&communication::VIDEO_DISPLAY) => {
+                            // 之后是新尺寸的完整一帧, 解码器拒绝的尺寸不分配画面
+                            if !decoder.display(&buf) {
+                                break;
+                            }
+                            let size = decoder.size();
+                            (w, h) = (size.0 as i32, size.1 as i32);
+                            let new_screen = match RemoteScreen::new(w, h) {
+                                Some(screen) => screen,
+                                None => break,
+                            };
+                            if let Ok(mut screen) = work_buf.write() {
+                                *screen = new_screen;
+                            }
+                            tx.send(Msg::Draw);
+                            continue;
+                        }
+                        _ => continue,
+                    },
+                    mux::CONTROL => {
+                        // server结束了会话, 不再重连
+                        if let Some((&communication::SESSION_END, reason)) = buf.split_first() {
+                            let reason = String::from_utf8_lossy(reason).into_owned();
+                            tx.send(Msg::Closed(Error::Remote(reason)));
+                            return;
+                        }
+                        if let Some((communication::PONG, timestamp)) = heartbeat::decode(&buf) {
+                            rtt = heartbeat::rtt(timestamp);
+                            if let Ok(mut a) = _tool_str.write() {
+                                *a = stats_text(fps, _length_all, rtt);
+                            }
+                            tx.send(Msg::Draw);
+                        }
+                        if let Some((&communication::DISPLAY_LIST, data)) = buf.split_first() {
+                            if let Some(displays) = display::decode_list(data) {
+                                tx.send(Msg::Displays(displays));
+                            }
+                        }
+                        continue;
+                    }
+                    mux::CURSOR => {
+                        match buf.split_first() {
+                            Some((&communication::CURSOR_SHAPE, data)) => {
+                                if let (Some(shape), Ok(mut c)) =
+                                    (CursorShape::decode(data), remote_cursor.write())
+                                {
+                                    c.set_shape(shape);
+                                }
+                            }
+                            Some((&communication::CURSOR_POS, data)) => {
+                                if let (Some(pos), Ok(mut c)) =
+                                    (CursorPos::decode(data), remote_cursor.write())
+                                {
+                                    if c.set_pos(pos) {
+                                        tx.send(Msg::Draw);
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    mux::CLIPBOARD => {
+                        recv_clipboard.lock().unwrap().apply(&buf);
+                        continue;
+                    }
+                    mux::FILE => {
+                        match FileMsg::decode(&buf) {
+                            // 目录列表交给浏览窗口
+                            Some(msg @ (FileMsg::Roots { .. } | FileMsg::DirList { .. })) => {
+                                tx.send(Msg::Files(msg));
+                            }
+                            Some(FileMsg::Error { id: 0, message }) => {
+                                tx.send(Msg::Files(FileMsg::Error { id: 0, message }));
+                            }
+                            Some(msg) => recv_transfers.remote(msg),
+                            None => {}
+                        }
+                        continue;
+                    }
+                    // 忽略未知消息
+                    _ => continue,
+                }
+                // 数据损坏或长度与当前画面不符时重连, 新会话从完整的一帧开始
+                match decoder.decode(&buf) {
+                    Some(Decoded::Frame) => {}
+                    Some(Decoded::Skipped) => {
+                        next_frame(&recv_conn, &recv_pull);
+                        continue;
+                    }
+                    // 丢帧或与server的画面不一致, 请求关键帧重新同步
+                    Some(Decoded::Refresh) => {
+                        let _ = recv_conn.send(mux::CONTROL, &[communication::REFRESH]);
+                        next_frame(&recv_conn, &recv_pull);
+                        continue;
+                    }
+                    None => break,
+                }
 
-            if buf.capacity() < recv_len {
-                buf.resize(recv_len, 0u8);
-            } else {
-                unsafe {
-                    buf.set_len(recv_len);
+                if let Ok(mut _buf) = work_buf.write() {
+                    decoder.to_rgb(&mut _buf.rgb);
                 }
+                next_frame(&recv_conn, &recv_pull);
+                {
+                    let cur = std::time::Instant::now();
+                    let dur = cur.duration_since(last);
+                    fpscount += 1;
+                    if dur.as_millis() >= 1000 {
+                        last = cur;
+                        _length_all = _length_sum;
+                        if let Ok(mut a) = _tool_str.write() {
+                            *a = stats_text(fps, _length_all, rtt);
+                        }
+                        fps = fpscount;
+                        fpscount = 0;
+                        _length_sum = 0;
+                    }
+                }
+                tx.send(Msg::Draw);
             }
-            if let Err(_) = conn.read_exact(&mut buf) {
-                return;
-            }
-            unsafe {
-                yuv.set_len(0);
-            }
-            d.write_all(&buf).unwrap();
-            yuv = d.reset(yuv).unwrap();
 
-            yuv.par_iter_mut().zip(_yuv.par_iter()).for_each(|(a, b)| {
-                *a = *b ^ *a;
+            // 关闭旧连接, 唤醒阻塞在旧 mux 上的发送方
+            let _ = stream.shutdown(Shutdown::Both);
+            let set_status = |status: String| {
+                if let Ok(mut a) = _conn_str.write() {
+                    *a = status;
+                }
+                tx.send(Msg::Draw);
+            };
+            set_status("Reconnecting...".to_string());
+            let res = session::connect_with_backoff(
+                &host,
+                &pwd,
+                Some(token),
+                RECONNECT_ATTEMPTS,
+                |attempt, delay| {
+                    set_status(format!(
+                        "Reconnecting... (retry {} in {:.1}s)",
+                        attempt,
+                        delay.as_secs_f32()
+                    ));
+                },
+            )
+            .and_then(|next| {
+                let sender = MuxSender::start(next.stream.try_clone()?);
+                Ok((next, sender))
             });
+            let (next, sender) = match res {
+                Ok(res) => res,
+                Err(e) => {
+                    tx.send(Msg::Closed(e));
+                    return;
+                }
+            };
+            token = next.token;
+            recv_conn.replace(sender);
 
-            if let Ok(mut _buf) = work_buf.write() {
-                communication::convert::i420_to_rgb(
-                    w as usize,
-                    h as usize,
-                    &yuv[..u],
-                    &yuv[u..v],
-                    &yuv[v..],
-                    &mut _buf,
-                );
-            }
-            (_yuv, yuv) = (yuv, _yuv);
-            {
-                let cur = std::time::Instant::now();
-                let dur = cur.duration_since(last);
-                fpscount += 1;
-                if dur.as_millis() >= 1000 {
-                    last = cur;
-                    _length_all = _length_sum;
-                    if let Ok(mut a) = _tool_str.write() {
-                        *a = format!("FPS:{:2} | Rate:{:>6}KB/s", fps, _length_all / 1024);
+            // 新会话的第一帧是完整的, 上一帧重置为全0, 分辨率可能已经改变
+            if (next.w, next.h) != (w, h) {
+                (w, h) = (next.w, next.h);
+                let new_screen = match RemoteScreen::new(w, h) {
+                    Some(screen) => screen,
+                    None => {
+                        let size = format!("bad screen size {}x{}", w, h);
+                        tx.send(Msg::Closed(Error::Protocol(size)));
+                        return;
                     }
-                    fps = fpscount;
-                    fpscount = 0;
-                    _length_sum = 0;
+                };
+                if let Ok(mut screen) = work_buf.write() {
+                    *screen = new_screen;
                 }
             }
-            tx.send(Msg::Draw);
+            decoder.reset(w as usize, h as usize);
+            stream = next.stream;
+            // 恢复会话状态
+            let policy = recv_clipboard.lock().unwrap().policy();
+            let _ = recv_conn.send(
+                mux::CONTROL,
+                &[communication::CLIPBOARD_POLICY, policy as u8],
+            );
+            if let Some(n) = *recv_selected.lock().unwrap() {
+                let _ = recv_conn.send(mux::CONTROL, &[communication::SELECT_DISPLAY, n]);
+            }
+            if let Some((w, h)) = *recv_resolution.lock().unwrap() {
+                let _ = recv_conn.send(mux::CONTROL, &resolution_msg(w, h));
+            }
+            if let Some(space) = *recv_color.lock().unwrap() {
+                let _ = recv_conn.send(
+                    mux::CONTROL,
+                    &[communication::SET_COLOR_SPACE, space.to_u8()],
+                );
+            }
+            if recv_lossless.load(Ordering::Relaxed) {
+                request_format(&recv_conn, true);
+            }
+            if recv_pull.load(Ordering::Relaxed) {
+                request_frames(&recv_conn, true);
+            }
+            recv_transfers.resume();
+            set_status(String::new());
         }
     });
 
+    // 窗口大小和它开始保持不变的时间
+    let mut seen = ((frame.width(), frame.height()), Instant::now());
+
     // 主线程不断重画
     while app::wait() {
+        // 适应窗口时, 等拖动结束再请求, 避免反复发送完整的一帧
+        if fit.load(Ordering::Relaxed) {
+            let size = (frame.width(), frame.height());
+            if size != seen.0 {
+                seen = (size, Instant::now());
+            } else if seen.1.elapsed() >= RESIZE_DELAY {
+                let size = (size.0.max(2) as u16, size.1.max(2) as u16);
+                let mut resolution = resolution.lock().unwrap();
+                if *resolution != Some(size) {
+                    *resolution = Some(size);
+                    let _ = shared_conn.send(mux::CONTROL, &resolution_msg(size.0, size.1));
+                }
+            }
+        }
         match rx.recv() {
             Some(Msg::Draw) => {
                 frame.redraw();
             }
+            Some(Msg::Files(msg)) => {
+                browser.update(msg);
+            }
+            Some(Msg::Displays(displays)) => {
+                build_display_menu(&mut menu, &shared_conn, &displays, &selected);
+            }
+            Some(Msg::Screenshot(Ok(path))) => {
+                dialog::message_default(&format!("Screenshot saved to {}", path.display()));
+            }
+            Some(Msg::Screenshot(Err(e))) => {
+                dialog::alert_default(&format!("Failed to save screenshot: {}", e));
+            }
+            Some(Msg::Closed(e)) => {
+                // 关闭会话的窗口, 回到登录窗口
+                browser.close();
+                Window::delete(wind_screen);
+                return Err(e);
+            }
             _ => {}
         }
     }
+    Ok(())
 }
 
-/// 发送密码，进行验证
-fn validate_password(conn: &mut TcpStream, pwd: &str) -> Result<()> {
-    // 验证密码
-    let mut hasher = DefaultHasher::new();
-    hasher.write(pwd.as_bytes());
-    let pk = hasher.finish();
-    conn.write_all(&pk.to_be_bytes()).unwrap();
-    let mut verify_result: [u8; 1] = [0];
-
-    //获取server的信息
-    conn.read_exact(&mut verify_result).unwrap();
-
-    // 对结果验证
-    if verify_result[0] != 1 {
-        if verify_result[0] == 2 {
-            panic!("Password error !");
+/// 右上角的统计信息
+fn stats_text(fps: u8, rate: usize, rtt: Duration) -> String {
+    format!(
+        "FPS:{:2} | Rate:{:>6}KB/s | RTT:{:>4}ms",
+        fps,
+        rate / 1024,
+        rtt.as_millis()
+    )
+}
+
+/// 组合键菜单
+/// 系统快捷键会被本地系统拦截, 通过菜单原子地发送给server
+fn build_key_menu(menu: &mut MenuBar, wind: &Window, conn: Conn) {
+    for (name, keys) in chord::PRESETS {
+        let conn = conn.clone();
+        menu.add(
+            &format!("Keys/{}", name),
+            Shortcut::None,
+            MenuFlag::Normal,
+            move |_| {
+                let _ = conn.send(mux::INPUT, &chord::encode(keys));
+            },
+        );
+    }
+
+    // 自定义组合键, 输入后加入菜单方便再次使用
+    let custom_conn = conn.clone();
+    menu.add(
+        "Keys/Custom...",
+        Shortcut::None,
+        MenuFlag::MenuDivider,
+        move |m| {
+            let input = match dialog::input_default("Key chord, e.g. Ctrl+Shift+Esc", "") {
+                Some(input) => input,
+                None => return,
+            };
+            let keys = match chord::parse(&input) {
+                Some(keys) => keys,
+                None => {
+                    dialog::alert_default(&format!("Unknown key chord: {}", input));
+                    return;
+                }
+            };
+            let _ = custom_conn.send(mux::INPUT, &chord::encode(&keys));
+            let conn = custom_conn.clone();
+            m.add(
+                &format!("Keys/{}", input.trim()),
+                Shortcut::None,
+                MenuFlag::Normal,
+                move |_| {
+                    let _ = conn.send(mux::INPUT, &chord::encode(&keys));
+                },
+            );
+        },
+    );
+
+    // 键盘捕获, 窗口在前台时转发系统快捷键
+    let hwnd = wind.raw_handle();
+    menu.add(
+        "Keys/Keyboard grab",
+        Shortcut::None,
+        MenuFlag::Toggle,
+        move |m| {
+            let mut item = match m.find_item("Keys/Keyboard grab") {
+                Some(item) => item,
+                None => return,
+            };
+            let enable = item.value();
+            if !grab::set_grab(enable, hwnd, &conn) && enable {
+                item.clear();
+                dialog::alert_default("Keyboard grab is not supported on this platform");
+            }
+        },
+    );
+}
+
+/// 剪贴板菜单, 选择同步方向并通知server
+fn build_clipboard_menu(
+    menu: &mut MenuBar,
+    conn: Conn,
+    clipboard: Arc<Mutex<ClipboardSync<SystemClipboard>>>,
+) {
+    let policies = [
+        ("Clipboard/Off", Policy::Off),
+        ("Clipboard/To remote", Policy::ToRemote),
+        ("Clipboard/From remote", Policy::FromRemote),
+        ("Clipboard/Both", Policy::Both),
+    ];
+    let current = clipboard.lock().unwrap().policy();
+    let _ = conn.send(
+        mux::CONTROL,
+        &[communication::CLIPBOARD_POLICY, current as u8],
+    );
+    for (name, policy) in policies {
+        let conn = conn.clone();
+        let clipboard = clipboard.clone();
+        let flag = if policy == current {
+            MenuFlag::Radio | MenuFlag::Value
         } else {
-            panic!("Some error !");
-        }
+            MenuFlag::Radio
+        };
+        menu.add(name, Shortcut::None, flag, move |_| {
+            clipboard.lock().unwrap().set_policy(policy);
+            let _ = conn.send(
+                mux::CONTROL,
+                &[communication::CLIPBOARD_POLICY, policy as u8],
+            );
+        });
     }
-    Ok(())
+}
+
+/// 显示器菜单, 显示器列表变化时重建
+fn build_display_menu(
+    menu: &mut MenuBar,
+    conn: &Conn,
+    displays: &[DisplayInfo],
+    selected: &Arc<Mutex<Option<u8>>>,
+) {
+    let idx = menu.find_index("Display");
+    if idx >= 0 {
+        let _ = menu.clear_submenu(idx);
+    }
+    // 选择的显示器已被移除时, server回到主显示器
+    let current = match *selected.lock().unwrap() {
+        Some(n) if n == display::ALL || (n as usize) < displays.len() => n,
+        _ => displays.iter().position(|d| d.primary).unwrap_or(0) as u8,
+    };
+    let items = displays
+        .iter()
+        .enumerate()
+        .map(|(i, d)| {
+            let primary = if d.primary { " (primary)" } else { "" };
+            (
+                format!("Display/{}: {}x{}{}", i + 1, d.w, d.h, primary),
+                i as u8,
+            )
+        })
+        .chain(std::iter::once((
+            "Display/All displays".to_string(),
+            display::ALL,
+        )));
+    for (name, n) in items {
+        let conn = conn.clone();
+        let selected = selected.clone();
+        let flag = if n == current {
+            MenuFlag::Radio | MenuFlag::Value
+        } else {
+            MenuFlag::Radio
+        };
+        menu.add(&name, Shortcut::None, flag, move |_| {
+            *selected.lock().unwrap() = Some(n);
+            let _ = conn.send(mux::CONTROL, &[communication::SELECT_DISPLAY, n]);
+        });
+    }
+}
+
+/// 画面尺寸菜单, 请求server缩小画面以节省带宽
+fn build_quality_menu(
+    menu: &mut MenuBar,
+    conn: Conn,
+    resolution: Arc<Mutex<Option<(u16, u16)>>>,
+    fit: Arc<AtomicBool>,
+) {
+    // None 为适应窗口, 由主循环在窗口大小变化时请求
+    let choices = [
+        ("Quality/Native", Some((0, 0))),
+        ("Quality/Fit window", None),
+        ("Quality/1920x1080", Some((1920, 1080))),
+        ("Quality/1280x720", Some((1280, 720))),
+    ];
+    for (name, size) in choices {
+        let conn = conn.clone();
+        let resolution = resolution.clone();
+        let fit = fit.clone();
+        let flag = if size == Some((0, 0)) {
+            MenuFlag::Radio | MenuFlag::Value
+        } else {
+            MenuFlag::Radio
+        };
+        menu.add(name, Shortcut::None, flag, move |_| {
+            fit.store(size.is_none(), Ordering::Relaxed);
+            if let Some((w, h)) = size {
+                *resolution.lock().unwrap() = Some((w, h));
+                let _ = conn.send(mux::CONTROL, &resolution_msg(w, h));
+            }
+        });
+    }
+}
+
+/// 截图菜单, 当前画面直接保存, 完整画面向server请求, 收到后保存
+fn build_screenshot_menu(
+    menu: &mut MenuBar,
+    conn: Conn,
+    screen: Arc<RwLock<RemoteScreen>>,
+    tx: app::Sender<Msg>,
+) {
+    menu.add(
+        "Screenshot/Current frame",
+        Shortcut::Ctrl | Shortcut::Alt | 's',
+        MenuFlag::Normal,
+        move |_| {
+            // 在菜单回调中只复制画面, 编码 PNG 交给其他线程, 不阻塞界面
+            let (w, h, rgb) = {
+                let screen = screen.read().unwrap();
+                (screen.w as usize, screen.h as usize, screen.rgb.clone())
+            };
+            std::thread::spawn(move || {
+                tx.send(Msg::Screenshot(save_screenshot(w, h, &rgb)));
+            });
+        },
+    );
+    menu.add(
+        "Screenshot/Full quality from server",
+        Shortcut::None,
+        MenuFlag::Normal,
+        move |_| {
+            let _ = conn.send(mux::CONTROL, &[communication::SNAPSHOT]);
+        },
+    );
+}
+
+/// 把当前画面按收到的分辨率保存为 PNG, 不受窗口缩放的影响
+fn save_screenshot(w: usize, h: usize, rgb: &[u8]) -> io::Result<PathBuf> {
+    let path = snapshot::path();
+    snapshot::save_png(&path, w, h, rgb)?;
+    Ok(path)
+}
+
+/// 保存server发来的完整画面
+fn save_snapshot(msg: &[u8], max_frame: usize) -> io::Result<PathBuf> {
+    let (w, h, rgb) = snapshot::decode(msg, max_frame)
+        .ok_or_else(|| io::Error::other(Error::Snapshot.to_string()))?;
+    save_screenshot(w, h, &rgb)
+}
+
+/// 画面发送方式菜单
+fn build_frame_mode_menu(menu: &mut MenuBar, conn: Conn, pull: Arc<AtomicBool>) {
+    menu.add(
+        "Quality/On-demand frames",
+        Shortcut::None,
+        MenuFlag::Toggle,
+        move |m| {
+            let enable = match m.find_item("Quality/On-demand frames") {
+                Some(item) => item.value(),
+                None => return,
+            };
+            pull.store(enable, Ordering::Relaxed);
+            request_frames(&conn, enable);
+        },
+    );
+}
+
+/// 颜色空间菜单, server切换后从完整的一帧开始
+fn build_color_menu(menu: &mut MenuBar, conn: Conn, color: Arc<Mutex<Option<ColorSpace>>>) {
+    let choices = [
+        (
+            "Quality/Color/BT.601 limited",
+            Matrix::Bt601,
+            Range::Limited,
+        ),
+        ("Quality/Color/BT.601 full", Matrix::Bt601, Range::Full),
+        (
+            "Quality/Color/BT.709 limited",
+            Matrix::Bt709,
+            Range::Limited,
+        ),
+        ("Quality/Color/BT.709 full", Matrix::Bt709, Range::Full),
+    ];
+    for (name, matrix, range) in choices {
+        let conn = conn.clone();
+        let color = color.clone();
+        let space = ColorSpace { matrix, range };
+        let flag = if space == ColorSpace::default() {
+            MenuFlag::Radio | MenuFlag::Value
+        } else {
+            MenuFlag::Radio
+        };
+        menu.add(name, Shortcut::None, flag, move |_| {
+            *color.lock().unwrap() = Some(space);
+            let _ = conn.send(
+                mux::CONTROL,
+                &[communication::SET_COLOR_SPACE, space.to_u8()],
+            );
+        });
+    }
+}
+
+/// 无损画面菜单, 文字和代码更清晰, 但需要更多带宽
+fn build_lossless_menu(menu: &mut MenuBar, conn: Conn, lossless: Arc<AtomicBool>) {
+    menu.add(
+        "Quality/Lossless (RGB)",
+        Shortcut::None,
+        MenuFlag::Toggle,
+        move |m| {
+            let enable = match m.find_item("Quality/Lossless (RGB)") {
+                Some(item) => item.value(),
+                None => return,
+            };
+            lossless.store(enable, Ordering::Relaxed);
+            request_format(&conn, enable);
+        },
+    );
+}
+
+/// 请求像素格式: SET_PIXEL_FORMAT format
+fn request_format(conn: &Conn, lossless: bool) {
+    let format = if lossless {
+        PixelFormat::Rgb
+    } else {
+        PixelFormat::I420
+    };
+    let _ = conn.send(
+        mux::CONTROL,
+        &[communication::SET_PIXEL_FORMAT, format as u8],
+    );
+}
+
+/// 切换发送方式, 按需发送时先请求第一帧
+fn request_frames(conn: &Conn, pull: bool) {
+    if pull {
+        let _ = conn.send(
+            mux::CONTROL,
+            &[communication::FRAME_MODE, communication::FRAME_PULL],
+        );
+        let _ = conn.send(mux::CONTROL, &[communication::FRAME_REQUEST]);
+    } else {
+        let _ = conn.send(
+            mux::CONTROL,
+            &[communication::FRAME_MODE, communication::FRAME_PUSH],
+        );
+    }
+}
+
+/// 按需发送时, 处理完一帧后请求下一帧
+fn next_frame(conn: &Conn, pull: &AtomicBool) {
+    if pull.load(Ordering::Relaxed) {
+        let _ = conn.send(mux::CONTROL, &[communication::FRAME_REQUEST]);
+    }
+}
+
+/// 请求画面尺寸: SET_RESOLUTION w h
+fn resolution_msg(w: u16, h: u16) -> Vec<u8> {
+    let mut msg = vec![communication::SET_RESOLUTION];
+    msg.extend_from_slice(&w.to_be_bytes());
+    msg.extend_from_slice(&h.to_be_bytes());
+    msg
+}
+
+/// 文件菜单
+fn build_file_menu(menu: &mut MenuBar, browser: RemoteBrowser, transfers: Transfers) {
+    let mut remote = browser.clone();
+    menu.add(
+        "Files/Browse remote...",
+        Shortcut::None,
+        MenuFlag::Normal,
+        move |_| remote.show(),
+    );
+    menu.add(
+        "Files/Upload...",
+        Shortcut::None,
+        MenuFlag::Normal,
+        move |_| {
+            let mut chooser =
+                dialog::NativeFileChooser::new(dialog::NativeFileChooserType::BrowseMultiFile);
+            chooser.set_title("Upload");
+            chooser.show();
+            browser.upload(chooser.filenames());
+        },
+    );
+    menu.add(
+        "Files/Cancel transfers",
+        Shortcut::None,
+        MenuFlag::Normal,
+        move |_| transfers.cancel_all(),
+    );
+}
+
+/// 把鼠标在frame中的坐标映射为远程屏幕坐标
+/// 窗口最小化时frame的大小为 0, 返回 None, 丢弃这个事件
+fn to_remote(screen: &RwLock<RemoteScreen>, f: &Frame) -> Option<(u16, u16)> {
+    let (fw, fh) = (f.width(), f.height());
+    if fw <= 0 || fh <= 0 {
+        return None;
+    }
+    let (w, h) = match screen.read() {
+        Ok(screen) => (screen.w, screen.h),
+        Err(_) => return Some((0, 0)),
+    };
+    let x = (app::event_x() - f.x()).max(0).min(fw - 1);
+    let y = (app::event_y() - f.y()).max(0).min(fh - 1);
+    Some(((w * x / fw) as u16, (h * y / fh) as u16))
+}
+
+/// Ctrl+Alt+S
+fn is_screenshot_key() -> bool {
+    app::is_event_ctrl() && app::is_event_alt() && app::event_key() == Key::from_char('s')
 }
 
 /// 进行操控
 /// 当遇到一个鼠标或者键盘事件，就进行发送指令给server
-fn deal_with_events(w: i32, h: i32, frame: &mut Frame, txc: TcpStream) {
+fn deal_with_events(
+    screen: Arc<RwLock<RemoteScreen>>,
+    frame: &mut Frame,
+    txc: Conn,
+    browser: RemoteBrowser,
+) {
     let mut hooked = false;
 
     //用来防止一直按键
     let mut bmap = bitmap::Bitmap::new();
     let mut cmd_buf = [0u8; 5];
-    let mut txc = txc;
     frame.handle(move |f, ev| {
         match ev {
+            Event::DndEnter | Event::DndDrag | Event::DndRelease => {
+                // 接受拖放, 之后会收到 Paste 事件
+            }
+            Event::Paste => {
+                // 拖放文件, 上传到远程浏览窗口的当前目录
+                browser.upload(files::dropped_paths(&app::event_text()));
+            }
             Event::Enter => {
                 // 进入窗口
                 hooked = true;
@@ -283,13 +1046,17 @@ fn deal_with_events(w: i32, h: i32, frame: &mut Frame, txc: TcpStream) {
                 // 离开窗口
                 hooked = false;
             }
+            // 截图的快捷键不发送给server, 交给菜单处理
+            Event::KeyDown | Event::Shortcut if hooked && is_screenshot_key() => {
+                return false;
+            }
             Event::KeyDown if hooked => {
                 // 按键按下
                 let key = app::event_key().bits() as u8;
                 cmd_buf[0] = communication::KEY_DOWN;
                 cmd_buf[1] = key;
                 if bmap.push(key) {
-                    txc.write_all(&cmd_buf[..2]).unwrap();
+                    let _ = txc.send(mux::INPUT, &cmd_buf[..2]);
                 }
             }
             Event::Shortcut if hooked => {
@@ -298,7 +1065,7 @@ fn deal_with_events(w: i32, h: i32, frame: &mut Frame, txc: TcpStream) {
                 cmd_buf[0] = communication::KEY_DOWN;
                 cmd_buf[1] = key;
                 if bmap.push(key) {
-                    txc.write_all(&cmd_buf[..2]).unwrap();
+                    let _ = txc.send(mux::INPUT, &cmd_buf[..2]);
                 }
             }
             Event::KeyUp if hooked => {
@@ -307,43 +1074,43 @@ fn deal_with_events(w: i32, h: i32, frame: &mut Frame, txc: TcpStream) {
                 bmap.remove(key);
                 cmd_buf[0] = communication::KEY_UP;
                 cmd_buf[1] = key;
-                txc.write_all(&cmd_buf[..2]).unwrap();
+                let _ = txc.send(mux::INPUT, &cmd_buf[..2]);
             }
             Event::Move if hooked => {
                 // 鼠标移动
-                let relx = (w * app::event_x() / f.width()) as u16;
-                let rely = (h * app::event_y() / f.height()) as u16;
-                // MOVE xu xd yu yd
-                cmd_buf[0] = communication::MOVE;
-                cmd_buf[1] = (relx >> 8) as u8;
-                cmd_buf[2] = relx as u8;
-                cmd_buf[3] = (rely >> 8) as u8;
-                cmd_buf[4] = rely as u8;
-                txc.write_all(&cmd_buf).unwrap();
+                if let Some((relx, rely)) = to_remote(&screen, f) {
+                    // MOVE xu xd yu yd
+                    cmd_buf[0] = communication::MOVE;
+                    cmd_buf[1] = (relx >> 8) as u8;
+                    cmd_buf[2] = relx as u8;
+                    cmd_buf[3] = (rely >> 8) as u8;
+                    cmd_buf[4] = rely as u8;
+                    let _ = txc.send(mux::INPUT, &cmd_buf);
+                }
             }
             Event::Push if hooked => {
                 // 鼠标按下
                 cmd_buf[0] = communication::MOUSE_KEY_DOWN;
                 cmd_buf[1] = app::event_key().bits() as u8;
-                txc.write_all(&cmd_buf[..2]).unwrap();
+                let _ = txc.send(mux::INPUT, &cmd_buf[..2]);
             }
             Event::Released if hooked => {
                 // 鼠标释放
                 cmd_buf[0] = communication::MOUSE_KEY_UP;
                 cmd_buf[1] = app::event_key().bits() as u8;
-                txc.write_all(&cmd_buf[..2]).unwrap();
+                let _ = txc.send(mux::INPUT, &cmd_buf[..2]);
             }
             Event::Drag if hooked => {
                 // 鼠标按下移动
-                let relx = (w * app::event_x() / f.width()) as u16;
-                let rely = (h * app::event_y() / f.height()) as u16;
-                // MOVE xu xd yu yd
-                cmd_buf[0] = communication::MOVE;
-                cmd_buf[1] = (relx >> 8) as u8;
-                cmd_buf[2] = relx as u8;
-                cmd_buf[3] = (rely >> 8) as u8;
-                cmd_buf[4] = rely as u8;
-                txc.write_all(&cmd_buf).unwrap();
+                if let Some((relx, rely)) = to_remote(&screen, f) {
+                    // MOVE xu xd yu yd
+                    cmd_buf[0] = communication::MOVE;
+                    cmd_buf[1] = (relx >> 8) as u8;
+                    cmd_buf[2] = relx as u8;
+                    cmd_buf[3] = (rely >> 8) as u8;
+                    cmd_buf[4] = rely as u8;
+                    let _ = txc.send(mux::INPUT, &cmd_buf);
+                }
             }
             Event::MouseWheel if hooked => {
                 // app::MouseWheel::Down;
@@ -351,12 +1118,12 @@ fn deal_with_events(w: i32, h: i32, frame: &mut Frame, txc: TcpStream) {
                     app::MouseWheel::Down => {
                         // 滚轮下滚
                         cmd_buf[0] = communication::MOUSE_WHEEL_DOWN;
-                        txc.write_all(&cmd_buf[..1]).unwrap();
+                        let _ = txc.send(mux::INPUT, &cmd_buf[..1]);
                     }
                     app::MouseWheel::Up => {
                         // 滚轮上滚
                         cmd_buf[0] = communication::MOUSE_WHEEL_UP;
-                        txc.write_all(&cmd_buf[..1]).unwrap();
+                        let _ = txc.send(mux::INPUT, &cmd_buf[..1]);
                     }
                     _ => {}
                 }
@@ -373,7 +1140,8 @@ fn deal_with_events(w: i32, h: i32, frame: &mut Frame, txc: TcpStream) {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
 
     #[test]
     fn test_calculate_hash_to_bytes_big_endian() {
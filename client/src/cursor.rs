@@ -0,0 +1,53 @@
+use communication::cursor::CursorPos;
+use communication::cursor::CursorShape;
+use fltk::enums::ColorDepth;
+use fltk::image::RgbImage;
+use fltk::prelude::ImageExt;
+use std::collections::HashMap;
+
+/// 远程光标, 由server单独发送, 在画面之上绘制
+#[derive(Default)]
+pub struct RemoteCursor {
+    pos: CursorPos,
+    // 形状按 id 缓存
+    shapes: HashMap<u64, CursorShape>,
+}
+
+impl RemoteCursor {
+    pub fn set_shape(&mut self, shape: CursorShape) {
+        self.shapes.insert(shape.id, shape);
+    }
+
+    /// 更新位置, 返回是否需要重画
+    pub fn set_pos(&mut self, pos: CursorPos) -> bool {
+        if self.pos == pos {
+            return false;
+        }
+        self.pos = pos;
+        true
+    }
+
+    /// 在 (x, y, fw, fh) 区域中绘制, 远程屏幕大小为 w * h
+    /// 形状和热点与位置按同样的比例缩放
+    pub fn draw(&self, x: i32, y: i32, fw: i32, fh: i32, w: i32, h: i32) {
+        let shape = match self.shapes.get(&self.pos.id) {
+            Some(shape) => shape,
+            None => return,
+        };
+        if w <= 0 || h <= 0 {
+            return;
+        }
+        let (sx, sy) = (fw as f64 / w as f64, fh as f64 / h as f64);
+        let px = x + ((self.pos.x as f64 - shape.hotx as f64) * sx) as i32;
+        let py = y + ((self.pos.y as f64 - shape.hoty as f64) * sy) as i32;
+        let (sw, sh) = (shape.width as i32, shape.height as i32);
+        let (dw, dh) = (
+            ((sw as f64 * sx).round() as i32).max(1),
+            ((sh as f64 * sy).round() as i32).max(1),
+        );
+        if let Ok(mut image) = RgbImage::new(&shape.rgba, sw, sh, ColorDepth::Rgba8) {
+            image.scale(dw, dh, false, true);
+            image.draw(px, py, dw, dh);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::fmt;
+use std::io;
+
+/// 连接和会话中的错误, 由界面显示给用户
+#[derive(Debug)]
+pub enum Error {
+    // 无法连接到server
+    Connect(io::Error),
+    // 密码错误
+    Password,
+    // 会话 token 已过期, 需要重新用密码登录
+    SessionExpired,
+    // server的回复无法识别
+    Protocol(String),
+    // 连接建立后的读写错误
+    Io(io::Error),
+    // server结束了会话, 附带原因
+    Remote(String),
+    // 无法写入导出的视频
+    Export(io::Error),
+    // server没能截取完整画面
+    Snapshot,
+    // 无法保存截图
+    Screenshot(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// 是否值得稍后重试, 密码错误和协议错误重试也不会成功
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Connect(_) | Error::Io(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Connect(e) => write!(f, "Cannot connect to server: {}", e),
+            Error::Password => write!(f, "Password error !"),
+            Error::SessionExpired => write!(f, "Session expired"),
+            Error::Protocol(message) => write!(f, "Protocol error: {}", message),
+            Error::Io(e) => write!(f, "Connection error: {}", e),
+            Error::Remote(reason) => write!(f, "Session ended by server: {}", reason),
+            Error::Export(e) => write!(f, "Cannot write video: {}", e),
+            Error::Snapshot => write!(f, "Server failed to capture the screen"),
+            Error::Screenshot(e) => write!(f, "Cannot save screenshot: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,332 @@
+use crate::session::Conn;
+use crate::transfer::Transfers;
+use communication::file::DirEntry;
+use communication::file::FileMsg;
+use communication::mux;
+use fltk::browser::HoldBrowser;
+use fltk::button::Button;
+use fltk::dialog;
+use fltk::frame::Frame;
+use fltk::prelude::BrowserExt;
+use fltk::prelude::GroupExt;
+use fltk::prelude::WidgetBase;
+use fltk::prelude::WidgetExt;
+use fltk::window::Window;
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+struct State {
+    conn: Conn,
+    transfers: Transfers,
+    // None 表示正在显示根目录列表
+    dir: Option<String>,
+    roots: Vec<String>,
+    entries: Vec<DirEntry>,
+}
+
+/// 远程文件浏览窗口, 只能看到server允许访问的根目录
+#[derive(Clone)]
+pub struct RemoteBrowser {
+    wind: Window,
+    path: Frame,
+    list: HoldBrowser,
+    state: Rc<RefCell<State>>,
+}
+
+impl RemoteBrowser {
+    pub fn new(conn: Conn, transfers: Transfers) -> Self {
+        let wind = Window::default()
+            .with_size(400, 420)
+            .with_label("Remote files");
+        let path = Frame::new(5, 5, 390, 25, None);
+        let list = HoldBrowser::new(5, 35, 390, 340, None);
+        let mut buttons = Vec::new();
+        for (i, label) in ["Up", "Open", "Download", "Upload...", "Refresh"]
+            .iter()
+            .enumerate()
+        {
+            buttons.push(Button::new(5 + i as i32 * 78, 385, 74, 28, *label));
+        }
+        wind.resizable(&list);
+        wind.end();
+
+        let browser = RemoteBrowser {
+            wind,
+            path,
+            list,
+            state: Rc::new(RefCell::new(State {
+                conn,
+                transfers,
+                dir: None,
+                roots: Vec::new(),
+                entries: Vec::new(),
+            })),
+        };
+        let this = browser.clone();
+        buttons[0].set_callback(move |_| this.up());
+        let this = browser.clone();
+        buttons[1].set_callback(move |_| this.open());
+        let this = browser.clone();
+        buttons[2].set_callback(move |_| this.download());
+        let this = browser.clone();
+        buttons[3].set_callback(move |_| {
+            let mut chooser =
+                dialog::NativeFileChooser::new(dialog::NativeFileChooserType::BrowseMultiFile);
+            chooser.set_title("Upload");
+            chooser.show();
+            this.upload(chooser.filenames());
+        });
+        let this = browser.clone();
+        buttons[4].set_callback(move |_| this.refresh());
+        let this = browser.clone();
+        browser.list.clone().set_callback(move |_| {
+            // 双击打开
+            if fltk::app::event_clicks() {
+                this.open();
+            }
+        });
+        browser
+    }
+
+    pub fn show(&mut self) {
+        self.wind.show();
+        self.refresh();
+    }
+
+    /// 会话结束时关闭窗口
+    pub fn close(&self) {
+        Window::delete(self.wind.clone());
+    }
+
+    /// 上传的目标目录, 未打开过目录时为第一个根目录
+    pub fn upload_dir(&self) -> String {
+        self.state
+            .borrow()
+            .dir
+            .clone()
+            .unwrap_or_else(|| "0".to_string())
+    }
+
+    /// 上传本地文件到当前目录
+    pub fn upload(&self, paths: Vec<PathBuf>) {
+        let dir = self.upload_dir();
+        let state = self.state.borrow();
+        for path in paths.into_iter().filter(|p| p.is_file()) {
+            state.transfers.upload(path, dir.clone());
+        }
+    }
+
+    /// server返回的目录列表
+    pub fn update(&mut self, msg: FileMsg) {
+        {
+            let mut state = self.state.borrow_mut();
+            match msg {
+                FileMsg::Roots { names } => {
+                    state.dir = None;
+                    state.roots = names;
+                    state.entries.clear();
+                }
+                FileMsg::DirList { path, entries } => {
+                    state.dir = Some(path);
+                    state.entries = entries;
+                }
+                FileMsg::Error { message, .. } => {
+                    drop(state);
+                    dialog::alert_default(&message);
+                    return;
+                }
+                _ => return,
+            }
+        }
+        self.render();
+    }
+
+    fn render(&mut self) {
+        let state = self.state.borrow();
+        self.list.clear();
+        match &state.dir {
+            None => {
+                self.path.set_label("Roots");
+                for root in state.roots.iter() {
+                    // "@." 表示后面的文字不包含格式符
+                    self.list.add(&format!("@.{}", root));
+                }
+            }
+            Some(dir) => {
+                // 标签中 "@" 为格式符
+                self.path
+                    .set_label(&display_path(&state.roots, dir).replace('@', "@@"));
+                for e in state.entries.iter() {
+                    if e.is_dir {
+                        self.list.add(&format!("@.{}/", e.name));
+                    } else {
+                        self.list
+                            .add(&format!("@.{}  ({})", e.name, human_size(e.size)));
+                    }
+                }
+            }
+        }
+        self.wind.redraw();
+    }
+
+    fn request(&self, msg: FileMsg) {
+        let state = self.state.borrow();
+        let _ = state.conn.send(mux::FILE, &msg.encode());
+    }
+
+    fn refresh(&self) {
+        let dir = self.state.borrow().dir.clone();
+        match dir {
+            Some(path) => self.request(FileMsg::ListDir { path }),
+            None => self.request(FileMsg::ListRoots),
+        }
+    }
+
+    fn up(&self) {
+        let dir = self.state.borrow().dir.clone();
+        match dir.as_deref().and_then(|d| d.rsplit_once('/')) {
+            Some((parent, _)) => self.request(FileMsg::ListDir {
+                path: parent.to_string(),
+            }),
+            None => self.request(FileMsg::ListRoots),
+        }
+    }
+
+    /// 选中的项, 返回 (远程路径, 是否为目录)
+    fn selected(&self) -> Option<(String, bool)> {
+        let line = self.list.value();
+        if line <= 0 {
+            return None;
+        }
+        let index = line as usize - 1;
+        let state = self.state.borrow();
+        match &state.dir {
+            None if index < state.roots.len() => Some((index.to_string(), true)),
+            Some(dir) => {
+                let e = state.entries.get(index)?;
+                Some((format!("{}/{}", dir, e.name), e.is_dir))
+            }
+            _ => None,
+        }
+    }
+
+    fn open(&self) {
+        match self.selected() {
+            Some((path, true)) => self.request(FileMsg::ListDir { path }),
+            Some((_, false)) => self.download(),
+            None => {}
+        }
+    }
+
+    fn download(&self) {
+        let path = match self.selected() {
+            Some((path, false)) => path,
+            _ => return,
+        };
+        let mut chooser = dialog::NativeFileChooser::new(dialog::NativeFileChooserType::BrowseDir);
+        chooser.set_title("Save to");
+        chooser.show();
+        let dest = chooser.filename();
+        if dest.as_os_str().is_empty() {
+            return;
+        }
+        self.state.borrow().transfers.download(path, dest);
+    }
+}
+
+/// 把 "0/a/b" 显示为 "根目录/a/b"
+fn display_path(roots: &[String], path: &str) -> String {
+    let (index, rest) = path.split_once('/').unwrap_or((path, ""));
+    let root = index
+        .parse::<usize>()
+        .ok()
+        .and_then(|i| roots.get(i))
+        .map(|r| r.as_str())
+        .unwrap_or(index);
+    if rest.is_empty() {
+        root.to_string()
+    } else {
+        format!("{}/{}", root.trim_end_matches(['/', '\\']), rest)
+    }
+}
+
+fn human_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, UNITS[unit])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// 解析拖放到窗口上的文件列表, 每行一个路径或 file:// URI
+pub fn dropped_paths(text: &str) -> Vec<PathBuf> {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| match line.strip_prefix("file://") {
+            Some(uri) => {
+                // 跳过主机名
+                let path = &uri[uri.find('/').unwrap_or(uri.len())..];
+                let path = percent_decode(path);
+                // windows 上为 /C:/dir
+                match path.as_bytes() {
+                    [b'/', _, b':', ..] => PathBuf::from(&path[1..]),
+                    _ => PathBuf::from(path),
+                }
+            }
+            None => PathBuf::from(line),
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[test]
+fn test() {
+    assert_eq!(
+        dropped_paths("file:///home/a/My%20File.txt\r\nfile://localhost/tmp/b\n\n/tmp/c d"),
+        vec![
+            PathBuf::from("/home/a/My File.txt"),
+            PathBuf::from("/tmp/b"),
+            PathBuf::from("/tmp/c d"),
+        ]
+    );
+    assert_eq!(
+        dropped_paths("file:///C:/Users/%E4%BD%A0.txt"),
+        vec![PathBuf::from("C:/Users/你.txt")]
+    );
+    assert_eq!(dropped_paths("C:\\a.txt"), vec![PathBuf::from("C:\\a.txt")]);
+    assert_eq!(percent_decode("100%"), "100%");
+    assert_eq!(percent_decode("%zz%41"), "%zzA");
+
+    let roots = vec!["/srv/logs/".to_string()];
+    assert_eq!(display_path(&roots, "0"), "/srv/logs/");
+    assert_eq!(display_path(&roots, "0/a/b"), "/srv/logs/a/b");
+    assert_eq!(human_size(512), "512 B");
+    assert_eq!(human_size(1536), "1.5 KB");
+}
@@ -0,0 +1,153 @@
+/// 键盘捕获
+/// 开启后, 窗口在前台时 Alt+Tab, Win 等系统快捷键不再由本地系统处理, 而是转发给server
+/// 目前只支持 windows (低级键盘钩子), 其他平台返回 false
+#[cfg(windows)]
+pub use self::windows::set_grab;
+
+#[cfg(not(windows))]
+pub fn set_grab(
+    _enable: bool,
+    _hwnd: fltk::window::RawHandle,
+    _conn: &crate::session::Conn,
+) -> bool {
+    false
+}
+
+#[cfg(windows)]
+mod windows {
+    use crate::bitmap::Bitmap;
+    use crate::session::Conn;
+    use communication::mux;
+    use std::cell::RefCell;
+    use std::ptr::null_mut;
+    use winapi::shared::minwindef::{LPARAM, LRESULT, WPARAM};
+    use winapi::shared::windef::{HHOOK, HWND};
+    use winapi::um::libloaderapi::GetModuleHandleW;
+    use winapi::um::winuser::{
+        CallNextHookEx, GetForegroundWindow, SetWindowsHookExW, UnhookWindowsHookEx, HC_ACTION,
+        KBDLLHOOKSTRUCT, WH_KEYBOARD_LL, WM_KEYDOWN, WM_KEYUP, WM_SYSKEYDOWN, WM_SYSKEYUP,
+    };
+
+    struct Grab {
+        hook: HHOOK,
+        hwnd: HWND,
+        conn: Conn,
+        bmap: Bitmap,
+    }
+
+    // 钩子回调运行在安装钩子的线程(UI主线程)上
+    thread_local! {
+        static GRAB: RefCell<Option<Grab>> = RefCell::new(None);
+    }
+
+    pub fn set_grab(enable: bool, hwnd: fltk::window::RawHandle, conn: &Conn) -> bool {
+        GRAB.with(|g| {
+            let mut g = g.borrow_mut();
+            if let Some(old) = g.take() {
+                unsafe {
+                    UnhookWindowsHookEx(old.hook);
+                }
+            }
+            if !enable {
+                return true;
+            }
+            let hook = unsafe {
+                SetWindowsHookExW(
+                    WH_KEYBOARD_LL,
+                    Some(hook_proc),
+                    GetModuleHandleW(null_mut()),
+                    0,
+                )
+            };
+            if hook.is_null() {
+                return false;
+            }
+            *g = Some(Grab {
+                hook,
+                hwnd: hwnd as HWND,
+                conn: conn.clone(),
+                bmap: Bitmap::new(),
+            });
+            true
+        })
+    }
+
+    unsafe extern "system" fn hook_proc(code: i32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+        if code == HC_ACTION {
+            let vk = (*(lparam as *const KBDLLHOOKSTRUCT)).vkCode;
+            let swallow = GRAB.with(|g| {
+                let mut g = g.borrow_mut();
+                let grab = match g.as_mut() {
+                    Some(grab) if GetForegroundWindow() == grab.hwnd => grab,
+                    _ => return false,
+                };
+                let key = match vk_to_key(vk) {
+                    Some(key) => key,
+                    None => return false,
+                };
+                match wparam as u32 {
+                    WM_KEYDOWN | WM_SYSKEYDOWN => {
+                        if grab.bmap.push(key) {
+                            let _ = grab.conn.send(mux::INPUT, &[communication::KEY_DOWN, key]);
+                        }
+                    }
+                    WM_KEYUP | WM_SYSKEYUP => {
+                        grab.bmap.remove(key);
+                        let _ = grab.conn.send(mux::INPUT, &[communication::KEY_UP, key]);
+                    }
+                    _ => {}
+                }
+                true
+            });
+            if swallow {
+                return 1;
+            }
+        }
+        CallNextHookEx(null_mut(), code, wparam, lparam)
+    }
+
+    /// windows 虚拟键码转换为 fltk 按键码(低8位)
+    fn vk_to_key(vk: u32) -> Option<u8> {
+        let key = match vk {
+            0x08 => 8,                               // BACK
+            0x09 => 9,                               // TAB
+            0x0D => 13,                              // RETURN
+            0x14 => 229,                             // CAPITAL
+            0x1B => 27,                              // ESCAPE
+            0x20 => 32,                              // SPACE
+            0x21 => 85,                              // PRIOR
+            0x22 => 86,                              // NEXT
+            0x23 => 87,                              // END
+            0x24 => 80,                              // HOME
+            0x25 => 81,                              // LEFT
+            0x26 => 82,                              // UP
+            0x27 => 83,                              // RIGHT
+            0x28 => 84,                              // DOWN
+            0x2E => 255,                             // DELETE
+            0x30..=0x39 => vk as u8,                 // 0-9
+            0x41..=0x5A => (vk - 0x41) as u8 + b'a', // A-Z
+            0x5B => 231,                             // LWIN
+            0x5C => 232,                             // RWIN
+            0x70..=0x7B => (vk - 0x70) as u8 + 190,  // F1-F12
+            0xA0 => 225,                             // LSHIFT
+            0xA1 => 226,                             // RSHIFT
+            0xA2 => 227,                             // LCONTROL
+            0xA3 => 228,                             // RCONTROL
+            0xA4 => 233,                             // LMENU
+            0xA5 => 234,                             // RMENU
+            0xBA => b';',
+            0xBB => b'=',
+            0xBC => b',',
+            0xBD => b'-',
+            0xBE => b'.',
+            0xBF => b'/',
+            0xC0 => b'`',
+            0xDB => b'[',
+            0xDC => b'\\',
+            0xDD => b']',
+            0xDE => b'\'',
+            _ => return None,
+        };
+        Some(key)
+    }
+}
@@ -0,0 +1,223 @@
+use crate::client;
+use crate::error::Error;
+use crate::error::Result;
+use crate::session;
+use crate::session::Conn;
+use communication::decode::Decoded;
+use communication::decode::Decoder;
+use communication::frame;
+use communication::heartbeat;
+use communication::mux;
+use communication::mux::MuxReader;
+use communication::mux::MuxSender;
+use communication::snapshot;
+use communication::video;
+use communication::video::VideoExport;
+use std::net::Shutdown;
+use std::net::TcpStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+// 等待server回复完整画面的最长时间, 截屏失败时server会重试几次
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const USAGE: &str = "usage: client export HOST PASSWORD FILE SECONDS [FPS]\n       client screenshot HOST PASSWORD [FILE]";
+
+/**
+ * 没有窗口的client
+ * 连接server后只解码画面, 不发送键鼠指令, 断线后不重连
+ */
+pub struct Headless {
+    conn: Conn,
+    stream: TcpStream,
+    reader: MuxReader<TcpStream>,
+    decoder: Decoder,
+    max_frame: usize,
+    // 收到的 VIDEO_SNAPSHOT, 截屏失败时为 None
+    snapshot: Option<Option<(usize, usize, Vec<u8>)>>,
+    // 心跳线程在 drop 后结束
+    alive: Arc<AtomicBool>,
+}
+
+impl Headless {
+    pub fn connect(host: &str, pwd: &str) -> Result<Headless> {
+        let session =
+            session::connect_with_backoff(host, pwd, None, client::LOGIN_ATTEMPTS, |_, _| {})?;
+        let conn = Conn::new(MuxSender::start(session.stream.try_clone()?), None);
+        // server每次收到 PING 都会回复, 超时说明连接已断开
+        session
+            .stream
+            .set_read_timeout(Some(heartbeat::timeout()))?;
+        let max_frame = frame::max_frame();
+        let max_message = mux::MAX_MESSAGE.max(frame::max_message(max_frame));
+        let reader = MuxReader::new(session.stream.try_clone()?, max_message);
+
+        let alive = Arc::new(AtomicBool::new(true));
+        let ping_alive = alive.clone();
+        let ping_conn = conn.clone();
+        std::thread::spawn(move || {
+            while ping_alive.load(Ordering::Relaxed) {
+                std::thread::sleep(heartbeat::INTERVAL);
+                let _ = ping_conn.send(mux::CONTROL, &heartbeat::ping(heartbeat::now()));
+            }
+        });
+        Ok(Headless {
+            conn,
+            stream: session.stream,
+            reader,
+            decoder: Decoder::new(session.w as usize, session.h as usize, max_frame),
+            max_frame,
+            snapshot: None,
+            alive,
+        })
+    }
+
+    /// 保存着最近还原的一帧
+    pub fn decoder(&self) -> &Decoder {
+        &self.decoder
+    }
+
+    /// 改为按请求发送画面, 之后不再收到新的帧
+    pub fn pause(&self) -> Result<()> {
+        self.conn.send(
+            mux::CONTROL,
+            &[communication::FRAME_MODE, communication::FRAME_PULL],
+        )?;
+        Ok(())
+    }
+
+    /// 请求server截取一张完整画面, 返回 w h 和原始分辨率的 RGB
+    /// 心跳回复让 poll 不会一直阻塞, 超时没有回复时返回 Error::Snapshot
+    pub fn snapshot(&mut self) -> Result<(usize, usize, Vec<u8>)> {
+        self.snapshot = None;
+        self.conn.send(mux::CONTROL, &[communication::SNAPSHOT])?;
+        let deadline = Instant::now() + SNAPSHOT_TIMEOUT;
+        while Instant::now() < deadline {
+            self.poll()?;
+            if let Some(snapshot) = self.snapshot.take() {
+                return snapshot.ok_or(Error::Snapshot);
+            }
+        }
+        Err(Error::Snapshot)
+    }
+
+    /// 处理server的下一个消息, 还原了新的一帧时返回 true
+    /// 每秒至少收到一次心跳回复, 画面不变时也不会一直阻塞
+    pub fn poll(&mut self) -> Result<bool> {
+        let (channel, buf) = self.reader.recv()?;
+        match channel {
+            mux::CONTROL => match buf.split_first() {
+                Some((&communication::SESSION_END, reason)) => {
+                    Err(Error::Remote(String::from_utf8_lossy(reason).into_owned()))
+                }
+                _ => Ok(false),
+            },
+            mux::VIDEO => match buf.first() {
+                // 之后是新尺寸的完整一帧
+                Some(&communication::VIDEO_DISPLAY) => {
+                    if self.decoder.display(&buf) {
+                        Ok(false)
+                    } else {
+                        Err(Error::Protocol("bad display message".to_string()))
+                    }
+                }
+                Some(&communication::VIDEO_FRAME) => match self.decoder.decode(&buf) {
+                    Some(Decoded::Frame) => Ok(true),
+                    Some(Decoded::Skipped) => Ok(false),
+                    // 丢帧或与server的画面不一致, 请求关键帧重新同步
+                    Some(Decoded::Refresh) => {
+                        let _ = self.conn.send(mux::CONTROL, &[communication::REFRESH]);
+                        Ok(false)
+                    }
+                    None => Err(Error::Protocol("broken frame".to_string())),
+                },
+                Some(&communication::VIDEO_SNAPSHOT) => {
+                    self.snapshot = Some(snapshot::decode(&buf, self.max_frame));
+                    Ok(false)
+                }
+                _ => Ok(false),
+            },
+            // 光标, 剪贴板和文件消息都不需要
+            _ => Ok(false),
+        }
+    }
+}
+
+impl Drop for Headless {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::Relaxed);
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// 把 duration 内的会话画面写入 export, 返回写入的帧数
+pub fn export(host: &str, pwd: &str, mut export: VideoExport, duration: Duration) -> Result<u64> {
+    let mut headless = Headless::connect(host, pwd)?;
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        if headless.poll()? {
+            export
+                .frame(headless.decoder(), heartbeat::now())
+                .map_err(Error::Export)?;
+        }
+    }
+    export.finish(heartbeat::now()).map_err(Error::Export)
+}
+
+/// 暂停画面流, 向server请求一张完整画面保存为 PNG
+pub fn screenshot(host: &str, pwd: &str, path: &Path) -> Result<()> {
+    let mut headless = Headless::connect(host, pwd)?;
+    headless.pause()?;
+    let (w, h, rgb) = headless.snapshot()?;
+    snapshot::save_png(path, w, h, &rgb).map_err(Error::Screenshot)
+}
+
+/// 命令行:
+/// client export HOST PASSWORD FILE SECONDS [FPS]
+/// client screenshot HOST PASSWORD [FILE]
+pub fn run(args: &[String]) {
+    if let [command, host, pwd, rest @ ..] = args {
+        if command == "screenshot" && rest.len() <= 1 {
+            let path = rest
+                .first()
+                .map(PathBuf::from)
+                .unwrap_or_else(snapshot::path);
+            match screenshot(host, pwd, &path) {
+                Ok(()) => println!("Saved screenshot to {}", path.display()),
+                Err(e) => exit(&e.to_string()),
+            }
+            return;
+        }
+    }
+    let (host, pwd, path, seconds) = match args {
+        [command, host, pwd, path, seconds, ..] if command == "export" => {
+            (host, pwd, PathBuf::from(path), seconds.parse::<u64>().ok())
+        }
+        _ => exit(USAGE),
+    };
+    let fps = match args.get(5) {
+        Some(fps) => fps.parse().ok(),
+        None => Some(video::DEFAULT_FPS),
+    };
+    let (seconds, fps) = match (seconds, fps) {
+        (Some(seconds), Some(fps)) => (seconds, fps),
+        _ => exit(USAGE),
+    };
+    let result = VideoExport::create(&path, fps)
+        .map_err(Error::Export)
+        .and_then(|e| export(host, pwd, e, Duration::from_secs(seconds)));
+    match result {
+        Ok(count) => println!("Exported {} frames to {}", count, path.display()),
+        Err(e) => exit(&e.to_string()),
+    }
+}
+
+fn exit(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    std::process::exit(1);
+}
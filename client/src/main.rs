@@ -1,7 +1,21 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 mod bitmap;
+mod chord;
 mod client;
+mod cursor;
+mod error;
+mod files;
+mod grab;
+mod headless;
+mod session;
+mod transfer;
 
 fn main() {
-    client::run();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // 没有参数时打开登录窗口, 否则为没有窗口的命令
+    if args.is_empty() {
+        client::run();
+    } else {
+        headless::run(&args);
+    }
 }
@@ -0,0 +1,147 @@
+use crate::error::Error;
+use crate::error::Result;
+use communication::mux;
+use communication::mux::MuxSender;
+use communication::record;
+use communication::record::Recorder;
+use communication::TOKEN_LEN;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+
+// 等待server回复的超时
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// 重试间隔从 RETRY_MIN 开始翻倍, 最多 RETRY_MAX
+const RETRY_MIN: Duration = Duration::from_millis(500);
+const RETRY_MAX: Duration = Duration::from_secs(8);
+
+/// 认证成功的连接
+pub struct Session {
+    pub stream: TcpStream,
+    // 断线后用于恢复会话
+    pub token: [u8; TOKEN_LEN],
+    pub w: i32,
+    pub h: i32,
+}
+
+/**
+ * 发送给server的连接
+ * 重连后替换为新的 mux, 菜单, 输入和传输线程持有的克隆随之切换
+ * 录像时记录发送的键鼠指令
+ */
+#[derive(Clone)]
+pub struct Conn {
+    sender: Arc<RwLock<MuxSender>>,
+    recorder: Option<Arc<Recorder>>,
+}
+
+impl Conn {
+    pub fn new(sender: MuxSender, recorder: Option<Recorder>) -> Self {
+        Conn {
+            sender: Arc::new(RwLock::new(sender)),
+            recorder: recorder.map(Arc::new),
+        }
+    }
+
+    /// 断线期间返回错误, 消息被丢弃
+    pub fn send(&self, channel: u8, data: &[u8]) -> io::Result<()> {
+        self.sender.read().unwrap().send(channel, data)?;
+        if channel == mux::INPUT {
+            self.record(channel, data);
+        }
+        Ok(())
+    }
+
+    pub fn replace(&self, sender: MuxSender) {
+        *self.sender.write().unwrap() = sender;
+    }
+
+    /// 录像时记录一个消息
+    pub fn record(&self, channel: u8, data: &[u8]) {
+        if let Err(e) = record::record(self.recorder.as_deref(), channel, data) {
+            println!("{}", e);
+        }
+    }
+}
+
+/// 建立连接并认证, 有 token 时先尝试恢复会话, 过期后用密码重新登录
+pub fn connect(host: &str, pwd: &str, token: Option<[u8; TOKEN_LEN]>) -> Result<Session> {
+    if let Some(token) = token {
+        let mut request = vec![communication::AUTH_TOKEN];
+        request.extend_from_slice(&token);
+        match handshake(host, &request) {
+            Err(Error::SessionExpired) => {}
+            res => return res,
+        }
+    }
+    let mut hasher = DefaultHasher::new();
+    hasher.write(pwd.as_bytes());
+    let mut request = vec![communication::AUTH_PASSWORD];
+    request.extend_from_slice(&hasher.finish().to_be_bytes());
+    handshake(host, &request)
+}
+
+/// 失败后等待一段时间重试, 最多 attempts 次, 只重试网络错误
+/// 每次等待前调用 waiting(第几次重试, 等待时间)
+pub fn connect_with_backoff(
+    host: &str,
+    pwd: &str,
+    token: Option<[u8; TOKEN_LEN]>,
+    attempts: u32,
+    mut waiting: impl FnMut(u32, Duration),
+) -> Result<Session> {
+    let mut delay = RETRY_MIN;
+    let mut attempt = 1;
+    loop {
+        match connect(host, pwd, token) {
+            Err(e) if e.is_transient() && attempt < attempts => {
+                waiting(attempt, delay);
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(RETRY_MAX);
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+fn handshake(host: &str, request: &[u8]) -> Result<Session> {
+    let mut stream = TcpStream::connect(host).map_err(Error::Connect)?;
+    // 数据块较小, 不等待合并, 减少输入延迟
+    let _ = stream.set_nodelay(true);
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.write_all(request)?;
+
+    let mut result = [0u8];
+    stream.read_exact(&mut result)?;
+    match result[0] {
+        communication::AUTH_OK => {}
+        communication::AUTH_BAD_PASSWORD => return Err(Error::Password),
+        communication::AUTH_BAD_TOKEN => return Err(Error::SessionExpired),
+        other => return Err(Error::Protocol(format!("unexpected login reply {}", other))),
+    }
+    let mut token = [0u8; TOKEN_LEN];
+    stream.read_exact(&mut token)?;
+
+    // 接收meta信息
+    let mut meta = [0u8; 4];
+    stream.read_exact(&mut meta)?;
+    let w = (((meta[0] as u16) << 8) | meta[1] as u16) as i32;
+    let h = (((meta[2] as u16) << 8) | meta[3] as u16) as i32;
+    if w == 0 || h == 0 {
+        return Err(Error::Protocol(format!("bad screen size {}x{}", w, h)));
+    }
+    stream.set_read_timeout(None)?;
+    Ok(Session {
+        stream,
+        token,
+        w,
+        h,
+    })
+}
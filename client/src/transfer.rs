@@ -0,0 +1,436 @@
+use crate::client::Msg;
+use crate::session::Conn;
+use communication::file;
+use communication::file::FileMsg;
+use communication::mux;
+use fltk::app;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::TryRecvError;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+enum Command {
+    Upload { local: PathBuf, dir: String },
+    Download { path: String, dest: PathBuf },
+    CancelAll,
+    Resume,
+    Remote(FileMsg),
+}
+
+struct Upload {
+    name: String,
+    // 重连后重新发起上传
+    dir: String,
+    sha256: [u8; 32],
+    file: File,
+    size: u64,
+    offset: u64,
+    // 收到 UploadReady 后开始发送
+    ready: bool,
+}
+
+struct Download {
+    name: String,
+    path: String,
+    dest: PathBuf,
+    // 收到 DownloadInfo 后创建
+    part: Option<(PathBuf, File)>,
+    size: u64,
+    offset: u64,
+    sha256: [u8; 32],
+}
+
+/**
+ * 文件传输
+ * 在单独的线程中读写文件, 进度写入状态栏文字
+ * 未完成的下载保留为 .part 文件, 再次下载同一文件时从已有的长度继续
+ */
+#[derive(Clone)]
+pub struct Transfers {
+    tx: mpsc::Sender<Command>,
+}
+
+impl Transfers {
+    pub fn start(conn: Conn, events: app::Sender<Msg>, status: Arc<RwLock<String>>) -> Self {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            Manager {
+                conn,
+                events,
+                status,
+                uploads: BTreeMap::new(),
+                downloads: BTreeMap::new(),
+                next_id: 1,
+                turn: 0,
+                last: String::new(),
+            }
+            .run(rx);
+        });
+        Transfers { tx }
+    }
+
+    /// 上传本地文件到远程目录 dir
+    pub fn upload(&self, local: PathBuf, dir: String) {
+        let _ = self.tx.send(Command::Upload { local, dir });
+    }
+
+    /// 下载远程文件到本地目录 dest
+    pub fn download(&self, path: String, dest: PathBuf) {
+        let _ = self.tx.send(Command::Download { path, dest });
+    }
+
+    /// 取消所有传输, 已传输的部分保留用于续传
+    pub fn cancel_all(&self) {
+        let _ = self.tx.send(Command::CancelAll);
+    }
+
+    /// 重连后继续未完成的传输
+    pub fn resume(&self) {
+        let _ = self.tx.send(Command::Resume);
+    }
+
+    /// server发来的传输消息
+    pub fn remote(&self, msg: FileMsg) {
+        let _ = self.tx.send(Command::Remote(msg));
+    }
+}
+
+struct Manager {
+    conn: Conn,
+    events: app::Sender<Msg>,
+    status: Arc<RwLock<String>>,
+    uploads: BTreeMap<u32, Upload>,
+    downloads: BTreeMap<u32, Download>,
+    next_id: u32,
+    // 轮流发送各个上传的数据块
+    turn: usize,
+    // 最近一次的状态, 变化时才重画
+    last: String,
+}
+
+impl Manager {
+    fn run(mut self, rx: Receiver<Command>) {
+        loop {
+            // 有需要发送的数据块时不阻塞
+            let sending = self.uploads.values().any(|u| u.ready);
+            let cmd = if sending {
+                match rx.try_recv() {
+                    Ok(cmd) => Some(cmd),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            } else {
+                match rx.recv() {
+                    Ok(cmd) => Some(cmd),
+                    Err(_) => return,
+                }
+            };
+            match cmd {
+                Some(Command::Upload { local, dir }) => self.start_upload(local, dir),
+                Some(Command::Download { path, dest }) => self.start_download(path, dest),
+                Some(Command::CancelAll) => self.cancel_all(),
+                Some(Command::Resume) => self.resume(),
+                Some(Command::Remote(msg)) => self.handle(msg),
+                None => self.send_chunk(),
+            }
+            self.report(None);
+        }
+    }
+
+    fn send(&self, msg: &FileMsg) -> bool {
+        self.conn.send(mux::FILE, &msg.encode()).is_ok()
+    }
+
+    fn start_upload(&mut self, local: PathBuf, dir: String) {
+        let name = match local.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_string(),
+            None => return,
+        };
+        let (file, size, sha256) = match open_upload(&local) {
+            Ok(res) => res,
+            Err(e) => return self.report(Some(format!("{}: {}", name, e))),
+        };
+        let id = self.next_id();
+        let msg = FileMsg::Upload {
+            id,
+            dir: dir.clone(),
+            name: name.clone(),
+            size,
+            sha256,
+        };
+        self.uploads.insert(
+            id,
+            Upload {
+                name,
+                dir,
+                sha256,
+                file,
+                size,
+                offset: 0,
+                ready: false,
+            },
+        );
+        self.send(&msg);
+    }
+
+    fn start_download(&mut self, path: String, dest: PathBuf) {
+        let name = match path.rsplit('/').next() {
+            Some(name) if file::valid_name(name) => name.to_string(),
+            _ => return,
+        };
+        let id = self.next_id();
+        self.downloads.insert(
+            id,
+            Download {
+                dest: dest.join(&name),
+                name,
+                path: path.clone(),
+                part: None,
+                size: 0,
+                offset: 0,
+                sha256: [0; 32],
+            },
+        );
+        self.send(&FileMsg::Download { id, path });
+    }
+
+    fn cancel_all(&mut self) {
+        let ids: Vec<u32> = self
+            .uploads
+            .keys()
+            .chain(self.downloads.keys())
+            .copied()
+            .collect();
+        for id in ids {
+            self.send(&FileMsg::Cancel { id });
+        }
+        self.uploads.clear();
+        self.downloads.clear();
+        self.report(Some("Transfers cancelled".to_string()));
+    }
+
+    /// 新会话中重新请求所有未完成的传输, 双方都从 .part 文件的长度继续
+    fn resume(&mut self) {
+        let mut msgs = Vec::new();
+        for (&id, upload) in self.uploads.iter_mut() {
+            upload.ready = false;
+            msgs.push(FileMsg::Upload {
+                id,
+                dir: upload.dir.clone(),
+                name: upload.name.clone(),
+                size: upload.size,
+                sha256: upload.sha256,
+            });
+        }
+        for (&id, download) in self.downloads.iter_mut() {
+            download.part = None;
+            msgs.push(FileMsg::Download {
+                id,
+                path: download.path.clone(),
+            });
+        }
+        for msg in msgs.iter() {
+            self.send(msg);
+        }
+    }
+
+    fn handle(&mut self, msg: FileMsg) {
+        match msg {
+            FileMsg::UploadReady { id, offset } => {
+                if let Some(upload) = self.uploads.get_mut(&id) {
+                    if offset <= upload.size && upload.file.seek(SeekFrom::Start(offset)).is_ok() {
+                        upload.offset = offset;
+                        upload.ready = true;
+                    }
+                }
+            }
+            FileMsg::DownloadInfo { id, size, sha256 } => self.resume_download(id, size, sha256),
+            FileMsg::Chunk { id, offset, data } => self.write_chunk(id, offset, &data),
+            FileMsg::Done { id } => {
+                if let Some(upload) = self.uploads.remove(&id) {
+                    self.report(Some(format!("{}: uploaded", upload.name)));
+                }
+                if let Some(download) = self.downloads.remove(&id) {
+                    let res = finish_download(download);
+                    self.report(Some(res));
+                }
+            }
+            FileMsg::Error { id, message } => {
+                let name = match (self.uploads.remove(&id), self.downloads.remove(&id)) {
+                    (Some(upload), _) => upload.name,
+                    (_, Some(download)) => download.name,
+                    _ => return,
+                };
+                self.report(Some(format!("{}: {}", name, message)));
+            }
+            _ => {}
+        }
+    }
+
+    fn resume_download(&mut self, id: u32, size: u64, sha256: [u8; 32]) {
+        let download = match self.downloads.get_mut(&id) {
+            Some(download) => download,
+            None => return,
+        };
+        let part = file::part_path(&download.dest, &sha256);
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&part)
+            .and_then(|file| {
+                let mut offset = file.metadata()?.len();
+                if offset > size {
+                    file.set_len(0)?;
+                    offset = 0;
+                }
+                Ok((file, offset))
+            });
+        match res {
+            Ok((file, offset)) => {
+                download.part = Some((part, file));
+                download.size = size;
+                download.offset = offset;
+                download.sha256 = sha256;
+                self.send(&FileMsg::Resume { id, offset });
+            }
+            Err(e) => {
+                self.send(&FileMsg::Cancel { id });
+                if let Some(download) = self.downloads.remove(&id) {
+                    self.report(Some(format!("{}: {}", download.name, e)));
+                }
+            }
+        }
+    }
+
+    fn write_chunk(&mut self, id: u32, offset: u64, data: &[u8]) {
+        let download = match self.downloads.get_mut(&id) {
+            Some(download) => download,
+            None => return,
+        };
+        let ok = match download.part.as_mut() {
+            Some((_, file)) if offset == download.offset => file.write_all(data).is_ok(),
+            _ => false,
+        };
+        if !ok {
+            self.send(&FileMsg::Cancel { id });
+            if let Some(download) = self.downloads.remove(&id) {
+                self.report(Some(format!("{}: write failed", download.name)));
+            }
+            return;
+        }
+        download.offset += data.len() as u64;
+    }
+
+    /// 发送一个上传数据块, 多个上传轮流发送
+    fn send_chunk(&mut self) {
+        let ready: Vec<u32> = self
+            .uploads
+            .iter()
+            .filter(|(_, u)| u.ready)
+            .map(|(id, _)| *id)
+            .collect();
+        if ready.is_empty() {
+            return;
+        }
+        self.turn = self.turn.wrapping_add(1);
+        let id = ready[self.turn % ready.len()];
+        let upload = self.uploads.get_mut(&id).unwrap();
+        let len = (upload.size - upload.offset).min(file::CHUNK_SIZE as u64) as usize;
+        let mut data = vec![0u8; len];
+        if let Err(e) = upload.file.read_exact(&mut data) {
+            let msg = format!("{}: {}", upload.name, e);
+            self.uploads.remove(&id);
+            self.send(&FileMsg::Cancel { id });
+            return self.report(Some(msg));
+        }
+        let offset = upload.offset;
+        upload.offset += len as u64;
+        // 发完后等待server校验
+        upload.ready = upload.offset < upload.size;
+        if !self.send(&FileMsg::Chunk { id, offset, data }) {
+            // 断线, 等待重连后继续
+            if let Some(upload) = self.uploads.get_mut(&id) {
+                upload.ready = false;
+            }
+        }
+    }
+
+    fn next_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        id
+    }
+
+    /// 更新状态栏, message 为已结束的传输
+    fn report(&mut self, message: Option<String>) {
+        let mut parts: Vec<String> = Vec::new();
+        for u in self.uploads.values() {
+            parts.push(format!("↑ {} {}%", u.name, percent(u.offset, u.size)));
+        }
+        for d in self.downloads.values() {
+            parts.push(format!("↓ {} {}%", d.name, percent(d.offset, d.size)));
+        }
+        if let Some(message) = message {
+            parts.push(message);
+        } else if parts.is_empty() {
+            // 保留最后一次的结果
+            return;
+        }
+        let status = parts.join(" | ");
+        if status == self.last {
+            return;
+        }
+        if let Ok(mut s) = self.status.write() {
+            *s = status.clone();
+        }
+        self.last = status;
+        self.events.send(Msg::Draw);
+    }
+}
+
+fn open_upload(local: &PathBuf) -> std::io::Result<(File, u64, [u8; 32])> {
+    let sha256 = file::sha256_file(local)?;
+    let file = File::open(local)?;
+    let size = file.metadata()?.len();
+    Ok((file, size, sha256))
+}
+
+/// 校验并改名, 返回结果描述
+fn finish_download(download: Download) -> String {
+    let (part, file) = match download.part {
+        Some(part) => part,
+        None => return format!("{}: not started", download.name),
+    };
+    drop(file);
+    if download.offset != download.size {
+        return format!("{}: incomplete", download.name);
+    }
+    match file::sha256_file(&part) {
+        Ok(sha256) if sha256 == download.sha256 => {}
+        _ => {
+            // 内容损坏, 不能再用于续传
+            let _ = std::fs::remove_file(&part);
+            return format!("{}: checksum mismatch", download.name);
+        }
+    }
+    match std::fs::rename(&part, &download.dest) {
+        Ok(_) => format!("{}: downloaded", download.name),
+        Err(e) => format!("{}: {}", download.name, e),
+    }
+}
+
+fn percent(offset: u64, size: u64) -> u64 {
+    if size == 0 {
+        return 100;
+    }
+    offset * 100 / size
+}
@@ -0,0 +1,55 @@
+use communication::convert::bgra_to_i420_with;
+use communication::convert::i420_len;
+use communication::convert::i420_to_rgb_with;
+use communication::convert::Backend;
+use communication::convert::ColorSpace;
+use communication::convert::Matrix;
+use communication::convert::Range;
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+
+// 常见的屏幕尺寸
+const SIZES: [(usize, usize); 2] = [(1920, 1080), (3840, 2160)];
+// 各颜色空间只有系数不同, 速度相同
+const SPACE: ColorSpace = ColorSpace {
+    matrix: Matrix::Bt601,
+    range: Range::Limited,
+};
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bgra_to_i420");
+    for (w, h) in SIZES {
+        let src: Vec<u8> = (0..w * h * 4).map(|i| (i * 7) as u8).collect();
+        let mut dest = Vec::new();
+        group.throughput(Throughput::Elements((w * h) as u64));
+        for backend in Backend::available() {
+            let id = BenchmarkId::new(format!("{:?}", backend), format!("{}x{}", w, h));
+            group.bench_function(id, |b| {
+                b.iter(|| bgra_to_i420_with(backend, SPACE, w, h, w * 4, &src, &mut dest))
+            });
+        }
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("i420_to_rgb");
+    for (w, h) in SIZES {
+        let yuv: Vec<u8> = (0..i420_len(w, h)).map(|i| (i * 7) as u8).collect();
+        let mut dest = vec![0u8; w * h * 3];
+        group.throughput(Throughput::Elements((w * h) as u64));
+        for backend in Backend::available() {
+            let id = BenchmarkId::new(format!("{:?}", backend), format!("{}x{}", w, h));
+            group.bench_function(id, |b| {
+                b.iter(|| i420_to_rgb_with(backend, SPACE, w, h, &yuv, &mut dest))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
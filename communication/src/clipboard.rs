@@ -0,0 +1,401 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+/// 剪贴板内容的默认大小上限
+pub const DEFAULT_MAX_SIZE: usize = 8 * 1024 * 1024;
+
+// 内容格式
+const TEXT: u8 = 1;
+const IMAGE: u8 = 2;
+
+/// 剪贴板内容
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClipboardData {
+    Text(String),
+    /// RGBA 像素
+    Image {
+        width: u32,
+        height: u32,
+        rgba: Vec<u8>,
+    },
+}
+
+/*
+剪贴板内容字节序
++--------+----------------------------------+
+| format |              data                |
++--------+----------------------------------+
+|   1    | TEXT: utf8                       |
+|        | IMAGE: width(4) height(4) rgba   |
++--------+----------------------------------+
+*/
+impl ClipboardData {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            ClipboardData::Text(text) => {
+                let mut data = Vec::with_capacity(1 + text.len());
+                data.push(TEXT);
+                data.extend_from_slice(text.as_bytes());
+                data
+            }
+            ClipboardData::Image {
+                width,
+                height,
+                rgba,
+            } => {
+                let mut data = Vec::with_capacity(9 + rgba.len());
+                data.push(IMAGE);
+                data.extend_from_slice(&width.to_be_bytes());
+                data.extend_from_slice(&height.to_be_bytes());
+                data.extend_from_slice(rgba);
+                data
+            }
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Option<ClipboardData> {
+        match *data.first()? {
+            TEXT => String::from_utf8(data[1..].to_vec())
+                .ok()
+                .map(ClipboardData::Text),
+            IMAGE if data.len() >= 9 => {
+                let width = u32::from_be_bytes([data[1], data[2], data[3], data[4]]);
+                let height = u32::from_be_bytes([data[5], data[6], data[7], data[8]]);
+                let rgba = data[9..].to_vec();
+                if rgba.len() as u64 != width as u64 * height as u64 * 4 {
+                    return None;
+                }
+                Some(ClipboardData::Image {
+                    width,
+                    height,
+                    rgba,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// 编码后的大小
+    pub fn size(&self) -> usize {
+        match self {
+            ClipboardData::Text(text) => 1 + text.len(),
+            ClipboardData::Image { rgba, .. } => 9 + rgba.len(),
+        }
+    }
+
+    fn digest(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        match self {
+            ClipboardData::Text(text) => {
+                hasher.write_u8(TEXT);
+                hasher.write(text.as_bytes());
+            }
+            ClipboardData::Image {
+                width,
+                height,
+                rgba,
+            } => {
+                hasher.write_u8(IMAGE);
+                hasher.write_u32(*width);
+                hasher.write_u32(*height);
+                hasher.write(rgba);
+            }
+        }
+        hasher.finish()
+    }
+}
+
+/// 剪贴板访问, 方便测试时替换为内存剪贴板
+pub trait Clipboard {
+    fn get(&mut self) -> Option<ClipboardData>;
+    fn set(&mut self, data: &ClipboardData) -> bool;
+}
+
+/// 内存剪贴板
+#[derive(Debug, Default)]
+pub struct MemoryClipboard {
+    pub data: Option<ClipboardData>,
+}
+
+impl Clipboard for MemoryClipboard {
+    fn get(&mut self) -> Option<ClipboardData> {
+        self.data.clone()
+    }
+
+    fn set(&mut self, data: &ClipboardData) -> bool {
+        self.data = Some(data.clone());
+        true
+    }
+}
+
+/// 系统剪贴板
+#[cfg(feature = "system-clipboard")]
+pub struct SystemClipboard {
+    inner: Option<arboard::Clipboard>,
+}
+
+#[cfg(feature = "system-clipboard")]
+impl SystemClipboard {
+    pub fn new() -> SystemClipboard {
+        SystemClipboard {
+            inner: arboard::Clipboard::new().ok(),
+        }
+    }
+}
+
+#[cfg(feature = "system-clipboard")]
+impl Default for SystemClipboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "system-clipboard")]
+impl Clipboard for SystemClipboard {
+    fn get(&mut self) -> Option<ClipboardData> {
+        let inner = self.inner.as_mut()?;
+        if let Ok(text) = inner.get_text() {
+            return Some(ClipboardData::Text(text));
+        }
+        let image = inner.get_image().ok()?;
+        Some(ClipboardData::Image {
+            width: image.width as u32,
+            height: image.height as u32,
+            rgba: image.bytes.into_owned(),
+        })
+    }
+
+    fn set(&mut self, data: &ClipboardData) -> bool {
+        let inner = match self.inner.as_mut() {
+            Some(inner) => inner,
+            None => return false,
+        };
+        match data {
+            ClipboardData::Text(text) => inner.set_text(text.clone()).is_ok(),
+            ClipboardData::Image {
+                width,
+                height,
+                rgba,
+            } => inner
+                .set_image(arboard::ImageData {
+                    width: *width as usize,
+                    height: *height as usize,
+                    bytes: rgba.into(),
+                })
+                .is_ok(),
+        }
+    }
+}
+
+/// 同步策略, 由client选择, 方向以client为准
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Off = 0,
+    /// client -> server
+    ToRemote = 1,
+    /// server -> client
+    FromRemote = 2,
+    Both = 3,
+}
+
+impl Policy {
+    pub fn from_u8(v: u8) -> Option<Policy> {
+        match v {
+            0 => Some(Policy::Off),
+            1 => Some(Policy::ToRemote),
+            2 => Some(Policy::FromRemote),
+            3 => Some(Policy::Both),
+            _ => None,
+        }
+    }
+
+    fn client_sends(self) -> bool {
+        self == Policy::ToRemote || self == Policy::Both
+    }
+
+    fn server_sends(self) -> bool {
+        self == Policy::FromRemote || self == Policy::Both
+    }
+}
+
+/// 同步的一端
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+/// 剪贴板同步
+/// 通过内容摘要检测变化, 对端写入的内容不会再被发送回去
+pub struct ClipboardSync<C: Clipboard> {
+    clipboard: C,
+    side: Side,
+    policy: Policy,
+    max_size: usize,
+    // 最近一次同步的内容摘要
+    last: Option<u64>,
+}
+
+impl<C: Clipboard> ClipboardSync<C> {
+    pub fn new(clipboard: C, side: Side, policy: Policy, max_size: usize) -> Self {
+        let mut sync = ClipboardSync {
+            clipboard,
+            side,
+            policy,
+            max_size,
+            last: None,
+        };
+        // 已有的内容不算变化
+        sync.last = sync.clipboard.get().map(|data| data.digest());
+        sync
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    fn outgoing(&self) -> bool {
+        match self.side {
+            Side::Client => self.policy.client_sends(),
+            Side::Server => self.policy.server_sends(),
+        }
+    }
+
+    fn incoming(&self) -> bool {
+        match self.side {
+            Side::Client => self.policy.server_sends(),
+            Side::Server => self.policy.client_sends(),
+        }
+    }
+
+    /// 检查本地剪贴板, 有变化且允许发送时返回编码后的内容
+    pub fn poll(&mut self) -> Option<Vec<u8>> {
+        let data = self.clipboard.get()?;
+        let digest = data.digest();
+        if self.last == Some(digest) {
+            return None;
+        }
+        self.last = Some(digest);
+        if !self.outgoing() || data.size() > self.max_size {
+            return None;
+        }
+        Some(data.encode())
+    }
+
+    /// 写入对端发来的内容, 返回是否写入
+    pub fn apply(&mut self, data: &[u8]) -> bool {
+        if !self.incoming() || data.len() > self.max_size {
+            return false;
+        }
+        let data = match ClipboardData::decode(data) {
+            Some(data) => data,
+            None => return false,
+        };
+        if !self.clipboard.set(&data) {
+            return false;
+        }
+        self.last = Some(data.digest());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> ClipboardData {
+        ClipboardData::Text(s.to_string())
+    }
+
+    fn sync(side: Side, policy: Policy) -> ClipboardSync<MemoryClipboard> {
+        ClipboardSync::new(MemoryClipboard::default(), side, policy, 64)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let image = ClipboardData::Image {
+            width: 2,
+            height: 1,
+            rgba: vec![1, 2, 3, 4, 5, 6, 7, 8],
+        };
+        for data in [text("hello 你好"), image] {
+            assert_eq!(ClipboardData::decode(&data.encode()), Some(data.clone()));
+            assert_eq!(data.encode().len(), data.size());
+        }
+        assert_eq!(ClipboardData::decode(&[IMAGE, 0, 0, 0, 1, 0, 0, 0, 1]), None);
+        assert_eq!(ClipboardData::decode(&[TEXT, 0xff]), None);
+        assert_eq!(ClipboardData::decode(&[]), None);
+    }
+
+    #[test]
+    fn test_change_detection() {
+        let mut client = sync(Side::Client, Policy::Both);
+        assert_eq!(client.poll(), None);
+        client.clipboard.data = Some(text("a"));
+        assert_eq!(client.poll(), Some(text("a").encode()));
+        // 没有变化
+        assert_eq!(client.poll(), None);
+        client.clipboard.data = Some(text("b"));
+        assert_eq!(client.poll(), Some(text("b").encode()));
+    }
+
+    #[test]
+    fn test_no_echo() {
+        let mut client = sync(Side::Client, Policy::Both);
+        let mut server = sync(Side::Server, Policy::Both);
+        client.clipboard.data = Some(text("cmd"));
+        let msg = client.poll().unwrap();
+        assert!(server.apply(&msg));
+        assert_eq!(server.clipboard.data, Some(text("cmd")));
+        // 对端写入的内容不会被发回
+        assert_eq!(server.poll(), None);
+    }
+
+    #[test]
+    fn test_policy() {
+        let msg = text("x").encode();
+
+        let mut client = sync(Side::Client, Policy::ToRemote);
+        let mut server = sync(Side::Server, Policy::ToRemote);
+        client.clipboard.data = Some(text("x"));
+        server.clipboard.data = Some(text("y"));
+        assert!(client.poll().is_some());
+        assert!(server.poll().is_none());
+        assert!(server.apply(&msg));
+        assert!(!client.apply(&msg));
+
+        let mut client = sync(Side::Client, Policy::FromRemote);
+        let mut server = sync(Side::Server, Policy::FromRemote);
+        client.clipboard.data = Some(text("x"));
+        server.clipboard.data = Some(text("y"));
+        assert!(client.poll().is_none());
+        assert!(server.poll().is_some());
+        assert!(!server.apply(&msg));
+        assert!(client.apply(&msg));
+
+        let mut server = sync(Side::Server, Policy::Off);
+        server.clipboard.data = Some(text("y"));
+        assert!(server.poll().is_none());
+        assert!(!server.apply(&msg));
+        server.set_policy(Policy::Both);
+        assert!(server.apply(&msg));
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let mut client = sync(Side::Client, Policy::Both);
+        let big = text(&"a".repeat(100));
+        client.clipboard.data = Some(big.clone());
+        assert_eq!(client.poll(), None);
+        assert!(!client.apply(&big.encode()));
+        assert_eq!(client.clipboard.data, Some(big));
+    }
+}
@@ -1,70 +1,735 @@
-pub fn bgra_to_i420(width: usize, height: usize, src: &[u8], dest: &mut Vec<u8>) {
-    let stride = src.len() / height;
-    unsafe {
-        dest.set_len(0);
+use rayon::prelude::*;
+use std::sync::OnceLock;
+
+#[cfg(target_arch = "aarch64")]
+mod neon;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86;
+
+/// 色度平面的宽高, 奇数尺寸向上取整, 最后一列(行)单独占一个采样
+pub fn chroma_size(width: usize, height: usize) -> (usize, usize) {
+    (width.div_ceil(2), height.div_ceil(2))
+}
+
+/// I420 一帧的字节数
+pub fn i420_len(width: usize, height: usize) -> usize {
+    let (cw, ch) = chroma_size(width, height);
+    width * height + cw * ch * 2
+}
+
+/// 画面的像素格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    // 色度减半的 YUV, 数据量小
+    I420 = 0,
+    // 无损的 RGB, 彩色文字不会模糊, 数据量约为 I420 的两倍
+    Rgb = 1,
+}
+
+impl PixelFormat {
+    pub fn from_u8(v: u8) -> Option<PixelFormat> {
+        match v {
+            0 => Some(PixelFormat::I420),
+            1 => Some(PixelFormat::Rgb),
+            _ => None,
+        }
     }
 
-    for y in 0..height {
-        for x in 0..width {
-            let o = y * stride + 4 * x;
+    /// 一帧的字节数
+    pub fn frame_len(self, width: usize, height: usize) -> usize {
+        match self {
+            PixelFormat::I420 => i420_len(width, height),
+            PixelFormat::Rgb => width * height * 3,
+        }
+    }
+}
 
-            let b = src[o] as i32;
-            let g = src[o + 1] as i32;
-            let r = src[o + 2] as i32;
+/// BGRA 转 RGB, stride 为源图像每行的字节数, 输出没有行填充
+pub fn bgra_to_rgb(width: usize, height: usize, stride: usize, src: &[u8], dest: &mut Vec<u8>) {
+    assert!(
+        height == 0 || (stride >= width * 4 && src.len() >= stride * (height - 1) + width * 4),
+        "source too short for {}x{}",
+        width,
+        height
+    );
+    dest.clear();
+    if width == 0 || height == 0 {
+        return;
+    }
+    dest.resize(width * height * 3, 0);
+    dest.par_chunks_mut(width * 3)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let line = &src[y * stride..y * stride + width * 4];
+            for (rgb, px) in row.chunks_exact_mut(3).zip(line.chunks_exact(4)) {
+                rgb.copy_from_slice(&[px[2], px[1], px[0]]);
+            }
+        });
+}
 
-            let y = (66 * r + 129 * g + 25 * b + 128) / 256 + 16;
-            dest.push(clamp(y));
+/// 颜色转换的实现, 运行时根据 CPU 选择, 结果与 Scalar 逐字节相同
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Scalar,
+    Sse2,
+    Avx2,
+    Neon,
+}
+
+impl Backend {
+    /// 当前 CPU 支持的最快实现
+    pub fn detect() -> Backend {
+        static BACKEND: OnceLock<Backend> = OnceLock::new();
+        *BACKEND.get_or_init(|| {
+            [Backend::Avx2, Backend::Neon, Backend::Sse2]
+                .into_iter()
+                .find(|b| b.supported())
+                .unwrap_or(Backend::Scalar)
+        })
+    }
+
+    /// 当前 CPU 支持的所有实现, 用于测试和性能对比
+    pub fn available() -> Vec<Backend> {
+        [Backend::Scalar, Backend::Sse2, Backend::Avx2, Backend::Neon]
+            .into_iter()
+            .filter(|b| b.supported())
+            .collect()
+    }
+
+    pub fn supported(self) -> bool {
+        match self {
+            Backend::Scalar => true,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Sse2 => is_x86_feature_detected!("sse2"),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "aarch64")]
+            Backend::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            #[allow(unreachable_patterns)]
+            _ => false,
         }
     }
 
-    for y in (0..height).step_by(2) {
-        for x in (0..width).step_by(2) {
-            let o = y * stride + 4 * x;
+    fn kernels(self) -> Kernels {
+        assert!(self.supported(), "{:?} is not supported on this CPU", self);
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Sse2 => x86::SSE2,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Avx2 => x86::AVX2,
+            #[cfg(target_arch = "aarch64")]
+            Backend::Neon => neon::NEON,
+            _ => SCALAR,
+        }
+    }
+}
+
+// 一行 BGRA 转为亮度, 像素数为输出的长度
+type YRow = fn(&[u8], &mut [u8], &Encode);
+// 一行 BGRA 取偶数列转为色度
+type UvRow = fn(&[u8], &mut [u8], &mut [u8], &Encode);
+// 一行 I420 转为 RGB, 色度为所在行的色度
+type RgbRow = fn(&[u8], &[u8], &[u8], &mut [u8], &Decode);
 
-            let b = src[o] as i32;
-            let g = src[o + 1] as i32;
-            let r = src[o + 2] as i32;
+/// 按行处理的函数, 由 Backend 选择
+#[derive(Clone, Copy)]
+struct Kernels {
+    y_row: YRow,
+    uv_row: UvRow,
+    rgb_row: RgbRow,
+}
+
+const SCALAR: Kernels = Kernels {
+    y_row,
+    uv_row,
+    rgb_row,
+};
 
-            let u = (-38 * r - 74 * g + 112 * b + 128) / 256 + 128;
-            dest.push(clamp(u));
+/// 颜色矩阵
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Matrix {
+    // 标清
+    Bt601,
+    // 高清
+    Bt709,
+}
+
+/// 取值范围
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Range {
+    // Y 为 16~235, U V 为 16~240
+    Limited,
+    // 都为 0~255
+    Full,
+}
+
+/// 视频流的颜色空间, server在 VIDEO_DISPLAY 中告诉client, 两端使用对应的矩阵转换
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorSpace {
+    pub matrix: Matrix,
+    pub range: Range,
+}
+
+impl Default for ColorSpace {
+    fn default() -> Self {
+        ColorSpace {
+            matrix: Matrix::Bt601,
+            range: Range::Limited,
         }
     }
+}
 
-    for y in (0..height).step_by(2) {
-        for x in (0..width).step_by(2) {
-            let o = y * stride + 4 * x;
+impl ColorSpace {
+    /// 所有的组合
+    pub const ALL: [ColorSpace; 4] = [
+        ColorSpace {
+            matrix: Matrix::Bt601,
+            range: Range::Limited,
+        },
+        ColorSpace {
+            matrix: Matrix::Bt601,
+            range: Range::Full,
+        },
+        ColorSpace {
+            matrix: Matrix::Bt709,
+            range: Range::Limited,
+        },
+        ColorSpace {
+            matrix: Matrix::Bt709,
+            range: Range::Full,
+        },
+    ];
 
-            let b = src[o] as i32;
-            let g = src[o + 1] as i32;
-            let r = src[o + 2] as i32;
+    /// 第 0 位为范围, 第 1 位为矩阵
+    pub fn to_u8(self) -> u8 {
+        let matrix = match self.matrix {
+            Matrix::Bt601 => 0,
+            Matrix::Bt709 => 2,
+        };
+        let range = match self.range {
+            Range::Limited => 0,
+            Range::Full => 1,
+        };
+        matrix | range
+    }
 
-            let v = (112 * r - 94 * g - 18 * b + 128) / 256 + 128;
-            dest.push(clamp(v));
+    pub fn from_u8(v: u8) -> Option<ColorSpace> {
+        ColorSpace::ALL.into_iter().find(|c| c.to_u8() == v)
+    }
+
+    fn encode(self) -> &'static Encode {
+        match (self.matrix, self.range) {
+            (Matrix::Bt601, Range::Limited) => &BT601_LIMITED.0,
+            (Matrix::Bt601, Range::Full) => &BT601_FULL.0,
+            (Matrix::Bt709, Range::Limited) => &BT709_LIMITED.0,
+            (Matrix::Bt709, Range::Full) => &BT709_FULL.0,
+        }
+    }
+
+    fn decode(self) -> &'static Decode {
+        match (self.matrix, self.range) {
+            (Matrix::Bt601, Range::Limited) => &BT601_LIMITED.1,
+            (Matrix::Bt601, Range::Full) => &BT601_FULL.1,
+            (Matrix::Bt709, Range::Limited) => &BT709_LIMITED.1,
+            (Matrix::Bt709, Range::Full) => &BT709_FULL.1,
         }
     }
 }
 
+/// RGB 转 YUV 的系数, 8 位定点, 顺序为 r, g, b
+/// 结果为 ((k · rgb + 128) >> 8) + 偏移, U V 的偏移为 128
+#[derive(Debug, Clone, Copy)]
+struct Encode {
+    y: [i32; 3],
+    u: [i32; 3],
+    v: [i32; 3],
+    y_offset: i32,
+}
+
+impl Encode {
+    #[inline(always)]
+    fn dot(k: &[i32; 3], px: &[u8]) -> i32 {
+        (k[0] * px[2] as i32 + k[1] * px[1] as i32 + k[2] * px[0] as i32 + 128) >> 8
+    }
+}
+
+/// YUV 转 RGB 的系数, 8 位定点, 是 Encode 的逆矩阵
+/// c = (Y - y_offset) * y + 128, u v 为减去 128 后的色度
+/// r = (c + rv * v) >> 8, g = (c - gu * u - gv * v) >> 8, b = (c + bu * u) >> 8
+#[derive(Debug, Clone, Copy)]
+struct Decode {
+    y: i32,
+    y_offset: i32,
+    rv: i32,
+    gu: i32,
+    gv: i32,
+    bu: i32,
+}
+
+impl Decode {
+    #[inline(always)]
+    fn rgb(&self, y: i32, u: i32, v: i32) -> [u8; 3] {
+        let c = (y - self.y_offset) * self.y + 128;
+        [
+            clamp((c + self.rv * v) >> 8),
+            clamp((c - self.gu * u - self.gv * v) >> 8),
+            clamp((c + self.bu * u) >> 8),
+        ]
+    }
+}
+
+// Kr = 0.299, Kb = 0.114
+const BT601_LIMITED: (Encode, Decode) = (
+    Encode {
+        y: [66, 129, 25],
+        u: [-38, -74, 112],
+        v: [112, -94, -18],
+        y_offset: 16,
+    },
+    Decode {
+        y: 298,
+        y_offset: 16,
+        rv: 409,
+        gu: 100,
+        gv: 208,
+        bu: 516,
+    },
+);
+
+const BT601_FULL: (Encode, Decode) = (
+    Encode {
+        y: [77, 150, 29],
+        u: [-43, -85, 128],
+        v: [128, -107, -21],
+        y_offset: 0,
+    },
+    Decode {
+        y: 256,
+        y_offset: 0,
+        rv: 359,
+        gu: 88,
+        gv: 183,
+        bu: 454,
+    },
+);
+
+// Kr = 0.2126, Kb = 0.0722
+const BT709_LIMITED: (Encode, Decode) = (
+    Encode {
+        y: [47, 157, 16],
+        u: [-26, -87, 113],
+        v: [112, -102, -10],
+        y_offset: 16,
+    },
+    Decode {
+        y: 298,
+        y_offset: 16,
+        rv: 459,
+        gu: 55,
+        gv: 136,
+        bu: 541,
+    },
+);
+
+const BT709_FULL: (Encode, Decode) = (
+    Encode {
+        y: [54, 183, 19],
+        u: [-29, -99, 128],
+        v: [128, -116, -12],
+        y_offset: 0,
+    },
+    Decode {
+        y: 256,
+        y_offset: 0,
+        rv: 403,
+        gu: 48,
+        gv: 120,
+        bu: 475,
+    },
+);
+
+/// BGRA 转 I420, stride 为源图像每行的字节数
+/// 色度取每个 2x2 块左上角的像素
+pub fn bgra_to_i420(
+    space: ColorSpace,
+    width: usize,
+    height: usize,
+    stride: usize,
+    src: &[u8],
+    dest: &mut Vec<u8>,
+) {
+    bgra_to_i420_with(Backend::detect(), space, width, height, stride, src, dest);
+}
+
+/// 使用指定的实现转换, 当前 CPU 不支持时 panic
+pub fn bgra_to_i420_with(
+    backend: Backend,
+    space: ColorSpace,
+    width: usize,
+    height: usize,
+    stride: usize,
+    src: &[u8],
+    dest: &mut Vec<u8>,
+) {
+    assert!(
+        stride >= width * 4,
+        "stride {} < width {} * 4",
+        stride,
+        width
+    );
+    assert!(
+        height == 0 || src.len() >= stride * (height - 1) + width * 4,
+        "source too short for {}x{}",
+        width,
+        height
+    );
+    let k = backend.kernels();
+    let m = space.encode();
+    dest.clear();
+    if width == 0 || height == 0 {
+        return;
+    }
+    dest.resize(i420_len(width, height), 0);
+    let (cw, ch) = chroma_size(width, height);
+    let (py, rest) = dest.split_at_mut(width * height);
+    let (pu, pv) = rest.split_at_mut(cw * ch);
+    let row = |y: usize| &src[y * stride..y * stride + width * 4];
+
+    py.par_chunks_mut(width)
+        .enumerate()
+        .for_each(|(y, dst)| (k.y_row)(row(y), dst, m));
+    pu.par_chunks_mut(cw)
+        .zip(pv.par_chunks_mut(cw))
+        .enumerate()
+        .for_each(|(y, (u, v))| (k.uv_row)(row(y * 2), u, v, m));
+}
+
 fn clamp(x: i32) -> u8 {
-    x.min(255).max(0) as u8
+    x.clamp(0, 255) as u8
+}
+
+fn y_row(src: &[u8], dst: &mut [u8], m: &Encode) {
+    for (px, y) in src.chunks_exact(4).zip(dst.iter_mut()) {
+        *y = clamp(Encode::dot(&m.y, px) + m.y_offset);
+    }
 }
 
-pub fn i420_to_rgb(width: usize, height: usize, sy: &[u8], su: &[u8], sv: &[u8], dest: &mut [u8]) {
-    let uvw = width >> 1;
-    for i in 0..height {
-        let sw = i * width;
-        let t = (i >> 1) * uvw;
-        for j in 0..width {
-            let mut rgbstart = sw + j;
-            let uvi = t + (j >> 1);
+fn uv_row(src: &[u8], u: &mut [u8], v: &mut [u8], m: &Encode) {
+    for ((px, u), v) in src.chunks(8).zip(u.iter_mut()).zip(v.iter_mut()) {
+        *u = clamp(Encode::dot(&m.u, px) + 128);
+        *v = clamp(Encode::dot(&m.v, px) + 128);
+    }
+}
 
-            let y = sy[rgbstart] as i32;
-            let u = su[uvi] as i32 - 128;
-            let v = sv[uvi] as i32 - 128;
+/// I420 转 RGB, src 为完整的一帧, 输出没有行填充
+pub fn i420_to_rgb(space: ColorSpace, width: usize, height: usize, src: &[u8], dest: &mut [u8]) {
+    i420_to_rgb_with(Backend::detect(), space, width, height, src, dest);
+}
 
-            rgbstart *= 3;
-            dest[rgbstart] = clamp(y + (v * 359 >> 8));
-            dest[rgbstart + 1] = clamp(y - (u * 88 >> 8) - (v * 182 >> 8));
-            dest[rgbstart + 2] = clamp(y + (u * 453 >> 8));
+/// 使用指定的实现转换, 当前 CPU 不支持时 panic
+pub fn i420_to_rgb_with(
+    backend: Backend,
+    space: ColorSpace,
+    width: usize,
+    height: usize,
+    src: &[u8],
+    dest: &mut [u8],
+) {
+    let (cw, ch) = chroma_size(width, height);
+    assert!(src.len() >= i420_len(width, height) && dest.len() >= width * height * 3);
+    let k = backend.kernels();
+    let m = space.decode();
+    if width == 0 || height == 0 {
+        return;
+    }
+    let (sy, rest) = src.split_at(width * height);
+    let (su, sv) = rest.split_at(cw * ch);
+    dest[..width * height * 3]
+        .par_chunks_mut(width * 3)
+        .enumerate()
+        .for_each(|(i, dst)| {
+            let t = (i >> 1) * cw;
+            (k.rgb_row)(
+                &sy[i * width..(i + 1) * width],
+                &su[t..t + cw],
+                &sv[t..t + cw],
+                dst,
+                m,
+            );
+        });
+}
+
+fn rgb_row(sy: &[u8], su: &[u8], sv: &[u8], dest: &mut [u8], m: &Decode) {
+    for (j, (y, rgb)) in sy.iter().zip(dest.chunks_exact_mut(3)).enumerate() {
+        let u = su[j >> 1] as i32 - 128;
+        let v = sv[j >> 1] as i32 - 128;
+        rgb.copy_from_slice(&m.rgb(*y as i32, u, v));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 测试用的伪随机数
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
         }
     }
+
+    /// 逐像素计算的参考实现
+    fn reference_yuv(r: i32, g: i32, b: i32) -> (u8, u8, u8) {
+        (
+            clamp(((66 * r + 129 * g + 25 * b + 128) >> 8) + 16),
+            clamp(((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128),
+            clamp(((112 * r - 94 * g - 18 * b + 128) >> 8) + 128),
+        )
+    }
+
+    #[test]
+    fn test_random_dimensions() {
+        let mut rng = XorShift(0x9e3779b97f4a7c15);
+        let mut yuv = Vec::new();
+        for _ in 0..200 {
+            let (w, h) = (1 + rng.below(37), 1 + rng.below(37));
+            let stride = w * 4 + rng.below(3) * 4 + rng.below(4);
+            // 最后一行可以没有填充
+            let len = stride * (h - 1) + w * 4;
+            let src: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+            bgra_to_i420_with(
+                Backend::Scalar,
+                ColorSpace::default(),
+                w,
+                h,
+                stride,
+                &src,
+                &mut yuv,
+            );
+
+            let (cw, ch) = chroma_size(w, h);
+            assert_eq!(yuv.len(), i420_len(w, h));
+            let (py, rest) = yuv.split_at(w * h);
+            let (pu, pv) = rest.split_at(cw * ch);
+            let pixel = |x: usize, y: usize| {
+                let o = y * stride + x * 4;
+                reference_yuv(src[o + 2] as i32, src[o + 1] as i32, src[o] as i32)
+            };
+            for y in 0..h {
+                for x in 0..w {
+                    assert_eq!(py[y * w + x], pixel(x, y).0, "{}x{} y at {},{}", w, h, x, y);
+                }
+            }
+            for y in 0..ch {
+                for x in 0..cw {
+                    let (_, u, v) = pixel(x * 2, y * 2);
+                    assert_eq!((pu[y * cw + x], pv[y * cw + x]), (u, v), "{}x{}", w, h);
+                }
+            }
+
+            // 每个像素使用所在 2x2 块的色度
+            let mut rgb = vec![0u8; w * h * 3];
+            i420_to_rgb_with(Backend::Scalar, ColorSpace::default(), w, h, &yuv, &mut rgb);
+            for y in 0..h {
+                for x in 0..w {
+                    let c = (py[y * w + x] as i32 - 16) * 298 + 128;
+                    let u = pu[(y / 2) * cw + x / 2] as i32 - 128;
+                    let v = pv[(y / 2) * cw + x / 2] as i32 - 128;
+                    let expect = [
+                        clamp((c + 409 * v) >> 8),
+                        clamp((c - 100 * u - 208 * v) >> 8),
+                        clamp((c + 516 * u) >> 8),
+                    ];
+                    let o = (y * w + x) * 3;
+                    assert_eq!(rgb[o..o + 3], expect, "{}x{} rgb at {},{}", w, h, x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_backends_match_scalar() {
+        let mut rng = XorShift(0x2545f4914f6cdd1d);
+        let (mut expect, mut actual) = (Vec::new(), Vec::new());
+        for i in 0..100 {
+            let space = ColorSpace::ALL[i % 4];
+            // 覆盖向量化部分和剩余的像素
+            let (w, h) = (1 + rng.below(150), 1 + rng.below(9));
+            let stride = w * 4 + rng.below(5);
+            let len = stride * (h - 1) + w * 4;
+            let src: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+            bgra_to_i420_with(Backend::Scalar, space, w, h, stride, &src, &mut expect);
+
+            let yuv: Vec<u8> = (0..i420_len(w, h)).map(|_| rng.next() as u8).collect();
+            let mut rgb_expect = vec![0u8; w * h * 3];
+            i420_to_rgb_with(Backend::Scalar, space, w, h, &yuv, &mut rgb_expect);
+
+            for backend in Backend::available() {
+                bgra_to_i420_with(backend, space, w, h, stride, &src, &mut actual);
+                assert!(
+                    expect == actual,
+                    "{:?} {:?} i420 {}x{}",
+                    backend,
+                    space,
+                    w,
+                    h
+                );
+                let mut rgb = vec![0u8; w * h * 3];
+                i420_to_rgb_with(backend, space, w, h, &yuv, &mut rgb);
+                assert!(
+                    rgb_expect == rgb,
+                    "{:?} {:?} rgb {}x{}",
+                    backend,
+                    space,
+                    w,
+                    h
+                );
+            }
+        }
+    }
+
+    /// 按标准公式用浮点数计算的 YUV, 范围为 Limited 时已缩放和加偏移
+    fn float_yuv(space: ColorSpace, rgb: [f64; 3]) -> [f64; 3] {
+        let (kr, kb) = match space.matrix {
+            Matrix::Bt601 => (0.299, 0.114),
+            Matrix::Bt709 => (0.2126, 0.0722),
+        };
+        let [r, g, b] = rgb;
+        let y = kr * r + (1.0 - kr - kb) * g + kb * b;
+        let u = (b - y) / (2.0 * (1.0 - kb));
+        let v = (r - y) / (2.0 * (1.0 - kr));
+        match space.range {
+            Range::Limited => [
+                16.0 + y * 219.0 / 255.0,
+                128.0 + u * 224.0 / 255.0,
+                128.0 + v * 224.0 / 255.0,
+            ],
+            Range::Full => [y, 128.0 + u, 128.0 + v],
+        }
+    }
+
+    #[test]
+    fn test_matrices_match_standard() {
+        let mut rng = XorShift(0x853c49e6748fea9b);
+        for space in ColorSpace::ALL {
+            assert_eq!(ColorSpace::from_u8(space.to_u8()), Some(space));
+            for _ in 0..2000 {
+                let px = [rng.next() as u8, rng.next() as u8, rng.next() as u8, 255];
+                let rgb = [px[2] as f64, px[1] as f64, px[0] as f64];
+                let expect = float_yuv(space, rgb);
+                let m = space.encode();
+                let actual = [
+                    Encode::dot(&m.y, &px) + m.y_offset,
+                    Encode::dot(&m.u, &px) + 128,
+                    Encode::dot(&m.v, &px) + 128,
+                ];
+                for (a, e) in actual.iter().zip(expect) {
+                    assert!((*a as f64 - e).abs() <= 1.0, "{:?} {:?}", space, rgb);
+                }
+            }
+        }
+        assert_eq!(ColorSpace::from_u8(4), None);
+    }
+
+    /// RGB 经过 I420 再转回 RGB 的最大和平均误差
+    fn round_trip(space: ColorSpace, rng: &mut XorShift) -> (u8, f64) {
+        // 每个 2x2 块颜色相同, 误差只来自矩阵和量化
+        let (w, h) = (64, 64);
+        let mut src = vec![0u8; w * h * 4];
+        for by in 0..h / 2 {
+            for bx in 0..w / 2 {
+                let px = [rng.next() as u8, rng.next() as u8, rng.next() as u8, 255];
+                for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let o = ((by * 2 + y) * w + bx * 2 + x) * 4;
+                    src[o..o + 4].copy_from_slice(&px);
+                }
+            }
+        }
+        let mut yuv = Vec::new();
+        let mut rgb = vec![0u8; w * h * 3];
+        bgra_to_i420(space, w, h, w * 4, &src, &mut yuv);
+        i420_to_rgb(space, w, h, &yuv, &mut rgb);
+        let (mut max, mut sum) = (0u8, 0u64);
+        for (px, out) in src.chunks_exact(4).zip(rgb.chunks_exact(3)) {
+            for (a, b) in [px[2], px[1], px[0]].iter().zip(out) {
+                let d = a.abs_diff(*b);
+                max = max.max(d);
+                sum += d as u64;
+            }
+        }
+        (max, sum as f64 / (w * h * 3) as f64)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = XorShift(0xda942042e4dd58b5);
+        for space in ColorSpace::ALL {
+            let (max, mean) = round_trip(space, &mut rng);
+            // Limited 只有 219 级亮度, 误差略大
+            let limit = match space.range {
+                Range::Limited => 4,
+                Range::Full => 3,
+            };
+            assert!(max <= limit, "{:?} max error {}", space, max);
+            assert!(mean < 1.0, "{:?} mean error {}", space, mean);
+        }
+    }
+
+    #[test]
+    fn test_grey_levels() {
+        for space in ColorSpace::ALL {
+            let (black, white) = match space.range {
+                Range::Limited => (16, 235),
+                Range::Full => (0, 255),
+            };
+            for (v, y) in [(0u8, black), (255, white)] {
+                let mut yuv = Vec::new();
+                bgra_to_i420(space, 2, 2, 8, &[v; 16], &mut yuv);
+                assert_eq!(yuv, [y, y, y, y, 128, 128], "{:?}", space);
+                let mut rgb = [0u8; 12];
+                i420_to_rgb(space, 2, 2, &yuv, &mut rgb);
+                assert_eq!(rgb, [v; 12], "{:?}", space);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rgb() {
+        // 2x2, 每行有 4 字节填充
+        let src = [
+            1, 2, 3, 255, 4, 5, 6, 255, 0, 0, 0, 0, //
+            7, 8, 9, 255, 10, 11, 12, 255,
+        ];
+        let mut rgb = vec![0xff; 1];
+        bgra_to_rgb(2, 2, 12, &src, &mut rgb);
+        assert_eq!(rgb, [3, 2, 1, 6, 5, 4, 9, 8, 7, 12, 11, 10]);
+        assert_eq!(PixelFormat::Rgb.frame_len(2, 2), rgb.len());
+        assert_eq!(
+            PixelFormat::from_u8(PixelFormat::Rgb as u8),
+            Some(PixelFormat::Rgb)
+        );
+        assert_eq!(PixelFormat::from_u8(2), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_short_source() {
+        let mut yuv = Vec::new();
+        bgra_to_i420(
+            ColorSpace::default(),
+            3,
+            3,
+            16,
+            &[0u8; 16 * 2 + 11],
+            &mut yuv,
+        );
+    }
 }
@@ -0,0 +1,136 @@
+use std::arch::aarch64::*;
+
+use super::Decode;
+use super::Encode;
+use super::Kernels;
+
+// 只在 Backend::supported 检查过 CPU 之后使用
+pub(super) const NEON: Kernels = Kernels {
+    y_row: |src, dst, m| unsafe { y_row(src, dst, m) },
+    uv_row: |src, u, v, m| unsafe { uv_row(src, u, v, m) },
+    rgb_row: |y, u, v, dst, m| unsafe { rgb_row(y, u, v, dst, m) },
+};
+
+/// 8 个像素的 ((k · rgb + 128) >> 8) + offset, 结果为 16 位
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn dot(r: uint8x8_t, g: uint8x8_t, b: uint8x8_t, k: &[i32; 3], offset: i32) -> int16x8_t {
+    let r = vreinterpretq_s16_u16(vmovl_u8(r));
+    let g = vreinterpretq_s16_u16(vmovl_u8(g));
+    let b = vreinterpretq_s16_u16(vmovl_u8(b));
+    let half = |r: int16x4_t, g: int16x4_t, b: int16x4_t| {
+        let sum = vmull_n_s16(r, k[0] as i16);
+        let sum = vmlal_n_s16(sum, g, k[1] as i16);
+        let sum = vmlal_n_s16(sum, b, k[2] as i16);
+        let sum = vshrq_n_s32::<8>(vaddq_s32(sum, vdupq_n_s32(128)));
+        vqmovn_s32(vaddq_s32(sum, vdupq_n_s32(offset)))
+    };
+    vcombine_s16(
+        half(vget_low_s16(r), vget_low_s16(g), vget_low_s16(b)),
+        half(vget_high_s16(r), vget_high_s16(g), vget_high_s16(b)),
+    )
+}
+
+/// 16 个像素的结果饱和为 u8
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn dot16(px: uint8x16x4_t, k: &[i32; 3], offset: i32) -> uint8x16_t {
+    // BGRA 解交错后 0 为 b, 1 为 g, 2 为 r
+    let lo = dot(
+        vget_low_u8(px.2),
+        vget_low_u8(px.1),
+        vget_low_u8(px.0),
+        k,
+        offset,
+    );
+    let hi = dot(
+        vget_high_u8(px.2),
+        vget_high_u8(px.1),
+        vget_high_u8(px.0),
+        k,
+        offset,
+    );
+    vcombine_u8(vqmovun_s16(lo), vqmovun_s16(hi))
+}
+
+#[target_feature(enable = "neon")]
+unsafe fn y_row(src: &[u8], dst: &mut [u8], m: &Encode) {
+    let n = dst.len() / 16 * 16;
+    for i in (0..n).step_by(16) {
+        let px = vld4q_u8(src.as_ptr().add(i * 4));
+        vst1q_u8(dst.as_mut_ptr().add(i), dot16(px, &m.y, m.y_offset));
+    }
+    super::y_row(&src[n * 4..], &mut dst[n..], m);
+}
+
+#[target_feature(enable = "neon")]
+unsafe fn uv_row(src: &[u8], u: &mut [u8], v: &mut [u8], m: &Encode) {
+    // 每次 16 个色度, 需要 32 个完整的像素
+    let n = src.len() / 4 / 2 / 16 * 16;
+    for i in (0..n).step_by(16) {
+        let a = vld4q_u8(src.as_ptr().add(i * 8));
+        let b = vld4q_u8(src.as_ptr().add(i * 8 + 64));
+        // 偶数列
+        let px = uint8x16x4_t(
+            vuzp1q_u8(a.0, b.0),
+            vuzp1q_u8(a.1, b.1),
+            vuzp1q_u8(a.2, b.2),
+            vuzp1q_u8(a.3, b.3),
+        );
+        vst1q_u8(u.as_mut_ptr().add(i), dot16(px, &m.u, 128));
+        vst1q_u8(v.as_mut_ptr().add(i), dot16(px, &m.v, 128));
+    }
+    super::uv_row(&src[n * 8..], &mut u[n..], &mut v[n..], m);
+}
+
+/// 8 个像素的 r, g, b, y 已减去偏移, u v 已减去 128, 都为 16 位
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn rgb8(y: int16x8_t, u: int16x8_t, v: int16x8_t, m: &Decode) -> [uint8x8_t; 3] {
+    let half = |y: int16x4_t, u: int16x4_t, v: int16x4_t| {
+        let c = vaddq_s32(vmull_n_s16(y, m.y as i16), vdupq_n_s32(128));
+        [
+            vshrq_n_s32::<8>(vmlal_n_s16(c, v, m.rv as i16)),
+            vshrq_n_s32::<8>(vmlsl_n_s16(vmlsl_n_s16(c, u, m.gu as i16), v, m.gv as i16)),
+            vshrq_n_s32::<8>(vmlal_n_s16(c, u, m.bu as i16)),
+        ]
+    };
+    let lo = half(vget_low_s16(y), vget_low_s16(u), vget_low_s16(v));
+    let hi = half(vget_high_s16(y), vget_high_s16(u), vget_high_s16(v));
+    [0, 1, 2].map(|c| vqmovun_s16(vcombine_s16(vqmovn_s32(lo[c]), vqmovn_s32(hi[c]))))
+}
+
+#[target_feature(enable = "neon")]
+unsafe fn rgb_row(sy: &[u8], su: &[u8], sv: &[u8], dst: &mut [u8], m: &Decode) {
+    let n = sy.len() / 16 * 16;
+    let c128 = vdupq_n_s16(128);
+    let offset = vdupq_n_s16(m.y_offset as i16);
+    for i in (0..n).step_by(16) {
+        let y = vld1q_u8(sy.as_ptr().add(i));
+        let u = vld1_u8(su.as_ptr().add(i / 2));
+        let v = vld1_u8(sv.as_ptr().add(i / 2));
+        // 每个色度对应两个像素
+        let u = vzip1q_u8(vcombine_u8(u, u), vcombine_u8(u, u));
+        let v = vzip1q_u8(vcombine_u8(v, v), vcombine_u8(v, v));
+        let mut rgb = [vdup_n_u8(0); 6];
+        for (half, (y, (u, v))) in [
+            (vget_low_u8(y), (vget_low_u8(u), vget_low_u8(v))),
+            (vget_high_u8(y), (vget_high_u8(u), vget_high_u8(v))),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let y = vsubq_s16(vreinterpretq_s16_u16(vmovl_u8(y)), offset);
+            let u = vsubq_s16(vreinterpretq_s16_u16(vmovl_u8(u)), c128);
+            let v = vsubq_s16(vreinterpretq_s16_u16(vmovl_u8(v)), c128);
+            rgb[half * 3..half * 3 + 3].copy_from_slice(&rgb8(y, u, v, m));
+        }
+        let out = uint8x16x3_t(
+            vcombine_u8(rgb[0], rgb[3]),
+            vcombine_u8(rgb[1], rgb[4]),
+            vcombine_u8(rgb[2], rgb[5]),
+        );
+        vst3q_u8(dst.as_mut_ptr().add(i * 3), out);
+    }
+    super::rgb_row(&sy[n..], &su[n / 2..], &sv[n / 2..], &mut dst[n * 3..], m);
+}
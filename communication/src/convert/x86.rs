@@ -0,0 +1,332 @@
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+use super::Decode;
+use super::Encode;
+use super::Kernels;
+
+// 只在 Backend::supported 检查过 CPU 之后使用
+pub(super) const SSE2: Kernels = Kernels {
+    y_row: |src, dst, m| unsafe { sse2::y_row(src, dst, m) },
+    uv_row: |src, u, v, m| unsafe { sse2::uv_row(src, u, v, m) },
+    rgb_row: |y, u, v, dst, m| unsafe { sse2::rgb_row(y, u, v, dst, m) },
+};
+
+pub(super) const AVX2: Kernels = Kernels {
+    y_row: |src, dst, m| unsafe { avx2::y_row(src, dst, m) },
+    uv_row: |src, u, v, m| unsafe { avx2::uv_row(src, u, v, m) },
+    rgb_row: |y, u, v, dst, m| unsafe { avx2::rgb_row(y, u, v, dst, m) },
+};
+
+/// 把向量化计算出的 r, g, b 平面交错写入 RGB
+#[inline(always)]
+fn interleave(r: &[u8], g: &[u8], b: &[u8], dst: &mut [u8]) {
+    for (((rgb, r), g), b) in dst.chunks_exact_mut(3).zip(r).zip(g).zip(b) {
+        rgb[0] = *r;
+        rgb[1] = *g;
+        rgb[2] = *b;
+    }
+}
+
+/**
+ * 每个 32 位通道一个像素, 用 madd 计算 16 位系数与通道低 16 位的乘积
+ * 通道的值为 0~255 或有符号的色度, 系数的高 16 位为 0, 乘积即为 32 位结果
+ */
+mod sse2 {
+    use super::*;
+
+    #[inline(always)]
+    unsafe fn mul(x: __m128i, k: i32) -> __m128i {
+        _mm_madd_epi16(x, _mm_set1_epi32(k & 0xffff))
+    }
+
+    /// 4 个 BGRA 像素的 ((k · rgb + 128) >> 8) + offset
+    #[inline(always)]
+    unsafe fn dot(px: __m128i, k: &[i32; 3], offset: i32) -> __m128i {
+        let mask = _mm_set1_epi32(0xff);
+        let b = _mm_and_si128(px, mask);
+        let g = _mm_and_si128(_mm_srli_epi32(px, 8), mask);
+        let r = _mm_and_si128(_mm_srli_epi32(px, 16), mask);
+        let sum = _mm_add_epi32(
+            _mm_add_epi32(mul(r, k[0]), mul(g, k[1])),
+            _mm_add_epi32(mul(b, k[2]), _mm_set1_epi32(128)),
+        );
+        _mm_add_epi32(_mm_srai_epi32(sum, 8), _mm_set1_epi32(offset))
+    }
+
+    /// 16 个 32 位结果饱和为 u8
+    #[inline(always)]
+    unsafe fn pack(a: __m128i, b: __m128i, c: __m128i, d: __m128i) -> __m128i {
+        _mm_packus_epi16(_mm_packs_epi32(a, b), _mm_packs_epi32(c, d))
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn y_row(src: &[u8], dst: &mut [u8], m: &Encode) {
+        let n = dst.len() / 16 * 16;
+        let p = src.as_ptr() as *const __m128i;
+        for i in (0..n).step_by(16) {
+            let q = p.add(i / 4);
+            let y = pack(
+                dot(_mm_loadu_si128(q), &m.y, m.y_offset),
+                dot(_mm_loadu_si128(q.add(1)), &m.y, m.y_offset),
+                dot(_mm_loadu_si128(q.add(2)), &m.y, m.y_offset),
+                dot(_mm_loadu_si128(q.add(3)), &m.y, m.y_offset),
+            );
+            _mm_storeu_si128(dst.as_mut_ptr().add(i) as *mut __m128i, y);
+        }
+        super::super::y_row(&src[n * 4..], &mut dst[n..], m);
+    }
+
+    /// 8 个像素中的偶数列
+    #[inline(always)]
+    unsafe fn even(a: __m128i, b: __m128i) -> __m128i {
+        _mm_castps_si128(_mm_shuffle_ps(
+            _mm_castsi128_ps(a),
+            _mm_castsi128_ps(b),
+            0b10_00_10_00,
+        ))
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn uv_row(src: &[u8], u: &mut [u8], v: &mut [u8], m: &Encode) {
+        // 每次 8 个色度, 需要 16 个完整的像素
+        let n = src.len() / 4 / 2 / 8 * 8;
+        let p = src.as_ptr() as *const __m128i;
+        for i in (0..n).step_by(8) {
+            let q = p.add(i / 2);
+            let e0 = even(_mm_loadu_si128(q), _mm_loadu_si128(q.add(1)));
+            let e1 = even(_mm_loadu_si128(q.add(2)), _mm_loadu_si128(q.add(3)));
+            let uv = pack(
+                dot(e0, &m.u, 128),
+                dot(e1, &m.u, 128),
+                dot(e0, &m.v, 128),
+                dot(e1, &m.v, 128),
+            );
+            _mm_storel_epi64(u.as_mut_ptr().add(i) as *mut __m128i, uv);
+            _mm_storel_epi64(v.as_mut_ptr().add(i) as *mut __m128i, _mm_srli_si128(uv, 8));
+        }
+        super::super::uv_row(&src[n * 8..], &mut u[n..], &mut v[n..], m);
+    }
+
+    /// 8 个 16 位有符号数扩展为两组 32 位
+    #[inline(always)]
+    unsafe fn widen(x: __m128i) -> (__m128i, __m128i) {
+        (
+            _mm_srai_epi32(_mm_unpacklo_epi16(x, x), 16),
+            _mm_srai_epi32(_mm_unpackhi_epi16(x, x), 16),
+        )
+    }
+
+    /// 4 个像素的 r, g, b, y 已减去偏移
+    #[inline(always)]
+    unsafe fn rgb(y: __m128i, u: __m128i, v: __m128i, m: &Decode) -> (__m128i, __m128i, __m128i) {
+        let c = _mm_add_epi32(mul(y, m.y), _mm_set1_epi32(128));
+        let r = _mm_srai_epi32(_mm_add_epi32(c, mul(v, m.rv)), 8);
+        let g = _mm_srai_epi32(
+            _mm_sub_epi32(_mm_sub_epi32(c, mul(u, m.gu)), mul(v, m.gv)),
+            8,
+        );
+        let b = _mm_srai_epi32(_mm_add_epi32(c, mul(u, m.bu)), 8);
+        (r, g, b)
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn rgb_row(sy: &[u8], su: &[u8], sv: &[u8], dst: &mut [u8], m: &Decode) {
+        let n = sy.len() / 16 * 16;
+        let zero = _mm_setzero_si128();
+        let c128 = _mm_set1_epi16(128);
+        let offset = _mm_set1_epi16(m.y_offset as i16);
+        let (mut r, mut g, mut b) = ([0u8; 16], [0u8; 16], [0u8; 16]);
+        for i in (0..n).step_by(16) {
+            let y = _mm_loadu_si128(sy.as_ptr().add(i) as *const __m128i);
+            let u = _mm_loadl_epi64(su.as_ptr().add(i / 2) as *const __m128i);
+            let v = _mm_loadl_epi64(sv.as_ptr().add(i / 2) as *const __m128i);
+            // 每个色度对应两个像素
+            let (u, v) = (_mm_unpacklo_epi8(u, u), _mm_unpacklo_epi8(v, v));
+            let y16 = [
+                _mm_sub_epi16(_mm_unpacklo_epi8(y, zero), offset),
+                _mm_sub_epi16(_mm_unpackhi_epi8(y, zero), offset),
+            ];
+            let u16 = [
+                _mm_sub_epi16(_mm_unpacklo_epi8(u, zero), c128),
+                _mm_sub_epi16(_mm_unpackhi_epi8(u, zero), c128),
+            ];
+            let v16 = [
+                _mm_sub_epi16(_mm_unpacklo_epi8(v, zero), c128),
+                _mm_sub_epi16(_mm_unpackhi_epi8(v, zero), c128),
+            ];
+            let mut out = [[zero; 4]; 3];
+            for half in 0..2 {
+                let (y0, y1) = widen(y16[half]);
+                let (u0, u1) = widen(u16[half]);
+                let (v0, v1) = widen(v16[half]);
+                let (r0, g0, b0) = rgb(y0, u0, v0, m);
+                let (r1, g1, b1) = rgb(y1, u1, v1, m);
+                out[0][half * 2] = r0;
+                out[0][half * 2 + 1] = r1;
+                out[1][half * 2] = g0;
+                out[1][half * 2 + 1] = g1;
+                out[2][half * 2] = b0;
+                out[2][half * 2 + 1] = b1;
+            }
+            for (plane, c) in [&mut r, &mut g, &mut b].into_iter().zip(out) {
+                _mm_storeu_si128(
+                    plane.as_mut_ptr() as *mut __m128i,
+                    pack(c[0], c[1], c[2], c[3]),
+                );
+            }
+            interleave(&r, &g, &b, &mut dst[i * 3..(i + 16) * 3]);
+        }
+        super::super::rgb_row(&sy[n..], &su[n / 2..], &sv[n / 2..], &mut dst[n * 3..], m);
+    }
+}
+
+/**
+ * 与 sse2 相同的算法, 每次处理两倍的像素
+ * pack 在 128 位的两半内分别进行, 需要重新排列
+ */
+mod avx2 {
+    use super::*;
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn mul(x: __m256i, k: i32) -> __m256i {
+        _mm256_madd_epi16(x, _mm256_set1_epi32(k & 0xffff))
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn dot(px: __m256i, k: &[i32; 3], offset: i32) -> __m256i {
+        let mask = _mm256_set1_epi32(0xff);
+        let b = _mm256_and_si256(px, mask);
+        let g = _mm256_and_si256(_mm256_srli_epi32(px, 8), mask);
+        let r = _mm256_and_si256(_mm256_srli_epi32(px, 16), mask);
+        let sum = _mm256_add_epi32(
+            _mm256_add_epi32(mul(r, k[0]), mul(g, k[1])),
+            _mm256_add_epi32(mul(b, k[2]), _mm256_set1_epi32(128)),
+        );
+        _mm256_add_epi32(_mm256_srai_epi32(sum, 8), _mm256_set1_epi32(offset))
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn y_row(src: &[u8], dst: &mut [u8], m: &Encode) {
+        let n = dst.len() / 32 * 32;
+        let p = src.as_ptr() as *const __m256i;
+        // pack 后 4 字节一组的顺序为 0 2 4 6 1 3 5 7
+        let order = _mm256_setr_epi32(0, 4, 1, 5, 2, 6, 3, 7);
+        for i in (0..n).step_by(32) {
+            let q = p.add(i / 8);
+            let a = dot(_mm256_loadu_si256(q), &m.y, m.y_offset);
+            let b = dot(_mm256_loadu_si256(q.add(1)), &m.y, m.y_offset);
+            let c = dot(_mm256_loadu_si256(q.add(2)), &m.y, m.y_offset);
+            let d = dot(_mm256_loadu_si256(q.add(3)), &m.y, m.y_offset);
+            let y = _mm256_packus_epi16(_mm256_packs_epi32(a, b), _mm256_packs_epi32(c, d));
+            let y = _mm256_permutevar8x32_epi32(y, order);
+            _mm256_storeu_si256(dst.as_mut_ptr().add(i) as *mut __m256i, y);
+        }
+        super::super::y_row(&src[n * 4..], &mut dst[n..], m);
+    }
+
+    /// 16 个像素中的偶数列
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn even(a: __m256i, b: __m256i) -> __m256i {
+        let e = _mm256_castps_si256(_mm256_shuffle_ps(
+            _mm256_castsi256_ps(a),
+            _mm256_castsi256_ps(b),
+            0b10_00_10_00,
+        ));
+        _mm256_permute4x64_epi64(e, 0b11_01_10_00)
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn uv_row(src: &[u8], u: &mut [u8], v: &mut [u8], m: &Encode) {
+        // 每次 16 个色度, 需要 32 个完整的像素
+        let n = src.len() / 4 / 2 / 16 * 16;
+        let p = src.as_ptr() as *const __m256i;
+        for i in (0..n).step_by(16) {
+            let q = p.add(i / 4);
+            let e0 = even(_mm256_loadu_si256(q), _mm256_loadu_si256(q.add(1)));
+            let e1 = even(_mm256_loadu_si256(q.add(2)), _mm256_loadu_si256(q.add(3)));
+            let pu = _mm256_permute4x64_epi64(
+                _mm256_packs_epi32(dot(e0, &m.u, 128), dot(e1, &m.u, 128)),
+                0b11_01_10_00,
+            );
+            let pv = _mm256_permute4x64_epi64(
+                _mm256_packs_epi32(dot(e0, &m.v, 128), dot(e1, &m.v, 128)),
+                0b11_01_10_00,
+            );
+            // 8 字节一组的顺序为 u0 v0 u1 v1
+            let uv = _mm256_permute4x64_epi64(_mm256_packus_epi16(pu, pv), 0b11_01_10_00);
+            _mm_storeu_si128(
+                u.as_mut_ptr().add(i) as *mut __m128i,
+                _mm256_castsi256_si128(uv),
+            );
+            _mm_storeu_si128(
+                v.as_mut_ptr().add(i) as *mut __m128i,
+                _mm256_extracti128_si256(uv, 1),
+            );
+        }
+        super::super::uv_row(&src[n * 8..], &mut u[n..], &mut v[n..], m);
+    }
+
+    /// 8 个 32 位结果饱和为 u8, 放在低 8 字节
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn pack(a: __m256i, b: __m256i) -> __m128i {
+        let x = _mm256_permute4x64_epi64(_mm256_packs_epi32(a, b), 0b11_01_10_00);
+        let x = _mm256_permute4x64_epi64(_mm256_packus_epi16(x, x), 0b11_01_10_00);
+        _mm256_castsi256_si128(x)
+    }
+
+    /// 8 个 u8 扩展为 32 位并减去 128
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn chroma(x: __m128i) -> __m256i {
+        _mm256_sub_epi32(_mm256_cvtepu8_epi32(x), _mm256_set1_epi32(128))
+    }
+
+    /// 低 8 个像素的 r, g, b
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn rgb(y: __m128i, u: __m128i, v: __m128i, m: &Decode) -> [__m256i; 3] {
+        let y = _mm256_sub_epi32(_mm256_cvtepu8_epi32(y), _mm256_set1_epi32(m.y_offset));
+        let (u, v) = (chroma(u), chroma(v));
+        let c = _mm256_add_epi32(mul(y, m.y), _mm256_set1_epi32(128));
+        [
+            _mm256_srai_epi32(_mm256_add_epi32(c, mul(v, m.rv)), 8),
+            _mm256_srai_epi32(
+                _mm256_sub_epi32(_mm256_sub_epi32(c, mul(u, m.gu)), mul(v, m.gv)),
+                8,
+            ),
+            _mm256_srai_epi32(_mm256_add_epi32(c, mul(u, m.bu)), 8),
+        ]
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn rgb_row(sy: &[u8], su: &[u8], sv: &[u8], dst: &mut [u8], m: &Decode) {
+        let n = sy.len() / 16 * 16;
+        let (mut r, mut g, mut b) = ([0u8; 16], [0u8; 16], [0u8; 16]);
+        for i in (0..n).step_by(16) {
+            let y = _mm_loadu_si128(sy.as_ptr().add(i) as *const __m128i);
+            let u = _mm_loadl_epi64(su.as_ptr().add(i / 2) as *const __m128i);
+            let v = _mm_loadl_epi64(sv.as_ptr().add(i / 2) as *const __m128i);
+            // 每个色度对应两个像素
+            let (u, v) = (_mm_unpacklo_epi8(u, u), _mm_unpacklo_epi8(v, v));
+            let lo = rgb(y, u, v, m);
+            let hi = rgb(
+                _mm_srli_si128(y, 8),
+                _mm_srli_si128(u, 8),
+                _mm_srli_si128(v, 8),
+                m,
+            );
+            for (c, plane) in [&mut r, &mut g, &mut b].into_iter().enumerate() {
+                _mm_storeu_si128(plane.as_mut_ptr() as *mut __m128i, pack(lo[c], hi[c]));
+            }
+            interleave(&r, &g, &b, &mut dst[i * 3..(i + 16) * 3]);
+        }
+        super::super::rgb_row(&sy[n..], &su[n / 2..], &sv[n / 2..], &mut dst[n * 3..], m);
+    }
+}
@@ -0,0 +1,123 @@
+/// 光标形状, 按 id 缓存, 只在client第一次遇到时发送
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CursorShape {
+    pub id: u64,
+    pub width: u16,
+    pub height: u16,
+    pub hotx: u16,
+    pub hoty: u16,
+    /// width * height * 4 字节的 RGBA 像素
+    pub rgba: Vec<u8>,
+}
+
+/// 光标位置, id 为 0 表示光标隐藏
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CursorPos {
+    pub x: i16,
+    pub y: i16,
+    pub id: u64,
+}
+
+/*
+光标形状字节序
++------+-------+--------+------+------+--------+
+|  id  | width | height | hotx | hoty |  rgba  |
++------+-------+--------+------+------+--------+
+|  8   |   2   |   2    |  2   |  2   | w*h*4  |
++------+-------+--------+------+------+--------+
+*/
+impl CursorShape {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(16 + self.rgba.len());
+        data.extend_from_slice(&self.id.to_be_bytes());
+        data.extend_from_slice(&self.width.to_be_bytes());
+        data.extend_from_slice(&self.height.to_be_bytes());
+        data.extend_from_slice(&self.hotx.to_be_bytes());
+        data.extend_from_slice(&self.hoty.to_be_bytes());
+        data.extend_from_slice(&self.rgba);
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Option<CursorShape> {
+        if data.len() < 16 {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&data[..8]);
+        let shape = CursorShape {
+            id: u64::from_be_bytes(id),
+            width: u16_at(8),
+            height: u16_at(10),
+            hotx: u16_at(12),
+            hoty: u16_at(14),
+            rgba: data[16..].to_vec(),
+        };
+        if shape.rgba.len() != shape.width as usize * shape.height as usize * 4 {
+            return None;
+        }
+        Some(shape)
+    }
+}
+
+/*
+光标位置字节序
++-----+-----+------+
+|  x  |  y  |  id  |
++-----+-----+------+
+|  2  |  2  |  8   |
++-----+-----+------+
+*/
+impl CursorPos {
+    pub fn encode(&self) -> [u8; 12] {
+        let mut data = [0u8; 12];
+        data[..2].copy_from_slice(&self.x.to_be_bytes());
+        data[2..4].copy_from_slice(&self.y.to_be_bytes());
+        data[4..].copy_from_slice(&self.id.to_be_bytes());
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Option<CursorPos> {
+        if data.len() != 12 {
+            return None;
+        }
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&data[4..]);
+        Some(CursorPos {
+            x: i16::from_be_bytes([data[0], data[1]]),
+            y: i16::from_be_bytes([data[2], data[3]]),
+            id: u64::from_be_bytes(id),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shape_round_trip() {
+        let shape = CursorShape {
+            id: 0x0102030405060708,
+            width: 2,
+            height: 3,
+            hotx: 1,
+            hoty: 2,
+            rgba: (0..24).collect(),
+        };
+        assert_eq!(CursorShape::decode(&shape.encode()), Some(shape.clone()));
+        // 像素数据长度不对
+        assert_eq!(CursorShape::decode(&shape.encode()[..20]), None);
+    }
+
+    #[test]
+    fn test_pos_round_trip() {
+        let pos = CursorPos {
+            x: -20,
+            y: 1080,
+            id: 42,
+        };
+        assert_eq!(CursorPos::decode(&pos.encode()), Some(pos));
+        assert_eq!(CursorPos::decode(&[0u8; 4]), None);
+    }
+}
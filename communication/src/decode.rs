@@ -0,0 +1,312 @@
+use crate::convert;
+use crate::convert::ColorSpace;
+use crate::convert::PixelFormat;
+use crate::frame;
+use crate::frame::FrameHeader;
+use flate2::Decompress;
+use flate2::FlushDecompress;
+use flate2::Status;
+use rayon::prelude::*;
+
+/// 解码一个图像帧消息的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decoded {
+    /// 还原了完整的一帧
+    Frame,
+    /// 等待关键帧时丢弃的差异帧
+    Skipped,
+    /// 丢失了差异帧或校验失败, 应该请求关键帧
+    Refresh,
+}
+
+/**
+ * 图像帧解码
+ * 按 VIDEO_DISPLAY 告知的尺寸, 颜色空间和像素格式解压 VIDEO_FRAME, 与上一帧异或还原后校验
+ * client和录像回放共用
+ */
+pub struct Decoder {
+    w: usize,
+    h: usize,
+    space: ColorSpace,
+    format: PixelFormat,
+    // 上一帧, 差异帧与其异或后还原
+    frame: Vec<u8>,
+    // 解压的缓冲区, 校验通过后与 frame 交换
+    buf: Vec<u8>,
+    // 每个条带一个解压器
+    ds: Vec<Decompress>,
+    max_frame: usize,
+    // 上一帧的序号; 校验失败或丢帧时等待关键帧
+    seq: Option<u32>,
+    resync: bool,
+}
+
+impl Decoder {
+    /// 连接时server告知的尺寸, 解压后一帧最长 max_frame
+    pub fn new(w: usize, h: usize, max_frame: usize) -> Self {
+        let mut decoder = Decoder {
+            w,
+            h,
+            space: ColorSpace::default(),
+            format: PixelFormat::I420,
+            frame: Vec::new(),
+            buf: Vec::new(),
+            ds: Vec::new(),
+            max_frame,
+            seq: None,
+            resync: false,
+        };
+        decoder.reset(w, h);
+        decoder
+    }
+
+    /// 新的会话从完整的一帧开始, 上一帧重置为全0, 颜色空间和像素格式恢复默认
+    pub fn reset(&mut self, w: usize, h: usize) {
+        (self.w, self.h) = (w, h);
+        (self.space, self.format) = (ColorSpace::default(), PixelFormat::I420);
+        self.frame = vec![0u8; self.format.frame_len(w, h)];
+        (self.seq, self.resync) = (None, false);
+    }
+
+    /// 跳转到其他位置后等待关键帧, 之前的差异帧都被丢弃
+    pub fn seek(&mut self) {
+        (self.seq, self.resync) = (None, true);
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.w, self.h)
+    }
+
+    pub fn space(&self) -> ColorSpace {
+        self.space
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// 最近还原的一帧, I420 或 RGB
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    /// VIDEO_DISPLAY w h 颜色空间 像素格式, 之后是新尺寸的完整一帧
+    /// 无效或一帧超过 max_frame 时返回 false, 不分配缓冲区
+    pub fn display(&mut self, msg: &[u8]) -> bool {
+        let (w, h, color, pixel) = match msg {
+            [crate::VIDEO_DISPLAY, w1, w2, h1, h2, color, pixel] => (
+                u16::from_be_bytes([*w1, *w2]) as usize,
+                u16::from_be_bytes([*h1, *h2]) as usize,
+                *color,
+                *pixel,
+            ),
+            _ => return false,
+        };
+        if w == 0 || h == 0 {
+            return false;
+        }
+        match (ColorSpace::from_u8(color), PixelFormat::from_u8(pixel)) {
+            (Some(space), Some(format)) => {
+                if format.frame_len(w, h) > self.max_frame {
+                    return false;
+                }
+                (self.w, self.h, self.space, self.format) = (w, h, space, format);
+                self.frame = vec![0u8; format.frame_len(w, h)];
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// 解码 VIDEO_FRAME, 数据损坏或长度与当前画面不符时返回 None
+    pub fn decode(&mut self, msg: &[u8]) -> Option<Decoded> {
+        let (header, data) = FrameHeader::decode(msg)?;
+        // 在分配缓冲区之前检查长度
+        let length = header.length as usize;
+        if length > self.max_frame || length != self.frame.len() {
+            return None;
+        }
+        // 丢失了差异帧
+        let in_order = self.seq.map(|s| s.wrapping_add(1)) == Some(header.seq);
+        self.seq = Some(header.seq);
+        if !header.keyframe() && !in_order && !self.resync {
+            self.resync = true;
+            return Some(Decoded::Refresh);
+        }
+        if self.resync && !header.keyframe() {
+            return Some(Decoded::Skipped);
+        }
+        let stripes = frame::split_stripes(data, header.stripes as usize)?;
+        if !inflate(&mut self.ds, &stripes, length, &mut self.buf) {
+            return None;
+        }
+        if !header.keyframe() {
+            self.buf
+                .par_iter_mut()
+                .zip(self.frame.par_iter())
+                .for_each(|(a, b)| *a ^= *b);
+        }
+        // 与server的画面不一致
+        self.resync = frame::checksum(&self.buf) != header.checksum;
+        if self.resync {
+            return Some(Decoded::Refresh);
+        }
+        std::mem::swap(&mut self.frame, &mut self.buf);
+        Some(Decoded::Frame)
+    }
+
+    /// 把当前的一帧转为 RGB, dest 为 w * h * 3 字节
+    pub fn to_rgb(&self, dest: &mut [u8]) {
+        match self.format {
+            PixelFormat::I420 => {
+                convert::i420_to_rgb(self.space, self.w, self.h, &self.frame, dest)
+            }
+            PixelFormat::Rgb => dest.copy_from_slice(&self.frame),
+        }
+    }
+}
+
+/// 并行解压一帧的各条带, 每个条带解压后必须正好填满对应的范围, 超出的部分不会写入
+fn inflate(ds: &mut Vec<Decompress>, stripes: &[&[u8]], length: usize, out: &mut Vec<u8>) -> bool {
+    while ds.len() < stripes.len() {
+        ds.push(Decompress::new(false));
+    }
+    out.clear();
+    out.resize(length, 0);
+    let parts = frame::stripes_mut(out, stripes.len());
+    ds.par_iter_mut()
+        .zip(stripes.par_iter())
+        .zip(parts.into_par_iter())
+        .all(|((d, data), part)| {
+            d.reset(false);
+            matches!(
+                d.decompress(data, part, FlushDecompress::Finish),
+                Ok(Status::StreamEnd)
+            ) && d.total_out() == part.len() as u64
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::FrameKind;
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut e = DeflateEncoder::new(Vec::new(), Compression::default());
+        e.write_all(data).unwrap();
+        e.finish().unwrap()
+    }
+
+    /// 与server相同的消息, 分为两个条带
+    fn frame_msg(kind: FrameKind, seq: u32, data: &[u8], frame: &[u8]) -> Vec<u8> {
+        let header = FrameHeader {
+            kind,
+            seq,
+            timestamp: 0,
+            length: data.len() as u32,
+            checksum: frame::checksum(frame),
+            stripes: 2,
+        };
+        let stripes: Vec<Vec<u8>> = (0..2)
+            .map(|i| deflate(&data[frame::stripe(data.len(), 2, i)]))
+            .collect();
+        let mut msg = Vec::new();
+        header.encode(&mut msg);
+        frame::encode_stripes(&stripes, &mut msg);
+        msg
+    }
+
+    #[test]
+    fn test_inflate() {
+        let frame: Vec<u8> = (0..100_000).map(|i| (i * 7 % 251) as u8).collect();
+        let data = deflate(&frame);
+
+        let mut ds = Vec::new();
+        let mut out = Vec::new();
+        assert!(inflate(&mut ds, &[&data], frame.len(), &mut out));
+        assert_eq!(out, frame);
+        // 解压后比声明的长度长或短
+        assert!(!inflate(&mut ds, &[&data], frame.len() - 1, &mut out));
+        assert!(!inflate(&mut ds, &[&data], frame.len() + 1, &mut out));
+        // 可以继续解压下一帧
+        assert!(inflate(&mut ds, &[&data], frame.len(), &mut out));
+
+        // 分为三个条带
+        let stripes: Vec<Vec<u8>> = (0..3)
+            .map(|i| deflate(&frame[frame::stripe(frame.len(), 3, i)]))
+            .collect();
+        let stripes: Vec<&[u8]> = stripes.iter().map(|s| &s[..]).collect();
+        assert!(inflate(&mut ds, &stripes, frame.len(), &mut out));
+        assert_eq!(out, frame);
+        assert_eq!(ds.len(), 3);
+        // 条带的长度与范围不符
+        let swapped = [stripes[0], stripes[2], stripes[1]];
+        assert!(!inflate(&mut ds, &swapped, frame.len(), &mut out));
+    }
+
+    #[test]
+    fn test_decoder() {
+        let (w, h) = (4, 2);
+        let mut decoder = Decoder::new(16, 16, frame::DEFAULT_MAX_FRAME);
+        // 新的尺寸和 RGB 格式
+        assert!(!decoder.display(&[crate::VIDEO_DISPLAY, 0, 0, 0, 2, 0, 1]));
+        assert!(!decoder.display(&[crate::VIDEO_DISPLAY, 0, 4, 0, 2, 0, 9]));
+        assert!(decoder.display(&[crate::VIDEO_DISPLAY, 0, 4, 0, 2, 3, 1]));
+        assert_eq!(decoder.size(), (w, h));
+        assert_eq!(decoder.format(), PixelFormat::Rgb);
+        assert_eq!(decoder.frame().len(), w * h * 3);
+        // 超过 max_frame 的尺寸不分配, 保持原来的画面
+        let mut small = Decoder::new(4, 2, 24);
+        assert!(small.display(&[crate::VIDEO_DISPLAY, 0, 4, 0, 2, 3, 1]));
+        assert!(!small.display(&[crate::VIDEO_DISPLAY, 0, 4, 0, 3, 3, 1]));
+        assert!(!small.display(&[crate::VIDEO_DISPLAY, 255, 255, 255, 255, 3, 1]));
+        assert_eq!(small.size(), (4, 2));
+        assert_eq!(small.frame().len(), 24);
+
+        let a: Vec<u8> = (0..24).collect();
+        let b: Vec<u8> = (0..24).map(|i| 100 - i).collect();
+        let xor: Vec<u8> = a.iter().zip(&b).map(|(x, y)| x ^ y).collect();
+        let key = frame_msg(FrameKind::Key, 0, &a, &a);
+        assert_eq!(decoder.decode(&key), Some(Decoded::Frame));
+        assert_eq!(decoder.frame(), &a[..]);
+        let delta = frame_msg(FrameKind::Delta, 1, &xor, &b);
+        assert_eq!(decoder.decode(&delta), Some(Decoded::Frame));
+        let mut rgb = vec![0u8; w * h * 3];
+        decoder.to_rgb(&mut rgb);
+        assert_eq!(rgb, b);
+
+        // 丢帧后请求关键帧, 之前的差异帧被丢弃
+        let delta = frame_msg(FrameKind::Delta, 3, &xor, &a);
+        assert_eq!(decoder.decode(&delta), Some(Decoded::Refresh));
+        let delta = frame_msg(FrameKind::Delta, 4, &xor, &b);
+        assert_eq!(decoder.decode(&delta), Some(Decoded::Skipped));
+        let key = frame_msg(FrameKind::Key, 5, &a, &a);
+        assert_eq!(decoder.decode(&key), Some(Decoded::Frame));
+
+        // 校验失败时保留上一帧
+        let bad = frame_msg(FrameKind::Delta, 6, &xor, &a);
+        assert_eq!(decoder.decode(&bad), Some(Decoded::Refresh));
+        assert_eq!(decoder.frame(), &a[..]);
+
+        // 跳转后从关键帧开始
+        decoder.seek();
+        let delta = frame_msg(FrameKind::Delta, 1, &xor, &b);
+        assert_eq!(decoder.decode(&delta), Some(Decoded::Skipped));
+        assert_eq!(decoder.decode(&key), Some(Decoded::Frame));
+
+        // 长度与画面不符和损坏的数据
+        let short = frame_msg(FrameKind::Key, 6, &a[1..], &a[1..]);
+        assert_eq!(decoder.decode(&short), None);
+        let mut broken = frame_msg(FrameKind::Key, 6, &a, &a);
+        broken.pop();
+        assert_eq!(decoder.decode(&broken), None);
+
+        // 新的会话恢复默认的格式
+        decoder.reset(2, 2);
+        assert_eq!(decoder.format(), PixelFormat::I420);
+        assert_eq!(decoder.frame(), &[0u8; 6][..]);
+    }
+}
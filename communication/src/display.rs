@@ -0,0 +1,81 @@
+/// SELECT_DISPLAY 的参数, 所有显示器组成的虚拟桌面
+pub const ALL: u8 = 255;
+
+/// 显示器在虚拟桌面中的位置, 坐标可以为负
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayInfo {
+    pub x: i32,
+    pub y: i32,
+    pub w: u16,
+    pub h: u16,
+    pub primary: bool,
+}
+
+/*
+显示器列表字节序, 在 DISPLAY_LIST 之后
++---+-------------------------------+
+| n | x(4) y(4) w(2) h(2) primary(1) | * n
++---+-------------------------------+
+*/
+pub fn encode_list(displays: &[DisplayInfo]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(1 + displays.len() * 13);
+    data.push(displays.len().min(ALL as usize - 1) as u8);
+    for d in displays.iter().take(ALL as usize - 1) {
+        data.extend_from_slice(&d.x.to_be_bytes());
+        data.extend_from_slice(&d.y.to_be_bytes());
+        data.extend_from_slice(&d.w.to_be_bytes());
+        data.extend_from_slice(&d.h.to_be_bytes());
+        data.push(d.primary as u8);
+    }
+    data
+}
+
+pub fn decode_list(data: &[u8]) -> Option<Vec<DisplayInfo>> {
+    let (&n, mut rest) = data.split_first()?;
+    if rest.len() != n as usize * 13 {
+        return None;
+    }
+    let mut displays = Vec::with_capacity(n as usize);
+    while let [x1, x2, x3, x4, y1, y2, y3, y4, w1, w2, h1, h2, primary, tail @ ..] = rest {
+        displays.push(DisplayInfo {
+            x: i32::from_be_bytes([*x1, *x2, *x3, *x4]),
+            y: i32::from_be_bytes([*y1, *y2, *y3, *y4]),
+            w: u16::from_be_bytes([*w1, *w2]),
+            h: u16::from_be_bytes([*h1, *h2]),
+            primary: *primary != 0,
+        });
+        rest = tail;
+    }
+    Some(displays)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let displays = vec![
+            DisplayInfo {
+                x: 0,
+                y: 0,
+                w: 1920,
+                h: 1080,
+                primary: true,
+            },
+            DisplayInfo {
+                x: -1280,
+                y: 200,
+                w: 1280,
+                h: 1024,
+                primary: false,
+            },
+        ];
+        let data = encode_list(&displays);
+        assert_eq!(data.len(), 1 + 2 * 13);
+        assert_eq!(decode_list(&data), Some(displays));
+        assert_eq!(decode_list(&[0]), Some(Vec::new()));
+        assert_eq!(decode_list(&data[..data.len() - 1]), None);
+        assert_eq!(decode_list(&[]), None);
+    }
+}
@@ -0,0 +1,460 @@
+use sha2::Digest;
+use sha2::Sha256;
+use std::fs::File;
+use std::io::Read;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// 每个数据块的大小
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// 一个文件消息的最大长度
+pub const MAX_MSG_SIZE: usize = CHUNK_SIZE + 1024;
+/// 未完成的文件以此为后缀, 重新传输时从已有的长度继续
+pub const PART_SUFFIX: &str = ".part";
+
+// 消息类型
+const LIST_ROOTS: u8 = 1;
+const LIST_DIR: u8 = 2;
+const UPLOAD: u8 = 3;
+const DOWNLOAD: u8 = 4;
+const CANCEL: u8 = 5;
+const CHUNK: u8 = 6;
+const ROOTS: u8 = 7;
+const DIR_LIST: u8 = 8;
+const UPLOAD_READY: u8 = 9;
+const DOWNLOAD_INFO: u8 = 10;
+const DONE: u8 = 11;
+const ERROR: u8 = 12;
+const RESUME: u8 = 13;
+
+/// 目录中的一项
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+}
+
+/// 文件传输消息
+/// 远程路径形如 "0/dir/file", 第一段为允许访问的根目录序号
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileMsg {
+    // client -> server
+    ListRoots,
+    ListDir {
+        path: String,
+    },
+    Upload {
+        id: u32,
+        dir: String,
+        name: String,
+        size: u64,
+        sha256: [u8; 32],
+    },
+    Download {
+        id: u32,
+        path: String,
+    },
+    /// 收到 DownloadInfo 后, 从 offset 处开始下载
+    Resume {
+        id: u32,
+        offset: u64,
+    },
+    Cancel {
+        id: u32,
+    },
+    // 双向
+    Chunk {
+        id: u32,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    // server -> client
+    Roots {
+        names: Vec<String>,
+    },
+    DirList {
+        path: String,
+        entries: Vec<DirEntry>,
+    },
+    /// 上传从 offset 处继续
+    UploadReady {
+        id: u32,
+        offset: u64,
+    },
+    DownloadInfo {
+        id: u32,
+        size: u64,
+        sha256: [u8; 32],
+    },
+    Done {
+        id: u32,
+    },
+    Error {
+        id: u32,
+        message: String,
+    },
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, v: u8) -> &mut Self {
+        self.0.push(v);
+        self
+    }
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.0.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.0.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+    fn bytes(&mut self, v: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(v);
+        self
+    }
+    fn str(&mut self, v: &str) -> &mut Self {
+        let v = &v.as_bytes()[..v.len().min(u16::MAX as usize)];
+        self.0.extend_from_slice(&(v.len() as u16).to_be_bytes());
+        self.bytes(v)
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (a, b) = self.0.split_at(n);
+        self.0 = b;
+        Some(a)
+    }
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+    fn hash(&mut self) -> Option<[u8; 32]> {
+        self.take(32)?.try_into().ok()
+    }
+    fn str(&mut self) -> Option<String> {
+        let len = u16::from_be_bytes(self.take(2)?.try_into().ok()?) as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+    fn rest(&mut self) -> Vec<u8> {
+        let rest = self.0.to_vec();
+        self.0 = &[];
+        rest
+    }
+}
+
+impl FileMsg {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer(Vec::new());
+        match self {
+            FileMsg::ListRoots => {
+                w.u8(LIST_ROOTS);
+            }
+            FileMsg::ListDir { path } => {
+                w.u8(LIST_DIR).str(path);
+            }
+            FileMsg::Upload {
+                id,
+                dir,
+                name,
+                size,
+                sha256,
+            } => {
+                w.u8(UPLOAD)
+                    .u32(*id)
+                    .str(dir)
+                    .str(name)
+                    .u64(*size)
+                    .bytes(sha256);
+            }
+            FileMsg::Download { id, path } => {
+                w.u8(DOWNLOAD).u32(*id).str(path);
+            }
+            FileMsg::Resume { id, offset } => {
+                w.u8(RESUME).u32(*id).u64(*offset);
+            }
+            FileMsg::Cancel { id } => {
+                w.u8(CANCEL).u32(*id);
+            }
+            FileMsg::Chunk { id, offset, data } => {
+                w.u8(CHUNK).u32(*id).u64(*offset).bytes(data);
+            }
+            FileMsg::Roots { names } => {
+                w.u8(ROOTS).u32(names.len() as u32);
+                for name in names {
+                    w.str(name);
+                }
+            }
+            FileMsg::DirList { path, entries } => {
+                w.u8(DIR_LIST).str(path).u32(entries.len() as u32);
+                for e in entries {
+                    w.str(&e.name).u8(e.is_dir as u8).u64(e.size);
+                }
+            }
+            FileMsg::UploadReady { id, offset } => {
+                w.u8(UPLOAD_READY).u32(*id).u64(*offset);
+            }
+            FileMsg::DownloadInfo { id, size, sha256 } => {
+                w.u8(DOWNLOAD_INFO).u32(*id).u64(*size).bytes(sha256);
+            }
+            FileMsg::Done { id } => {
+                w.u8(DONE).u32(*id);
+            }
+            FileMsg::Error { id, message } => {
+                w.u8(ERROR).u32(*id).str(message);
+            }
+        }
+        w.0
+    }
+
+    pub fn decode(data: &[u8]) -> Option<FileMsg> {
+        let mut r = Reader(data);
+        let msg = match r.u8()? {
+            LIST_ROOTS => FileMsg::ListRoots,
+            LIST_DIR => FileMsg::ListDir { path: r.str()? },
+            UPLOAD => FileMsg::Upload {
+                id: r.u32()?,
+                dir: r.str()?,
+                name: r.str()?,
+                size: r.u64()?,
+                sha256: r.hash()?,
+            },
+            DOWNLOAD => FileMsg::Download {
+                id: r.u32()?,
+                path: r.str()?,
+            },
+            RESUME => FileMsg::Resume {
+                id: r.u32()?,
+                offset: r.u64()?,
+            },
+            CANCEL => FileMsg::Cancel { id: r.u32()? },
+            CHUNK => FileMsg::Chunk {
+                id: r.u32()?,
+                offset: r.u64()?,
+                data: r.rest(),
+            },
+            ROOTS => {
+                let n = r.u32()?;
+                let mut names = Vec::new();
+                for _ in 0..n {
+                    names.push(r.str()?);
+                }
+                FileMsg::Roots { names }
+            }
+            DIR_LIST => {
+                let path = r.str()?;
+                let n = r.u32()?;
+                let mut entries = Vec::new();
+                for _ in 0..n {
+                    entries.push(DirEntry {
+                        name: r.str()?,
+                        is_dir: r.u8()? != 0,
+                        size: r.u64()?,
+                    });
+                }
+                FileMsg::DirList { path, entries }
+            }
+            UPLOAD_READY => FileMsg::UploadReady {
+                id: r.u32()?,
+                offset: r.u64()?,
+            },
+            DOWNLOAD_INFO => FileMsg::DownloadInfo {
+                id: r.u32()?,
+                size: r.u64()?,
+                sha256: r.hash()?,
+            },
+            DONE => FileMsg::Done { id: r.u32()? },
+            ERROR => FileMsg::Error {
+                id: r.u32()?,
+                message: r.str()?,
+            },
+            _ => return None,
+        };
+        if !r.0.is_empty() {
+            return None;
+        }
+        Some(msg)
+    }
+}
+
+/// 计算数据的 SHA-256
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// 计算文件的 SHA-256
+pub fn sha256_file(path: &Path) -> std::io::Result<[u8; 32]> {
+    sha256_reader(File::open(path)?)
+}
+
+/// 读到结尾, 计算读出内容的 SHA-256
+pub fn sha256_reader<R: Read>(mut file: R) -> std::io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// 未完成文件的路径, 文件名中带有摘要前缀, 内容不同的同名文件不会从错误的位置续传
+pub fn part_path(path: &Path, sha256: &[u8; 32]) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    for b in &sha256[..8] {
+        name.push(format!("{:02x}", b));
+    }
+    name.push(PART_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// 把远程路径解析到允许访问的根目录之下
+/// 拒绝 "..", 绝对路径等可能越出根目录的路径
+pub fn resolve(roots: &[PathBuf], path: &str) -> Option<PathBuf> {
+    let mut parts = path.split('/').filter(|p| !p.is_empty());
+    let root = roots.get(parts.next()?.parse::<usize>().ok()?)?;
+    let mut full = root.clone();
+    for part in parts {
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) => full.push(name),
+            _ => return None,
+        }
+    }
+    Some(full)
+}
+
+/// 校验文件名只有一段
+pub fn valid_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !name.contains('/')
+        && !name.contains('\\')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let msgs = vec![
+            FileMsg::ListRoots,
+            FileMsg::ListDir {
+                path: "0/日志".to_string(),
+            },
+            FileMsg::Upload {
+                id: 1,
+                dir: "0".to_string(),
+                name: "setup.exe".to_string(),
+                size: 1 << 40,
+                sha256: [7; 32],
+            },
+            FileMsg::Download {
+                id: 2,
+                path: "1/a/b.log".to_string(),
+            },
+            FileMsg::Resume { id: 2, offset: 100 },
+            FileMsg::Cancel { id: 3 },
+            FileMsg::Chunk {
+                id: 4,
+                offset: 65536,
+                data: vec![1, 2, 3],
+            },
+            FileMsg::Chunk {
+                id: 4,
+                offset: 0,
+                data: vec![],
+            },
+            FileMsg::Roots {
+                names: vec!["C:\\logs".to_string(), "/tmp".to_string()],
+            },
+            FileMsg::DirList {
+                path: "0".to_string(),
+                entries: vec![
+                    DirEntry {
+                        name: "a".to_string(),
+                        is_dir: true,
+                        size: 0,
+                    },
+                    DirEntry {
+                        name: "b.txt".to_string(),
+                        is_dir: false,
+                        size: 12,
+                    },
+                ],
+            },
+            FileMsg::UploadReady { id: 5, offset: 9 },
+            FileMsg::DownloadInfo {
+                id: 6,
+                size: 10,
+                sha256: [1; 32],
+            },
+            FileMsg::Done { id: 7 },
+            FileMsg::Error {
+                id: 8,
+                message: "no such file".to_string(),
+            },
+        ];
+        for msg in msgs {
+            assert_eq!(FileMsg::decode(&msg.encode()), Some(msg.clone()));
+        }
+        assert_eq!(FileMsg::decode(&[]), None);
+        assert_eq!(FileMsg::decode(&[DONE, 0, 0]), None);
+        assert_eq!(FileMsg::decode(&[DONE, 0, 0, 0, 1, 0]), None);
+        assert_eq!(FileMsg::decode(&[99]), None);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let roots = vec![PathBuf::from("/srv/a"), PathBuf::from("/srv/b")];
+        assert_eq!(resolve(&roots, "0"), Some(PathBuf::from("/srv/a")));
+        assert_eq!(
+            resolve(&roots, "1/x/y.txt"),
+            Some(PathBuf::from("/srv/b/x/y.txt"))
+        );
+        assert_eq!(resolve(&roots, "1//x/"), Some(PathBuf::from("/srv/b/x")));
+        assert_eq!(resolve(&roots, "2/x"), None);
+        assert_eq!(resolve(&roots, "0/../etc"), None);
+        assert_eq!(resolve(&roots, "0/./x"), None);
+        assert_eq!(resolve(&roots, ""), None);
+        assert_eq!(resolve(&roots, "x"), None);
+
+        assert!(valid_name("a.txt"));
+        assert!(!valid_name(".."));
+        assert!(!valid_name("a/b"));
+        assert!(!valid_name("a\\b"));
+        assert!(!valid_name(""));
+    }
+
+    #[test]
+    fn test_part_path() {
+        let mut sha256 = [0u8; 32];
+        sha256[0] = 0xab;
+        sha256[7] = 0x01;
+        assert_eq!(
+            part_path(Path::new("/tmp/a.log"), &sha256),
+            PathBuf::from("/tmp/a.log.ab00000000000001.part")
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use crate::VIDEO_FRAME;
+use std::ops::Range;
+
+/// 消息头的长度, 之后是条带表和各条带 deflate 压缩的数据
+pub const HEADER_LEN: usize = 23;
+/// 一帧最多切分的条带数
+pub const MAX_STRIPES: usize = 16;
+// 每个条带至少这么长, 较小的画面不切分
+const MIN_STRIPE: usize = 256 * 1024;
+/// 解压后一帧的最大长度可以通过环境变量修改, 单位为 MiB
+pub const MAX_FRAME_ENV: &str = "DIFFSCREEN_MAX_FRAME";
+/// 默认足够容纳 8K 的 RGB 画面
+pub const DEFAULT_MAX_FRAME: usize = 128 << 20;
+// 允许设置的范围
+const MAX_FRAME_LIMIT: usize = 1 << 30;
+
+/*
+图像帧消息字节序, 在 VIDEO 通道
++-------------+------+-----+-----------+--------+----------+---------+
+| VIDEO_FRAME | kind | seq | timestamp | length | checksum | stripes |
++-------------+------+-----+-----------+--------+----------+---------+
+|      1      |  1   |  4  |     8     |   4    |    4     |    1    |
++-------------+------+-----+-----------+--------+----------+---------+
+kind: 关键帧或差异帧
+seq: 帧序号, 每发送一帧加 1
+timestamp: 截屏时间, 微秒
+length: 解压后的长度, client在分配缓冲区之前检查
+checksum: 还原后完整一帧的校验和, client校验失败时请求关键帧
+stripes: 条带数, 1 ~ MAX_STRIPES
+
+消息头之后是条带表, 每个条带压缩后的长度占 4 字节, 然后依次是各条带的数据
+解压后的一帧按 stripe() 均分为条带, 各条带单独压缩, 两端可以并行压缩和解压
+*/
+
+/// 帧类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    // 完整的一帧
+    Key = 1,
+    // 与上一帧的异或
+    Delta = 2,
+}
+
+/// 图像帧的消息头
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub kind: FrameKind,
+    pub seq: u32,
+    pub timestamp: u64,
+    pub length: u32,
+    pub checksum: u32,
+    pub stripes: u8,
+}
+
+impl FrameHeader {
+    /// 追加到 buf, 压缩的数据接在后面
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(VIDEO_FRAME);
+        buf.push(self.kind as u8);
+        buf.extend_from_slice(&self.seq.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.length.to_be_bytes());
+        buf.extend_from_slice(&self.checksum.to_be_bytes());
+        buf.push(self.stripes);
+    }
+
+    /// 返回消息头和之后的条带表和数据
+    pub fn decode(buf: &[u8]) -> Option<(FrameHeader, &[u8])> {
+        if buf.len() < HEADER_LEN || buf[0] != VIDEO_FRAME {
+            return None;
+        }
+        let kind = match buf[1] {
+            1 => FrameKind::Key,
+            2 => FrameKind::Delta,
+            _ => return None,
+        };
+        let header = FrameHeader {
+            kind,
+            seq: u32::from_be_bytes(buf[2..6].try_into().ok()?),
+            timestamp: u64::from_be_bytes(buf[6..14].try_into().ok()?),
+            length: u32::from_be_bytes(buf[14..18].try_into().ok()?),
+            checksum: u32::from_be_bytes(buf[18..22].try_into().ok()?),
+            stripes: buf[22],
+        };
+        if header.stripes == 0 || header.stripes as usize > MAX_STRIPES {
+            return None;
+        }
+        Some((header, &buf[HEADER_LEN..]))
+    }
+
+    pub fn keyframe(&self) -> bool {
+        self.kind == FrameKind::Key
+    }
+}
+
+/// 按一帧的长度和可用的线程数选择条带数
+pub fn stripe_count(length: usize, threads: usize) -> usize {
+    length
+        .div_ceil(MIN_STRIPE)
+        .clamp(1, threads.clamp(1, MAX_STRIPES))
+}
+
+/// 第 i 个条带在解压后一帧中的范围, 各条带的长度最多相差 1
+pub fn stripe(length: usize, count: usize, i: usize) -> Range<usize> {
+    let at = |i: usize| (length as u64 * i as u64 / count as u64) as usize;
+    at(i)..at(i + 1)
+}
+
+/// 把解压的缓冲区切分为各条带
+pub fn stripes_mut(buf: &mut [u8], count: usize) -> Vec<&mut [u8]> {
+    let length = buf.len();
+    let mut rest = buf;
+    (0..count)
+        .map(|i| {
+            let (part, tail) =
+                std::mem::take(&mut rest).split_at_mut(stripe(length, count, i).len());
+            rest = tail;
+            part
+        })
+        .collect()
+}
+
+/// 追加条带表和各条带压缩后的数据
+pub fn encode_stripes(stripes: &[Vec<u8>], buf: &mut Vec<u8>) {
+    for data in stripes {
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    }
+    for data in stripes {
+        buf.extend_from_slice(data);
+    }
+}
+
+/// 按条带表切分压缩的数据, 长度必须正好用完
+pub fn split_stripes(data: &[u8], count: usize) -> Option<Vec<&[u8]>> {
+    let (table, mut rest) = data.split_at_checked(count * 4)?;
+    let mut stripes = Vec::with_capacity(count);
+    for len in table.chunks_exact(4) {
+        let len = u32::from_be_bytes(len.try_into().ok()?) as usize;
+        let (stripe, tail) = rest.split_at_checked(len)?;
+        stripes.push(stripe);
+        rest = tail;
+    }
+    rest.is_empty().then_some(stripes)
+}
+
+/// 一帧数据的校验和(CRC32)
+pub fn checksum(frame: &[u8]) -> u32 {
+    crc32fast::hash(frame)
+}
+
+/// 读取一帧的最大长度, 无效时使用默认值
+pub fn max_frame() -> usize {
+    parse_max_frame(std::env::var(MAX_FRAME_ENV).ok().as_deref())
+}
+
+fn parse_max_frame(value: Option<&str>) -> usize {
+    match value.and_then(|v| v.trim().parse::<usize>().ok()) {
+        Some(mib) if mib > 0 && mib <= MAX_FRAME_LIMIT >> 20 => mib << 20,
+        _ => DEFAULT_MAX_FRAME,
+    }
+}
+
+/// 接收图像帧需要的最大消息长度, 不可压缩的数据经过 deflate 后略有增加
+pub fn max_message(max_frame: usize) -> usize {
+    HEADER_LEN + max_frame + max_frame / 1024 + 64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let header = FrameHeader {
+            kind: FrameKind::Delta,
+            seq: u32::MAX,
+            timestamp: 0x0102030405060708,
+            // 超过 24 位的长度
+            length: 100 << 20,
+            checksum: checksum(b"123456789"),
+            stripes: 3,
+        };
+        assert_eq!(header.checksum, 0xcbf43926);
+        let mut buf = Vec::new();
+        header.encode(&mut buf);
+        assert_eq!(buf.len(), HEADER_LEN);
+        buf.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(FrameHeader::decode(&buf), Some((header, &[1u8, 2, 3][..])));
+        assert!(!header.keyframe());
+
+        // 未知的类型和不完整的消息头
+        buf[1] = 3;
+        assert_eq!(FrameHeader::decode(&buf), None);
+        buf[1] = FrameKind::Key as u8;
+        assert_eq!(FrameHeader::decode(&buf[..HEADER_LEN - 1]), None);
+        // 条带数超出范围
+        buf[22] = 0;
+        assert_eq!(FrameHeader::decode(&buf), None);
+        buf[22] = MAX_STRIPES as u8 + 1;
+        assert_eq!(FrameHeader::decode(&buf), None);
+    }
+
+    #[test]
+    fn test_stripes() {
+        assert_eq!(stripe_count(1000, 8), 1);
+        assert_eq!(stripe_count(MIN_STRIPE * 3 + 1, 8), 4);
+        assert_eq!(stripe_count(MIN_STRIPE * 100, 8), 8);
+        assert_eq!(stripe_count(MIN_STRIPE * 100, 64), MAX_STRIPES);
+        assert_eq!(stripe_count(MIN_STRIPE * 100, 0), 1);
+
+        // 各条带首尾相接, 覆盖整个范围
+        let ranges: Vec<Range<usize>> = (0..3).map(|i| stripe(10, 3, i)).collect();
+        assert_eq!(ranges, vec![0..3, 3..6, 6..10]);
+        let mut buf: Vec<u8> = (0..10).collect();
+        let parts = stripes_mut(&mut buf, 3);
+        assert_eq!(parts.iter().map(|p| p.len()).collect::<Vec<_>>(), [3, 3, 4]);
+        assert_eq!(parts[2], &[6, 7, 8, 9]);
+
+        let mut data = Vec::new();
+        encode_stripes(&[vec![1, 2], vec![], vec![3]], &mut data);
+        assert_eq!(data.len(), 3 * 4 + 3);
+        let stripes = split_stripes(&data, 3).unwrap();
+        assert_eq!(stripes, vec![&[1u8, 2][..], &[], &[3]]);
+        // 条带表与数据的长度不一致
+        assert_eq!(split_stripes(&data, 2), None);
+        assert_eq!(split_stripes(&data[..data.len() - 1], 3), None);
+        assert_eq!(split_stripes(&data[..8], 3), None);
+    }
+
+    #[test]
+    fn test_max_frame() {
+        assert_eq!(parse_max_frame(None), DEFAULT_MAX_FRAME);
+        assert_eq!(parse_max_frame(Some(" 256 ")), 256 << 20);
+        assert_eq!(parse_max_frame(Some("0")), DEFAULT_MAX_FRAME);
+        assert_eq!(parse_max_frame(Some("4096")), DEFAULT_MAX_FRAME);
+        assert!(max_message(DEFAULT_MAX_FRAME) > DEFAULT_MAX_FRAME);
+    }
+}
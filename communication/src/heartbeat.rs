@@ -0,0 +1,99 @@
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// client发送 PING 的间隔, server收到后立即回复 PONG
+pub const INTERVAL: Duration = Duration::from_secs(1);
+/// 超过这个时间没有收到任何消息, 认为对方已断开
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// 超时可以通过环境变量修改, 单位为秒
+pub const TIMEOUT_ENV: &str = "DIFFSCREEN_TIMEOUT";
+
+/*
+心跳消息字节序, 在 CONTROL 通道
++-----------------+-----------+
+| PING 或 PONG    | timestamp |
++-----------------+-----------+
+|       1         |     8     |
++-----------------+-----------+
+timestamp 为发送 PING 时的微秒数, PONG 原样返回
+*/
+pub fn ping(timestamp: u64) -> Vec<u8> {
+    encode(crate::PING, timestamp)
+}
+
+pub fn pong(timestamp: u64) -> Vec<u8> {
+    encode(crate::PONG, timestamp)
+}
+
+fn encode(kind: u8, timestamp: u64) -> Vec<u8> {
+    let mut data = Vec::with_capacity(9);
+    data.push(kind);
+    data.extend_from_slice(&timestamp.to_be_bytes());
+    data
+}
+
+/// 解析 PING 或 PONG, 返回 (类型, timestamp)
+pub fn decode(data: &[u8]) -> Option<(u8, u64)> {
+    match data {
+        [kind @ (crate::PING | crate::PONG), ts @ ..] if ts.len() == 8 => {
+            Some((*kind, u64::from_be_bytes(ts.try_into().ok()?)))
+        }
+        _ => None,
+    }
+}
+
+/// 当前时间, 微秒
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// 往返时间, 时钟回拨时为 0
+pub fn rtt(timestamp: u64) -> Duration {
+    Duration::from_micros(now().saturating_sub(timestamp))
+}
+
+/// 读取超时设置, 无效时使用默认值
+pub fn timeout() -> Duration {
+    parse_timeout(std::env::var(TIMEOUT_ENV).ok().as_deref())
+}
+
+fn parse_timeout(value: Option<&str>) -> Duration {
+    match value.and_then(|v| v.trim().parse::<f64>().ok()) {
+        // 至少要比心跳间隔长, 否则空闲时也会超时
+        Some(secs) if secs.is_finite() && secs > INTERVAL.as_secs_f64() => {
+            Duration::from_secs_f64(secs)
+        }
+        _ => DEFAULT_TIMEOUT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(decode(&ping(42)), Some((crate::PING, 42)));
+        assert_eq!(decode(&pong(u64::MAX)), Some((crate::PONG, u64::MAX)));
+        assert_eq!(decode(&ping(1)[..8]), None);
+        assert_eq!(
+            decode(&[crate::CLIPBOARD_POLICY, 0, 0, 0, 0, 0, 0, 0, 0]),
+            None
+        );
+        assert_eq!(rtt(now() + 1_000_000), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_timeout() {
+        assert_eq!(parse_timeout(None), DEFAULT_TIMEOUT);
+        assert_eq!(parse_timeout(Some(" 30 ")), Duration::from_secs(30));
+        assert_eq!(parse_timeout(Some("2.5")), Duration::from_millis(2500));
+        assert_eq!(parse_timeout(Some("0.5")), DEFAULT_TIMEOUT);
+        assert_eq!(parse_timeout(Some("abc")), DEFAULT_TIMEOUT);
+        assert_eq!(parse_timeout(Some("inf")), DEFAULT_TIMEOUT);
+    }
+}
@@ -1,4 +1,3 @@
-
 // key事件 start
 pub const KEY_UP: u8 = 1;
 pub const KEY_DOWN: u8 = 2;
@@ -7,5 +6,85 @@ pub const MOUSE_KEY_DOWN: u8 = 4;
 pub const MOUSE_WHEEL_UP: u8 = 5;
 pub const MOUSE_WHEEL_DOWN: u8 = 6;
 pub const MOVE: u8 = 7;
+// 组合键: KEY_COMBO n k1 .. kn, server 依次按下后再逆序放开
+pub const KEY_COMBO: u8 = 8;
 // key事件 end
-pub mod convert;
\ No newline at end of file
+
+// 握手 start
+// client 先发送认证方式: AUTH_PASSWORD 密码哈希(8) 或 AUTH_TOKEN 会话token(TOKEN_LEN)
+pub const AUTH_PASSWORD: u8 = 1;
+pub const AUTH_TOKEN: u8 = 2;
+// server 回复结果(1), 成功时后跟 token(TOKEN_LEN) 和 meta(4)
+pub const AUTH_OK: u8 = 1;
+pub const AUTH_BAD_PASSWORD: u8 = 2;
+// token 不存在或已过期, client 需要重新用密码登录
+pub const AUTH_BAD_TOKEN: u8 = 3;
+pub const TOKEN_LEN: usize = 16;
+// 握手 end
+
+// 握手之后所有消息都通过 mux 的逻辑通道发送
+// 键鼠事件在 INPUT 通道, 剪贴板在 CLIPBOARD 通道, 文件传输在 FILE 通道, 图像帧在 VIDEO 通道
+
+// 控制消息 start
+// CONTROL 通道, 第一个字节为消息类型
+// 剪贴板同步策略: CLIPBOARD_POLICY policy
+pub const CLIPBOARD_POLICY: u8 = 10;
+// 心跳: PING timestamp, 对方回复 PONG timestamp, 见 heartbeat 模块
+pub const PING: u8 = 11;
+pub const PONG: u8 = 12;
+// 会话结束: SESSION_END 原因(utf8), 之后server关闭连接
+pub const SESSION_END: u8 = 13;
+// 显示器列表: DISPLAY_LIST 列表, 见 display 模块, 显示器变化时重新发送
+pub const DISPLAY_LIST: u8 = 14;
+// 选择显示器: SELECT_DISPLAY 序号, display::ALL 为所有显示器
+pub const SELECT_DISPLAY: u8 = 15;
+// 请求画面尺寸: SET_RESOLUTION w(2) h(2), server保持宽高比缩小到不超过这个尺寸, 0 为原始分辨率
+pub const SET_RESOLUTION: u8 = 16;
+// 发送方式: FRAME_MODE mode, 默认画面变化时持续发送
+pub const FRAME_MODE: u8 = 17;
+pub const FRAME_PUSH: u8 = 0;
+// 按需发送, client每发送一个 FRAME_REQUEST, server发送一帧变化的画面
+pub const FRAME_PULL: u8 = 1;
+pub const FRAME_REQUEST: u8 = 18;
+// 请求颜色空间: SET_COLOR_SPACE 颜色空间, 见 convert::ColorSpace, 默认为 BT.601 limited
+pub const SET_COLOR_SPACE: u8 = 19;
+// 请求像素格式: SET_PIXEL_FORMAT 格式, 见 convert::PixelFormat, 默认为 I420
+pub const SET_PIXEL_FORMAT: u8 = 20;
+// 请求关键帧: REFRESH, client画面出错或用户要求刷新时发送
+pub const REFRESH: u8 = 21;
+// 请求一张完整画面: SNAPSHOT, server按原始分辨率截屏, 无损地单独发送一次, 见 snapshot 模块
+pub const SNAPSHOT: u8 = 22;
+// 控制消息 end
+
+// 视频消息 start
+// VIDEO 通道, 类型(1) + 数据, 尺寸变化和图像帧在同一通道, 保证顺序
+// 图像帧: VIDEO_FRAME 消息头 deflate(I420 或 RGB 的一帧, 或与上一帧的异或), 见 frame 模块
+pub const VIDEO_FRAME: u8 = 1;
+// 显示配置变化: VIDEO_DISPLAY w(2) h(2) 颜色空间(1) 像素格式(1), 之后是新尺寸的完整一帧
+// 会话开始时先发送一次, client按其中的颜色空间和格式解码
+pub const VIDEO_DISPLAY: u8 = 2;
+// 完整画面: VIDEO_SNAPSHOT w(2) h(2) deflate(RGB), 回复 SNAPSHOT, 不影响图像帧的解码
+pub const VIDEO_SNAPSHOT: u8 = 3;
+// 视频消息 end
+
+// 光标消息 start
+// CURSOR 通道, 类型(1) + 数据
+pub const CURSOR_POS: u8 = 2;
+pub const CURSOR_SHAPE: u8 = 3;
+// 光标消息 end
+pub mod clipboard;
+pub mod convert;
+pub mod cursor;
+pub mod decode;
+pub mod display;
+pub mod file;
+pub mod frame;
+pub mod heartbeat;
+pub mod mux;
+pub mod pool;
+pub mod record;
+pub mod snapshot;
+pub mod video;
+
+/// 一个组合键最多包含的按键数
+pub const MAX_COMBO_KEYS: usize = 8;
@@ -0,0 +1,430 @@
+use crate::pool::Buffer;
+use crate::pool::BufferPool;
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+
+// 逻辑通道 start
+/// 控制消息, 第一个字节为消息类型
+pub const CONTROL: u8 = 0;
+/// 键鼠事件, 每个消息为一条指令
+pub const INPUT: u8 = 1;
+/// 光标, 第一个字节为 CURSOR_POS / CURSOR_SHAPE
+pub const CURSOR: u8 = 2;
+/// 剪贴板内容
+pub const CLIPBOARD: u8 = 3;
+/// 文件传输, file::FileMsg
+pub const FILE: u8 = 4;
+/// 图像帧
+pub const VIDEO: u8 = 5;
+// 逻辑通道 end
+
+/// 通道数
+pub const CHANNELS: usize = 6;
+
+/// 各通道的优先级, 越小越优先, 相同优先级的通道轮流发送
+const PRIORITIES: [u8; CHANNELS] = [0, 0, 1, 2, 3, 3];
+
+/// 每个数据块的最大长度, 大消息被切分, 高优先级的消息最多等待一个数据块
+pub const CHUNK_SIZE: usize = 16 * 1024;
+/// 单个消息的默认最大长度
+pub const MAX_MESSAGE: usize = 64 * 1024 * 1024;
+/// 每个通道排队的字节数上限, 超过时发送方阻塞
+const QUEUE_LIMIT: usize = 4 * 1024 * 1024;
+// 接收端保留的空闲缓冲区个数
+const READER_IDLE: usize = CHANNELS;
+
+// 消息的最后一个数据块
+const END: u8 = 1;
+
+/*
+数据块字节序
++---------+---------+-------------------+
+|    8    |    8    |        16         |
++---------+---------+-------------------+
+| channel |  flags  |      length       |
++---------+---------+-------------------+
+|                 data                  |
++---------------------------------------+
+channel: 逻辑通道
+flags: END 表示消息的最后一个数据块
+length: 数据长度, 不超过 CHUNK_SIZE
+*/
+
+struct Message {
+    // 发送完后回到原来的池中
+    data: Buffer,
+    // 已发送的长度
+    sent: usize,
+}
+
+/// 各通道的发送队列
+#[derive(Default)]
+struct Queues {
+    queues: [VecDeque<Message>; CHANNELS],
+    // 各通道排队的字节数
+    queued: [usize; CHANNELS],
+    // 上一次发送的通道, 用于相同优先级的轮转
+    last: usize,
+}
+
+impl Queues {
+    fn push(&mut self, channel: u8, data: Buffer) {
+        let c = channel as usize;
+        self.queued[c] += data.len();
+        self.queues[c].push_back(Message { data, sent: 0 });
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(|q| q.is_empty())
+    }
+
+    fn clear(&mut self) {
+        for c in 0..CHANNELS {
+            self.queues[c].clear();
+            self.queued[c] = 0;
+        }
+    }
+
+    /// 选出优先级最高的通道, 取出其下一个数据块 (header + data)
+    fn next_chunk(&mut self) -> Option<Vec<u8>> {
+        let priority = (0..CHANNELS)
+            .filter(|c| !self.queues[*c].is_empty())
+            .map(|c| PRIORITIES[c])
+            .min()?;
+        let c = (1..=CHANNELS)
+            .map(|i| (self.last + i) % CHANNELS)
+            .find(|c| !self.queues[*c].is_empty() && PRIORITIES[*c] == priority)?;
+        self.last = c;
+
+        let msg = self.queues[c].front_mut()?;
+        let len = (msg.data.len() - msg.sent).min(CHUNK_SIZE);
+        let end = msg.sent + len == msg.data.len();
+        let mut chunk = Vec::with_capacity(4 + len);
+        chunk.push(c as u8);
+        chunk.push(if end { END } else { 0 });
+        chunk.extend_from_slice(&(len as u16).to_be_bytes());
+        chunk.extend_from_slice(&msg.data[msg.sent..msg.sent + len]);
+        msg.sent += len;
+        self.queued[c] -= len;
+        if end {
+            self.queues[c].pop_front();
+        }
+        Some(chunk)
+    }
+}
+
+struct State {
+    queues: Queues,
+    // 发送方个数, 为0时写线程退出
+    senders: usize,
+    // 有数据块正在写入
+    writing: bool,
+    // 连接已断开
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+/**
+ * 多路复用的发送端
+ * 各线程通过同一个写线程发送, 消息按通道优先级切分交错, 同一通道内保持顺序
+ */
+pub struct MuxSender {
+    shared: Arc<Shared>,
+}
+
+impl MuxSender {
+    /// 启动写线程
+    pub fn start<W: Write + Send + 'static>(mut writer: W) -> MuxSender {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queues: Queues::default(),
+                senders: 1,
+                writing: false,
+                closed: false,
+            }),
+            cond: Condvar::new(),
+        });
+        let ws = shared.clone();
+        std::thread::spawn(move || loop {
+            let chunk = {
+                let mut state = ws.state.lock().unwrap();
+                loop {
+                    if let Some(chunk) = state.queues.next_chunk() {
+                        state.writing = true;
+                        // 队列有空位, 唤醒等待的发送方
+                        ws.cond.notify_all();
+                        break chunk;
+                    }
+                    if state.senders == 0 || state.closed {
+                        return;
+                    }
+                    state = ws.cond.wait(state).unwrap();
+                }
+            };
+            let res = writer.write_all(&chunk).and_then(|_| writer.flush());
+            let mut state = ws.state.lock().unwrap();
+            state.writing = false;
+            ws.cond.notify_all();
+            if res.is_err() {
+                state.closed = true;
+                state.queues.clear();
+                return;
+            }
+        });
+        MuxSender { shared }
+    }
+
+    /// 在通道 channel 上发送一个消息, 队列满时阻塞
+    pub fn send(&self, channel: u8, data: &[u8]) -> io::Result<()> {
+        self.send_buffer(channel, data.to_vec().into())
+    }
+
+    /// 与 send 相同, 但不复制数据, 发送完后缓冲区回到原来的池中
+    pub fn send_buffer(&self, channel: u8, data: Buffer) -> io::Result<()> {
+        if channel as usize >= CHANNELS {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "bad channel"));
+        }
+        let c = channel as usize;
+        let mut state = self.shared.state.lock().unwrap();
+        while !state.closed
+            && state.queues.queued[c] > 0
+            && state.queues.queued[c] + data.len() > QUEUE_LIMIT
+        {
+            state = self.shared.cond.wait(state).unwrap();
+        }
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.queues.push(channel, data);
+        self.shared.cond.notify_all();
+        Ok(())
+    }
+
+    /// 等待已排队的消息全部发出
+    pub fn flush(&self) -> io::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        while !state.closed && (state.writing || !state.queues.is_empty()) {
+            state = self.shared.cond.wait(state).unwrap();
+        }
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        Ok(())
+    }
+}
+
+impl Clone for MuxSender {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        MuxSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for MuxSender {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        self.shared.cond.notify_all();
+    }
+}
+
+/// 多路复用的接收端, 把数据块重新组装为完整的消息
+pub struct MuxReader<R: Read> {
+    inner: R,
+    partial: [Buffer; CHANNELS],
+    // 返回的消息用完后回到这里, 下一个消息复用
+    pool: BufferPool,
+    // 单个消息的最大长度
+    max_message: usize,
+}
+
+impl<R: Read> MuxReader<R> {
+    pub fn new(inner: R, max_message: usize) -> Self {
+        let pool = BufferPool::new(READER_IDLE);
+        MuxReader {
+            inner,
+            partial: std::array::from_fn(|_| pool.get()),
+            pool,
+            max_message,
+        }
+    }
+
+    /// 接收下一个完整的消息, 返回 (通道, 数据)
+    pub fn recv(&mut self) -> io::Result<(u8, Buffer)> {
+        let mut header = [0u8; 4];
+        loop {
+            self.inner.read_exact(&mut header)?;
+            let c = header[0] as usize;
+            let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+            if c >= CHANNELS || len > CHUNK_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "bad chunk"));
+            }
+            let buf = &mut self.partial[c];
+            if buf.len() + len > self.max_message {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "message too large",
+                ));
+            }
+            let start = buf.len();
+            buf.resize(start + len, 0);
+            self.inner.read_exact(&mut buf[start..])?;
+            if header[1] & END != 0 {
+                return Ok((c as u8, std::mem::replace(buf, self.pool.get())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 可以在写线程和测试线程之间共享的缓冲区
+    #[derive(Clone, Default)]
+    struct Pipe(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn drain(queues: &mut Queues) -> Vec<u8> {
+        let mut out = Vec::new();
+        while let Some(chunk) = queues.next_chunk() {
+            out.extend_from_slice(&chunk);
+        }
+        out
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let pipe = Pipe::default();
+        let sender = MuxSender::start(pipe.clone());
+        let big: Vec<u8> = (0..CHUNK_SIZE * 3 + 5).map(|i| i as u8).collect();
+        let other = sender.clone();
+        sender.send(VIDEO, &big).unwrap();
+        other.send(INPUT, &[1, 2]).unwrap();
+        sender.send(CONTROL, &[]).unwrap();
+        sender.flush().unwrap();
+
+        let data = pipe.0.lock().unwrap().clone();
+        let mut reader = MuxReader::new(&data[..], big.len());
+        let mut got = Vec::new();
+        while let Ok((c, msg)) = reader.recv() {
+            got.push((c, msg.into_vec()));
+        }
+        got.sort();
+        assert_eq!(
+            got,
+            vec![(CONTROL, vec![]), (INPUT, vec![1, 2]), (VIDEO, big)]
+        );
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut queues = Queues::default();
+        let big = vec![7u8; CHUNK_SIZE * 4];
+        queues.push(VIDEO, big.clone().into());
+        // 第一个数据块已经开始发送
+        let first = queues.next_chunk().unwrap();
+        assert_eq!(first[0], VIDEO);
+        queues.push(INPUT, vec![1, 2].into());
+        // 输入不必等待整帧发送完
+        let next = queues.next_chunk().unwrap();
+        assert_eq!(next, vec![INPUT, END, 0, 2, 1, 2]);
+
+        // 相同优先级的通道轮流发送
+        queues.push(FILE, big.into());
+        let order: Vec<u8> = (0..4).map(|_| queues.next_chunk().unwrap()[0]).collect();
+        assert_eq!(order, vec![FILE, VIDEO, FILE, VIDEO]);
+        drain(&mut queues);
+        assert!(queues.is_empty());
+        assert_eq!(queues.queued, [0; CHANNELS]);
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let mut queues = Queues::default();
+        let a: Vec<u8> = (0..CHUNK_SIZE * 2).map(|i| (i % 251) as u8).collect();
+        let b: Vec<u8> = (0..CHUNK_SIZE + 1).map(|i| (i % 13) as u8).collect();
+        queues.push(VIDEO, a.clone().into());
+        queues.push(FILE, b.clone().into());
+        queues.push(FILE, vec![9].into());
+        let data = drain(&mut queues);
+
+        let mut reader = MuxReader::new(&data[..], 1 << 20);
+        let mut video = Vec::new();
+        let mut file = Vec::new();
+        while let Ok((c, msg)) = reader.recv() {
+            match c {
+                VIDEO => video.push(msg.into_vec()),
+                FILE => file.push(msg.into_vec()),
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(video, vec![a]);
+        assert_eq!(file, vec![b, vec![9]]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut queues = Queues::default();
+        queues.push(FILE, vec![0u8; 100].into());
+        let data = drain(&mut queues);
+        let mut reader = MuxReader::new(&data[..], 99);
+        assert_eq!(
+            reader.recv().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let mut reader = MuxReader::new(&[CHANNELS as u8, END, 0, 0][..], 99);
+        assert_eq!(
+            reader.recv().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let sender = MuxSender::start(Pipe::default());
+        assert!(sender.send(CHANNELS as u8, &[]).is_err());
+    }
+
+    #[test]
+    fn test_buffer_reuse() {
+        // 发送完的缓冲区回到池中
+        let pipe = Pipe::default();
+        let sender = MuxSender::start(pipe.clone());
+        let pool = BufferPool::new(2);
+        let mut buf = pool.get();
+        buf.extend_from_slice(&[5u8; CHUNK_SIZE + 1]);
+        sender.send_buffer(VIDEO, buf).unwrap();
+        sender.flush().unwrap();
+        assert_eq!(pool.idle(), 1);
+
+        // 接收端用完的消息被下一个消息复用
+        let data = pipe.0.lock().unwrap().clone();
+        let data = [&data[..], &data[..]].concat();
+        let mut reader = MuxReader::new(&data[..], 1 << 20);
+        let (_, first) = reader.recv().unwrap();
+        drop(first);
+        assert_eq!(reader.pool.idle(), 1);
+        let (c, second) = reader.recv().unwrap();
+        assert_eq!((c, &second[..]), (VIDEO, &[5u8; CHUNK_SIZE + 1][..]));
+        assert_eq!(reader.pool.idle(), 0);
+    }
+}
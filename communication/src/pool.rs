@@ -0,0 +1,200 @@
+use std::io;
+use std::io::Write;
+use std::ops::Deref;
+use std::ops::DerefMut;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+
+// 空闲的缓冲区
+type Idle = Mutex<Vec<Vec<u8>>>;
+
+/**
+ * 缓冲区池
+ * 截屏, 转换, 压缩, 发送和接收之间传递的缓冲区用完后回到池中, 下次取出时复用已分配的内存
+ * 可以在线程之间共享, clone 得到的是同一个池
+ */
+#[derive(Clone)]
+pub struct BufferPool {
+    idle: Arc<Idle>,
+    // 最多保留的空闲缓冲区个数
+    max_idle: usize,
+}
+
+impl BufferPool {
+    pub fn new(max_idle: usize) -> Self {
+        BufferPool {
+            idle: Arc::new(Mutex::new(Vec::new())),
+            max_idle,
+        }
+    }
+
+    /// 取出一个空的缓冲区, 没有空闲的时新建
+    pub fn get(&self) -> Buffer {
+        let data = self.idle.lock().unwrap().pop().unwrap_or_default();
+        Buffer {
+            data,
+            pool: Some((Arc::downgrade(&self.idle), self.max_idle)),
+        }
+    }
+
+    /// 空闲的缓冲区个数
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
+
+/**
+ * 从池中取出的缓冲区, 使用方式与 Vec<u8> 相同
+ * drop 时清空并放回池中, 池已经释放或空闲的已满时直接释放
+ */
+#[derive(Default)]
+pub struct Buffer {
+    data: Vec<u8>,
+    pool: Option<(Weak<Idle>, usize)>,
+}
+
+impl Buffer {
+    /// 取出内部的 Vec, 不再放回池中
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.pool = None;
+        std::mem::take(&mut self.data)
+    }
+}
+
+/// 不属于任何池的缓冲区
+impl From<Vec<u8>> for Buffer {
+    fn from(data: Vec<u8>) -> Self {
+        Buffer { data, pool: None }
+    }
+}
+
+impl Deref for Buffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.data
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.data
+    }
+}
+
+impl std::fmt::Debug for Buffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.data.fmt(f)
+    }
+}
+
+/// 可以直接作为压缩器的输出
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        let (idle, max_idle) = match self.pool.take() {
+            Some((pool, max_idle)) => match pool.upgrade() {
+                Some(idle) => (idle, max_idle),
+                None => return,
+            },
+            None => return,
+        };
+        // 没有分配过内存的不需要保留
+        if self.data.capacity() == 0 {
+            return;
+        }
+        let mut idle = idle.lock().unwrap();
+        if idle.len() < max_idle {
+            self.data.clear();
+            idle.push(std::mem::take(&mut self.data));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse() {
+        let pool = BufferPool::new(2);
+        let mut a = pool.get();
+        a.extend_from_slice(&[1, 2, 3]);
+        let ptr = a.as_ptr();
+        let capacity = a.capacity();
+        drop(a);
+        assert_eq!(pool.idle(), 1);
+
+        // 取出的是清空后的同一块内存
+        let b = pool.get();
+        assert!(b.is_empty());
+        assert_eq!((b.as_ptr(), b.capacity()), (ptr, capacity));
+        assert_eq!(pool.idle(), 0);
+
+        // 没有分配过内存的不放回
+        drop(pool.get());
+        assert_eq!(pool.idle(), 0);
+
+        // 超过上限的直接释放
+        let buffers: Vec<Buffer> = (0..3)
+            .map(|i| {
+                let mut buf = pool.get();
+                buf.push(i);
+                buf
+            })
+            .collect();
+        drop(b);
+        drop(buffers);
+        assert_eq!(pool.idle(), 2);
+    }
+
+    #[test]
+    fn test_detached() {
+        let pool = BufferPool::new(2);
+        let mut buf = pool.get();
+        buf.write_all(b"abc").unwrap();
+        // 取出的 Vec 和普通的 Vec 都不回到池中
+        assert_eq!(buf.into_vec(), b"abc");
+        drop(Buffer::from(vec![1, 2]));
+        assert_eq!(pool.idle(), 0);
+
+        // 池释放后缓冲区仍然可以使用
+        let mut buf = pool.get();
+        drop(pool);
+        buf.push(1);
+        assert_eq!(&buf[..], &[1]);
+    }
+
+    #[test]
+    fn test_threads() {
+        let pool = BufferPool::new(4);
+        let (tx, rx) = std::sync::mpsc::sync_channel::<Buffer>(2);
+        let producer = {
+            let pool = pool.clone();
+            std::thread::spawn(move || {
+                for i in 0..20u8 {
+                    let mut buf = pool.get();
+                    buf.resize(64, i);
+                    tx.send(buf).unwrap();
+                }
+            })
+        };
+        // 在另一个线程用完后放回
+        for i in 0..20u8 {
+            let buf = rx.recv().unwrap();
+            assert!(buf.len() == 64 && buf.iter().all(|&b| b == i));
+        }
+        producer.join().unwrap();
+        assert!(pool.idle() >= 1 && pool.idle() <= 4);
+    }
+}
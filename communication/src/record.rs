@@ -0,0 +1,301 @@
+use crate::heartbeat;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// 录像文件保存的目录, 设置后每个会话写一个录像文件
+pub const RECORD_DIR_ENV: &str = "DIFFSCREEN_RECORD_DIR";
+/// 录像文件的扩展名
+pub const EXTENSION: &str = "dsrec";
+/// 文件开头的标识
+pub const MAGIC: &[u8; 8] = b"DSRECORD";
+pub const VERSION: u8 = 1;
+// 每条记录头的长度
+const RECORD_HEADER_LEN: usize = 13;
+
+/*
+录像文件字节序
++----------+---------+
+|  MAGIC   | VERSION |
++----------+---------+
+|    8     |    1    |
++----------+---------+
+之后是若干条记录, 直到文件结束
++---------+-----------+--------+------+
+| channel | timestamp | length | data |
++---------+-----------+--------+------+
+|    1    |     8     |   4    |      |
++---------+-----------+--------+------+
+channel: 消息所在的 mux 通道
+    VIDEO: 与发送的消息相同, VIDEO_DISPLAY 或 VIDEO_FRAME, 第一帧之前一定有 VIDEO_DISPLAY
+    INPUT: client发送的一条键鼠指令
+timestamp: 记录的时间, 微秒
+length: data 的长度
+*/
+
+/// 一条记录
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub channel: u8,
+    pub timestamp: u64,
+    pub data: Vec<u8>,
+}
+
+/// 写录像文件
+pub struct RecordWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> RecordWriter<W> {
+    /// 写入文件头
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(MAGIC)?;
+        inner.write_all(&[VERSION])?;
+        Ok(RecordWriter { inner })
+    }
+
+    pub fn write(&mut self, channel: u8, timestamp: u64, data: &[u8]) -> io::Result<()> {
+        let length = u32::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too large"))?;
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        header[0] = channel;
+        header[1..9].copy_from_slice(&timestamp.to_be_bytes());
+        header[9..].copy_from_slice(&length.to_be_bytes());
+        self.inner.write_all(&header)?;
+        self.inner.write_all(data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+/// 读录像文件
+pub struct RecordReader<R: Read> {
+    inner: R,
+    // 下一条记录在文件中的位置
+    offset: u64,
+}
+
+impl<R: Read> RecordReader<R> {
+    /// 检查文件头
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = [0u8; MAGIC.len() + 1];
+        inner.read_exact(&mut header)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a recording",
+            ));
+        }
+        if header[MAGIC.len()] != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unsupported recording version",
+            ));
+        }
+        Ok(RecordReader {
+            inner,
+            offset: header.len() as u64,
+        })
+    }
+
+    /// 读取下一条记录, 文件正好结束时返回 None, 只写了一半的记录返回错误
+    pub fn read(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        let mut read = 0;
+        while read < header.len() {
+            match self.inner.read(&mut header[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let timestamp = u64::from_be_bytes(header[1..9].try_into().unwrap());
+        let length = u32::from_be_bytes(header[9..].try_into().unwrap()) as u64;
+        // 按实际读到的数据增长, 损坏的长度不会一次分配很大的缓冲区
+        let mut data = Vec::new();
+        (&mut self.inner).take(length).read_to_end(&mut data)?;
+        if data.len() as u64 != length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.offset += RECORD_HEADER_LEN as u64 + length;
+        Ok(Some(Record {
+            channel: header[0],
+            timestamp,
+            data,
+        }))
+    }
+
+    /// 下一条记录的位置
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl<R: Read + Seek> RecordReader<R> {
+    /// 跳到 offset() 返回过的位置
+    pub fn seek(&mut self, offset: u64) -> io::Result<()> {
+        self.inner.seek(SeekFrom::Start(offset))?;
+        self.offset = offset;
+        Ok(())
+    }
+}
+
+/**
+ * 会话录像
+ * 可以在多个线程中共享, 写入出错后停止录像, 不影响会话
+ */
+pub struct Recorder {
+    writer: Mutex<Option<RecordWriter<BufWriter<File>>>>,
+    path: PathBuf,
+}
+
+impl Recorder {
+    /// 设置了录像目录时, 在其中创建 {prefix}-{微秒时间戳}.dsrec, 没有设置时返回 None
+    /// 创建失败的错误中带有文件路径
+    pub fn from_env(prefix: &str) -> Option<io::Result<Recorder>> {
+        let dir = std::env::var_os(RECORD_DIR_ENV)?;
+        let path = Path::new(&dir).join(format!("{}-{}.{}", prefix, heartbeat::now(), EXTENSION));
+        Some(
+            Recorder::create(&path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+        )
+    }
+
+    pub fn create(path: &Path) -> io::Result<Recorder> {
+        let writer = RecordWriter::new(BufWriter::new(File::create(path)?))?;
+        Ok(Recorder {
+            writer: Mutex::new(Some(writer)),
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// 记录一个消息, 时间为当前时间
+    /// 写入出错时停止录像并返回这次的错误, 之后的消息直接忽略
+    pub fn record(&self, channel: u8, data: &[u8]) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(w) = writer.as_mut() {
+            if let Err(e) = w.write(channel, heartbeat::now(), data) {
+                *writer = None;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// 设置了录像目录时开始录像, 失败时不录像, 不影响会话
+/// 开始录像或失败的提示交给 report, 由调用方决定是否打印
+pub fn start(prefix: &str, report: impl FnOnce(String)) -> Option<Recorder> {
+    match Recorder::from_env(prefix)? {
+        Ok(recorder) => {
+            report(format!("Recording to {}", recorder.path().display()));
+            Some(recorder)
+        }
+        Err(e) => {
+            report(format!("Failed to record to {}", e));
+            None
+        }
+    }
+}
+
+/// 录像时记录一个消息, 没有录像时什么也不做
+/// 写入出错时录像停止, 返回的错误中带有文件路径, 由调用方决定是否打印
+pub fn record(recorder: Option<&Recorder>, channel: u8, data: &[u8]) -> io::Result<()> {
+    match recorder {
+        Some(recorder) => recorder.record(channel, data).map_err(|e| {
+            let message = format!("Recording to {} stopped: {}", recorder.path().display(), e);
+            io::Error::new(e.kind(), message)
+        }),
+        None => Ok(()),
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Some(w) = self.writer.get_mut().unwrap().as_mut() {
+            let _ = w.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = RecordWriter::new(Vec::new()).unwrap();
+        writer.write(crate::mux::VIDEO, 1, &[1, 2, 3]).unwrap();
+        writer.write(crate::mux::INPUT, u64::MAX, &[]).unwrap();
+        let data = writer.into_inner();
+
+        let mut reader = RecordReader::new(Cursor::new(&data)).unwrap();
+        let first = reader.read().unwrap().unwrap();
+        assert_eq!(
+            first,
+            Record {
+                channel: crate::mux::VIDEO,
+                timestamp: 1,
+                data: vec![1, 2, 3]
+            }
+        );
+        let offset = reader.offset();
+        let second = reader.read().unwrap().unwrap();
+        assert_eq!((second.timestamp, second.data.len()), (u64::MAX, 0));
+        assert_eq!(reader.read().unwrap(), None);
+        assert_eq!(reader.offset(), data.len() as u64);
+
+        // 回到记过的位置
+        reader.seek(offset).unwrap();
+        assert_eq!(reader.read().unwrap(), Some(second));
+
+        // 文件头错误和只写了一半的记录
+        assert!(RecordReader::new(&b"DSRECORD\x02"[..]).is_err());
+        assert!(RecordReader::new(&data[..4]).is_err());
+        let mut reader = RecordReader::new(&data[..data.len() - 1]).unwrap();
+        reader.read().unwrap();
+        assert_eq!(
+            reader.read().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        let mut reader = RecordReader::new(&data[..MAGIC.len() + 3 + 3]).unwrap();
+        assert!(reader.read().is_err());
+    }
+
+    #[test]
+    fn test_recorder() {
+        let path =
+            std::env::temp_dir().join(format!("recorder-{}.{}", heartbeat::now(), EXTENSION));
+        let recorder = Recorder::create(&path).unwrap();
+        recorder.record(crate::mux::INPUT, &[7]).unwrap();
+        assert_eq!(recorder.path(), path);
+        drop(recorder);
+
+        let mut reader = RecordReader::new(File::open(&path).unwrap()).unwrap();
+        let record = reader.read().unwrap().unwrap();
+        assert_eq!((record.channel, record.data), (crate::mux::INPUT, vec![7]));
+        assert!(record.timestamp > 0);
+        assert_eq!(reader.read().unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+use crate::convert;
+use crate::heartbeat;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// 截图保存的目录, 未设置时为当前目录
+pub const SCREENSHOT_DIR_ENV: &str = "DIFFSCREEN_SCREENSHOT_DIR";
+
+/*
+完整画面字节序
++----------------+-----+-----+---------------+
+| VIDEO_SNAPSHOT |  w  |  h  | deflate(RGB)  |
++----------------+-----+-----+---------------+
+|       1        |  2  |  2  |               |
++----------------+-----+-----+---------------+
+原始分辨率的 RGB, 每行没有填充, 不经过缩小和 I420 转换
+server截屏失败时只有 VIDEO_SNAPSHOT 一个字节
+*/
+
+/// server截取的 BGRA 画面编码为 VIDEO_SNAPSHOT 消息
+pub fn encode(w: usize, h: usize, stride: usize, bgra: &[u8]) -> Vec<u8> {
+    let mut rgb = Vec::new();
+    convert::bgra_to_rgb(w, h, stride, bgra, &mut rgb);
+    let mut msg = vec![crate::VIDEO_SNAPSHOT];
+    msg.extend_from_slice(&(w as u16).to_be_bytes());
+    msg.extend_from_slice(&(h as u16).to_be_bytes());
+    let mut e = DeflateEncoder::new(msg, Compression::fast());
+    // 写入 Vec 不会失败
+    e.write_all(&rgb).unwrap();
+    e.finish().unwrap()
+}
+
+/// 截屏失败
+pub fn failed() -> Vec<u8> {
+    vec![crate::VIDEO_SNAPSHOT]
+}
+
+/// 解码 VIDEO_SNAPSHOT, 返回 w h 和 RGB 数据; 截屏失败, 数据损坏或解压后超过 max_frame 时返回 None
+pub fn decode(msg: &[u8], max_frame: usize) -> Option<(usize, usize, Vec<u8>)> {
+    let (w, h, data) = match msg {
+        [crate::VIDEO_SNAPSHOT, w1, w2, h1, h2, data @ ..] => (
+            u16::from_be_bytes([*w1, *w2]) as usize,
+            u16::from_be_bytes([*h1, *h2]) as usize,
+            data,
+        ),
+        _ => return None,
+    };
+    let length = w * h * 3;
+    if length == 0 || length > max_frame {
+        return None;
+    }
+    let mut rgb = Vec::with_capacity(length);
+    DeflateDecoder::new(data)
+        .take(length as u64 + 1)
+        .read_to_end(&mut rgb)
+        .ok()?;
+    if rgb.len() != length {
+        return None;
+    }
+    Some((w, h, rgb))
+}
+
+/// 新截图的文件名: 截图目录下的 screenshot-{微秒时间戳}.png
+pub fn path() -> PathBuf {
+    let dir = std::env::var_os(SCREENSHOT_DIR_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    dir.join(format!("screenshot-{}.png", heartbeat::now()))
+}
+
+/// 把 RGB 画面保存为 PNG 文件
+pub fn save_png(path: &Path, w: usize, h: usize, rgb: &[u8]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_png(&mut out, w, h, rgb)?;
+    out.flush()
+}
+
+/// 8 位 RGB 的 PNG, 每行不使用过滤
+pub fn write_png<W: Write>(out: &mut W, w: usize, h: usize, rgb: &[u8]) -> io::Result<()> {
+    if w == 0 || h == 0 || rgb.len() != w * h * 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "bad image size",
+        ));
+    }
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+    let mut header = Vec::new();
+    header.extend_from_slice(&(w as u32).to_be_bytes());
+    header.extend_from_slice(&(h as u32).to_be_bytes());
+    // 位深 8, RGB, deflate, 不过滤, 不隔行
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    chunk(out, b"IHDR", &header)?;
+    let mut e = ZlibEncoder::new(Vec::new(), Compression::fast());
+    for row in rgb.chunks_exact(w * 3) {
+        e.write_all(&[0])?;
+        e.write_all(row)?;
+    }
+    chunk(out, b"IDAT", &e.finish()?)?;
+    chunk(out, b"IEND", &[])
+}
+
+// 长度(4) 类型(4) 数据 CRC(4)
+fn chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc.finalize().to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+
+    #[test]
+    fn test_round_trip() {
+        // 每行末尾有填充
+        let (w, h, stride) = (2, 2, 12);
+        let bgra: Vec<u8> = (0..24).collect();
+        let msg = encode(w, h, stride, &bgra);
+        let (dw, dh, rgb) = decode(&msg, 1 << 20).unwrap();
+        assert_eq!((dw, dh), (w, h));
+        assert_eq!(rgb, [2, 1, 0, 6, 5, 4, 14, 13, 12, 18, 17, 16]);
+
+        // 超过上限, 截屏失败和损坏的数据
+        assert_eq!(decode(&msg, 11), None);
+        assert_eq!(decode(&failed(), 1 << 20), None);
+        assert_eq!(decode(&msg[..msg.len() - 1], 1 << 20), None);
+    }
+
+    #[test]
+    fn test_png() {
+        let rgb = [255, 0, 0, 0, 255, 0, 0, 0, 255, 9, 9, 9];
+        let mut png = Vec::new();
+        write_png(&mut png, 2, 2, &rgb).unwrap();
+        assert!(write_png(&mut Vec::new(), 2, 2, &rgb[1..]).is_err());
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        // 依次读出各块并检查 CRC
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + len]);
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(kind);
+            hasher.update(data);
+            assert_eq!(hasher.finalize(), crc);
+            chunks.push((kind.to_vec(), data.to_vec()));
+            rest = &rest[12 + len..];
+        }
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(k, _)| &k[..]).collect();
+        assert_eq!(kinds, [&b"IHDR"[..], b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        let mut raw = Vec::new();
+        ZlibDecoder::new(&chunks[1].1[..])
+            .read_to_end(&mut raw)
+            .unwrap();
+        assert_eq!(raw, [0, 255, 0, 0, 0, 255, 0, 0, 0, 0, 255, 9, 9, 9]);
+    }
+}
@@ -0,0 +1,399 @@
+use crate::convert;
+use crate::convert::ColorSpace;
+use crate::convert::Matrix;
+use crate::convert::PixelFormat;
+use crate::convert::Range;
+use crate::decode::Decoder;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::process::Stdio;
+
+/// 导出视频的默认帧率
+pub const DEFAULT_FPS: u32 = 30;
+/// 编码 WebM/MKV/MP4 使用的 ffmpeg, 未设置时从 PATH 中查找
+pub const FFMPEG_ENV: &str = "DIFFSCREEN_FFMPEG";
+
+/*
+Y4M 文件
+YUV4MPEG2 W宽 H高 F帧率:1 Ip A1:1 C420paldv XCOLORRANGE=LIMITED|FULL
+之后每一帧为
++----------+---+---+---+
+| FRAME\n  | Y | U | V |
++----------+---+---+---+
+平面的排列与 VIDEO_FRAME 解压后的 I420 相同, 直接写入不需要转换
+色度取每个 2x2 块左上角的像素, 所以是 420paldv(与左上角的亮度同位), 而不是位于中心的 420jpeg
+*/
+
+/// 写 Y4M 文件
+pub struct Y4mWriter<W: Write> {
+    inner: W,
+    // 每一帧 I420 的长度
+    len: usize,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    /// 写入文件头
+    pub fn new(mut inner: W, w: usize, h: usize, fps: u32, space: ColorSpace) -> io::Result<Self> {
+        let range = match space.range {
+            Range::Limited => "LIMITED",
+            Range::Full => "FULL",
+        };
+        writeln!(
+            inner,
+            "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C420paldv XCOLORRANGE={}",
+            w, h, fps, range
+        )?;
+        Ok(Y4mWriter {
+            inner,
+            len: convert::i420_len(w, h),
+        })
+    }
+
+    /// 写入完整的一帧 I420
+    pub fn write_frame(&mut self, i420: &[u8]) -> io::Result<()> {
+        if i420.len() != self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame size mismatch",
+            ));
+        }
+        self.inner.write_all(b"FRAME\n")?;
+        self.inner.write_all(i420)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+// 第一帧时打开的输出
+struct Output {
+    writer: Y4mWriter<Box<dyn Write>>,
+    // 编码 WebM/MKV/MP4 的 ffmpeg 进程
+    child: Option<Child>,
+    w: usize,
+    h: usize,
+    space: ColorSpace,
+}
+
+/**
+ * 把解码后的画面导出为固定帧率的视频
+ * 尺寸和颜色空间取第一帧的, 之后尺寸不同的画面放在左上角, 多出的裁掉, 不足的用黑色填充
+ * 两帧之间用上一帧填充; .y4m 直接写文件, .webm .mkv .mp4 把 Y4M 交给 ffmpeg 编码
+ */
+pub struct VideoExport {
+    path: PathBuf,
+    fps: u32,
+    output: Option<Output>,
+    // 第一帧的时间和已经写入的帧数
+    start: u64,
+    written: u64,
+    // 最近的一帧, 到下一帧的时间之前重复写入
+    last: Vec<u8>,
+    // 颜色空间或像素格式与输出不同时转换用
+    rgb: Vec<u8>,
+    bgra: Vec<u8>,
+    i420: Vec<u8>,
+}
+
+impl VideoExport {
+    /// 是否可以导出为这个文件
+    pub fn supports(path: &Path) -> bool {
+        encoded(path).is_some()
+    }
+
+    /// 文件在写入第一帧时创建
+    pub fn create(path: &Path, fps: u32) -> io::Result<VideoExport> {
+        if !VideoExport::supports(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unsupported video format, use .y4m .webm .mkv or .mp4",
+            ));
+        }
+        Ok(VideoExport {
+            path: path.to_path_buf(),
+            fps: fps.max(1),
+            output: None,
+            start: 0,
+            written: 0,
+            last: Vec::new(),
+            rgb: Vec::new(),
+            bgra: Vec::new(),
+            i420: Vec::new(),
+        })
+    }
+
+    /// 解码器刚还原的一帧, timestamp 为这一帧的时间, 微秒
+    pub fn frame(&mut self, decoder: &Decoder, timestamp: u64) -> io::Result<()> {
+        let (w, h) = decoder.size();
+        self.write(
+            decoder.frame(),
+            w,
+            h,
+            decoder.space(),
+            decoder.format(),
+            timestamp,
+        )
+    }
+
+    /// 写完到 end 为止的画面并关闭文件, 返回写入的帧数, 没有画面时不创建文件
+    pub fn finish(mut self, end: u64) -> io::Result<u64> {
+        let due = self.due(end).max(self.written + 1);
+        let mut output = match self.output.take() {
+            Some(output) => output,
+            None => return Ok(0),
+        };
+        while self.written < due {
+            output.writer.write_frame(&self.last)?;
+            self.written += 1;
+        }
+        output.writer.flush()?;
+        // 关闭 ffmpeg 的输入, 等待编码结束
+        drop(output.writer);
+        if let Some(mut child) = output.child {
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("ffmpeg exited with {}", status)));
+            }
+        }
+        Ok(self.written)
+    }
+
+    fn write(
+        &mut self,
+        frame: &[u8],
+        w: usize,
+        h: usize,
+        space: ColorSpace,
+        format: PixelFormat,
+        timestamp: u64,
+    ) -> io::Result<()> {
+        if self.output.is_none() {
+            self.output = Some(self.open(w, h, space)?);
+            self.start = timestamp;
+        }
+        // 到这一帧的时间之前重复上一帧
+        let due = self.due(timestamp);
+        let output = self.output.as_mut().unwrap();
+        while !self.last.is_empty() && self.written < due {
+            output.writer.write_frame(&self.last)?;
+            self.written += 1;
+        }
+
+        // 与输出相同的 I420 直接使用
+        let src = if format == PixelFormat::I420 && space == output.space {
+            frame
+        } else {
+            let rgb = match format {
+                PixelFormat::I420 => {
+                    self.rgb.resize(w * h * 3, 0);
+                    convert::i420_to_rgb(space, w, h, frame, &mut self.rgb);
+                    &self.rgb
+                }
+                PixelFormat::Rgb => frame,
+            };
+            // 缓冲区只在尺寸变化时重新分配, 原地填充
+            self.bgra.resize(w * h * 4, 255);
+            for (d, s) in self.bgra.chunks_exact_mut(4).zip(rgb.chunks_exact(3)) {
+                d[0] = s[2];
+                d[1] = s[1];
+                d[2] = s[0];
+            }
+            convert::bgra_to_i420(output.space, w, h, w * 4, &self.bgra, &mut self.i420);
+            &self.i420
+        };
+        if (w, h) == (output.w, output.h) {
+            self.last.clear();
+            self.last.extend_from_slice(src);
+        } else {
+            fit_i420(src, w, h, &mut self.last, output.w, output.h, output.space);
+        }
+        Ok(())
+    }
+
+    fn open(&self, w: usize, h: usize, space: ColorSpace) -> io::Result<Output> {
+        let (inner, child): (Box<dyn Write>, _) = if encoded(&self.path) == Some(false) {
+            (Box::new(BufWriter::new(File::create(&self.path)?)), None)
+        } else {
+            let mut child = ffmpeg(&self.path, space).spawn()?;
+            let stdin = child.stdin.take().unwrap();
+            (Box::new(BufWriter::new(stdin)), Some(child))
+        };
+        Ok(Output {
+            writer: Y4mWriter::new(inner, w, h, self.fps, space)?,
+            child,
+            w,
+            h,
+            space,
+        })
+    }
+
+    // 到 timestamp 为止应该写入的帧数
+    fn due(&self, timestamp: u64) -> u64 {
+        (timestamp.saturating_sub(self.start) as u128 * self.fps as u128 / 1_000_000) as u64
+    }
+}
+
+// 按扩展名区分, Y4M 为 false, 需要 ffmpeg 编码的为 true
+fn encoded(path: &Path) -> Option<bool> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "y4m" => Some(false),
+        "webm" | "mkv" | "mp4" => Some(true),
+        _ => None,
+    }
+}
+
+// 从标准输入读 Y4M, 编码器由 ffmpeg 按容器选择
+// libx264 等编码器的 yuv420p 要求宽高为偶数, 奇数时在右边和下边各补一个像素
+fn ffmpeg(path: &Path, space: ColorSpace) -> Command {
+    let program = std::env::var_os(FFMPEG_ENV).unwrap_or_else(|| "ffmpeg".into());
+    let matrix = match space.matrix {
+        Matrix::Bt601 => "smpte170m",
+        Matrix::Bt709 => "bt709",
+    };
+    let mut command = Command::new(program);
+    command
+        .args(["-loglevel", "error", "-y", "-f", "yuv4mpegpipe", "-i", "-"])
+        .args(["-vf", "pad=ceil(iw/2)*2:ceil(ih/2)*2"])
+        .args(["-pix_fmt", "yuv420p", "-colorspace", matrix])
+        .arg(path)
+        .stdin(Stdio::piped());
+    command
+}
+
+/// 把 sw x sh 的 I420 放到 dw x dh 的左上角, 多出的裁掉, 不足的用黑色填充
+fn fit_i420(
+    src: &[u8],
+    sw: usize,
+    sh: usize,
+    dest: &mut Vec<u8>,
+    dw: usize,
+    dh: usize,
+    space: ColorSpace,
+) {
+    let black = match space.range {
+        Range::Limited => 16,
+        Range::Full => 0,
+    };
+    dest.clear();
+    dest.resize(convert::i420_len(dw, dh), 0);
+    let (scw, sch) = convert::chroma_size(sw, sh);
+    let (dcw, dch) = convert::chroma_size(dw, dh);
+    let (sy, su) = src.split_at(sw * sh);
+    let (su, sv) = su.split_at(scw * sch);
+    let (dy, du) = dest.split_at_mut(dw * dh);
+    let (du, dv) = du.split_at_mut(dcw * dch);
+    fit_plane(sy, sw, sh, dy, dw, black);
+    fit_plane(su, scw, sch, du, dcw, 128);
+    fit_plane(sv, scw, sch, dv, dcw, 128);
+}
+
+fn fit_plane(src: &[u8], sw: usize, sh: usize, dest: &mut [u8], dw: usize, fill: u8) {
+    let n = sw.min(dw);
+    for (y, row) in dest.chunks_exact_mut(dw).enumerate() {
+        if y < sh {
+            row[..n].copy_from_slice(&src[y * sw..y * sw + n]);
+            row[n..].fill(fill);
+        } else {
+            row.fill(fill);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_y4m() {
+        let space = ColorSpace {
+            matrix: Matrix::Bt709,
+            range: Range::Full,
+        };
+        let mut writer = Y4mWriter::new(Vec::new(), 3, 1, 25, space).unwrap();
+        // 色度为 2x1
+        writer.write_frame(&[1, 2, 3, 4, 5, 6, 7]).unwrap();
+        assert!(writer.write_frame(&[1, 2, 3]).is_err());
+        let data = writer.into_inner();
+        let header = b"YUV4MPEG2 W3 H1 F25:1 Ip A1:1 C420paldv XCOLORRANGE=FULL\n";
+        assert_eq!(&data[..header.len()], header);
+        assert_eq!(
+            &data[header.len()..],
+            b"FRAME\n\x01\x02\x03\x04\x05\x06\x07"
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        assert!(VideoExport::supports(Path::new("a.WebM")));
+        assert!(!VideoExport::supports(Path::new("a.avi")));
+        assert!(VideoExport::create(Path::new("a"), 10).is_err());
+
+        let path = std::env::temp_dir().join(format!("export-{}.y4m", crate::heartbeat::now()));
+        let mut export = VideoExport::create(&path, 10).unwrap();
+        let space = ColorSpace::default();
+        let frame = |v: u8| vec![v; convert::i420_len(2, 2)];
+        let i420 = PixelFormat::I420;
+        // 第二帧之前重复两次第一帧, 同一帧的时间内只保留最后的画面
+        export.write(&frame(1), 2, 2, space, i420, 1_000).unwrap();
+        export.write(&frame(2), 2, 2, space, i420, 251_000).unwrap();
+        export.write(&frame(3), 2, 2, space, i420, 261_000).unwrap();
+        // 尺寸不同的画面放在左上角
+        export
+            .write(&frame(4)[..3], 1, 1, space, i420, 301_000)
+            .unwrap();
+        assert_eq!(export.finish(501_000).unwrap(), 5);
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let header = b"YUV4MPEG2 W2 H2 F10:1 Ip A1:1 C420paldv XCOLORRANGE=LIMITED\n";
+        assert_eq!(&data[..header.len()], header);
+        let frames: Vec<&[u8]> = data[header.len()..]
+            .chunks(6 + 6)
+            .map(|f| &f[6..])
+            .collect();
+        assert_eq!(frames.len(), 5);
+        assert_eq!(frames[0], frame(1));
+        assert_eq!(frames[1], frame(1));
+        assert_eq!(frames[2], frame(3));
+        assert_eq!(frames[3], [4, 16, 16, 16, 4, 4]);
+        assert_eq!(frames[4], frames[3]);
+    }
+
+    #[test]
+    fn test_export_odd_size() {
+        // 共享窗口时宽高经常是奇数, 色度向上取整
+        let path = std::env::temp_dir().join(format!("odd-{}.y4m", crate::heartbeat::now()));
+        let mut export = VideoExport::create(&path, 10).unwrap();
+        let len = convert::i420_len(3, 5);
+        assert_eq!(len, 3 * 5 + 2 * 2 * 3);
+        let space = ColorSpace::default();
+        export
+            .write(&vec![7; len], 3, 5, space, PixelFormat::I420, 0)
+            .unwrap();
+        assert_eq!(export.finish(100_000).unwrap(), 1);
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let header = b"YUV4MPEG2 W3 H5 F10:1 Ip A1:1 C420paldv XCOLORRANGE=LIMITED\n";
+        assert_eq!(&data[..header.len()], header);
+        assert_eq!(data.len(), header.len() + 6 + len);
+
+        // 交给 ffmpeg 编码时补齐为偶数
+        let command = ffmpeg(Path::new("a.mp4"), space);
+        let args: Vec<&std::ffi::OsStr> = command.get_args().collect();
+        let pad = args.iter().position(|a| *a == "-vf").unwrap();
+        assert_eq!(args[pad + 1], "pad=ceil(iw/2)*2:ceil(ih/2)*2");
+        assert_eq!(args.last().unwrap(), &"a.mp4");
+    }
+}
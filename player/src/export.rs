@@ -0,0 +1,64 @@
+use crate::playback::Playback;
+use communication::video::VideoExport;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::Write;
+use std::path::Path;
+
+/// 把录像的每一帧导出为 dir 下的 frame-000000.ppm, 返回导出的帧数
+pub fn export_ppm<R: Read + Seek>(playback: &mut Playback<R>, dir: &Path) -> io::Result<usize> {
+    std::fs::create_dir_all(dir)?;
+    let mut rgb = Vec::new();
+    let mut count = 0;
+    while let Some(record) = playback.next()? {
+        if !playback.apply(&record)? {
+            continue;
+        }
+        let decoder = playback.decoder();
+        let (w, h) = decoder.size();
+        rgb.resize(w * h * 3, 0);
+        decoder.to_rgb(&mut rgb);
+        let path = dir.join(format!("frame-{:06}.ppm", count));
+        let mut out = BufWriter::new(File::create(path)?);
+        write_ppm(&mut out, w, h, &rgb)?;
+        out.flush()?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// 按录制时的时间导出为固定帧率的视频, 返回写入的帧数
+pub fn export_video<R: Read + Seek>(
+    playback: &mut Playback<R>,
+    path: &Path,
+    fps: u32,
+) -> io::Result<u64> {
+    let mut export = VideoExport::create(path, fps)?;
+    while let Some(record) = playback.next()? {
+        if playback.apply(&record)? {
+            export.frame(playback.decoder(), record.timestamp)?;
+        }
+    }
+    export.finish(playback.position())
+}
+
+/// 二进制的 PPM(P6), 每个像素 RGB 三个字节
+fn write_ppm<W: Write>(out: &mut W, w: usize, h: usize, rgb: &[u8]) -> io::Result<()> {
+    write!(out, "P6\n{} {}\n255\n", w, h)?;
+    out.write_all(rgb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_ppm() {
+        let mut out = Vec::new();
+        write_ppm(&mut out, 2, 1, &[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(out, b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06");
+    }
+}
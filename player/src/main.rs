@@ -0,0 +1,85 @@
+mod export;
+mod play;
+mod playback;
+
+use communication::frame;
+use communication::video;
+use communication::video::VideoExport;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+const USAGE: &str = "usage: diffscreen-play FILE [--info] [--export DIR|VIDEO] [--fps N]";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut path = None;
+    let mut info = false;
+    let mut export = None;
+    let mut fps = video::DEFAULT_FPS;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--info" => info = true,
+            "--export" => match iter.next() {
+                Some(dir) => export = Some(PathBuf::from(dir)),
+                None => exit(USAGE),
+            },
+            "--fps" => match iter.next().and_then(|n| n.parse().ok()) {
+                Some(n) => fps = n,
+                None => exit(USAGE),
+            },
+            _ if path.is_none() => path = Some(PathBuf::from(arg)),
+            _ => exit(USAGE),
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => exit(USAGE),
+    };
+
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) => exit(&format!("Failed to open {}: {}", path.display(), e)),
+    };
+    let mut playback = match playback::Playback::open(BufReader::new(file), frame::max_frame()) {
+        Ok(playback) => playback,
+        Err(e) => exit(&format!("Failed to read {}: {}", path.display(), e)),
+    };
+
+    if info {
+        let summary = playback.summary();
+        println!(
+            "{:.1}s, {} frames, {} keyframes, {} inputs",
+            summary.end.saturating_sub(summary.start) as f64 / 1e6,
+            summary.frames,
+            playback.keyframes().len(),
+            summary.inputs
+        );
+        for (i, k) in playback.keyframes().iter().enumerate() {
+            println!(
+                "keyframe {} at {:.1}s",
+                i,
+                k.timestamp.saturating_sub(summary.start) as f64 / 1e6
+            );
+        }
+        return;
+    }
+
+    // 视频文件按扩展名选择格式, 否则导出为目录下的图片
+    let result = match export {
+        Some(out) if VideoExport::supports(&out) => export::export_video(&mut playback, &out, fps)
+            .map(|count| println!("Exported {} frames to {}", count, out.display())),
+        Some(dir) => export::export_ppm(&mut playback, &dir)
+            .map(|count| println!("Exported {} frames to {}", count, dir.display())),
+        None => play::play(&path.display().to_string(), playback),
+    };
+    if let Err(e) = result {
+        exit(&format!("{}: {}", path.display(), e));
+    }
+}
+
+fn exit(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    std::process::exit(1);
+}
@@ -0,0 +1,158 @@
+use crate::playback::Playback;
+use fltk::app;
+use fltk::draw;
+use fltk::enums;
+use fltk::enums::Event;
+use fltk::enums::Key;
+use fltk::frame::Frame;
+use fltk::image;
+use fltk::prelude::GroupExt;
+use fltk::prelude::ImageExt;
+use fltk::prelude::WidgetBase;
+use fltk::prelude::WidgetExt;
+use fltk::window::Window;
+use std::cell::RefCell;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::rc::Rc;
+use std::time::Instant;
+
+// 两次检查之间最长等待的时间, 秒
+const WAIT: f64 = 0.01;
+
+/// 窗口中的按键
+#[derive(Debug, Clone, Copy)]
+enum Control {
+    Pause,
+    Prev,
+    Next,
+    Restart,
+}
+
+// 当前显示的画面
+#[derive(Default)]
+struct Screen {
+    w: i32,
+    h: i32,
+    rgb: Vec<u8>,
+    status: String,
+}
+
+/**
+ * 按录像时的速度播放, 窗口关闭时返回
+ * 空格暂停, 左右方向键跳到上一个和下一个关键帧, Home 回到开头
+ */
+pub fn play<R: Read + Seek>(title: &str, mut playback: Playback<R>) -> io::Result<()> {
+    let _app = app::App::default();
+    let (sw, sh) = app::screen_size();
+    let (ww, wh) = ((sw / 2.0) as i32, (sh / 2.0) as i32);
+    let mut wind = Window::default().with_size(ww, wh).with_label(title);
+    let mut frame = Frame::new(0, 0, ww, wh, None);
+    wind.resizable(&frame);
+    wind.end();
+    wind.show();
+
+    let screen = Rc::new(RefCell::new(Screen::default()));
+    let draw_screen = screen.clone();
+    frame.draw(move |frame| {
+        let screen = draw_screen.borrow();
+        if let Ok(mut image) =
+            image::RgbImage::new(&screen.rgb, screen.w, screen.h, enums::ColorDepth::Rgb8)
+        {
+            image.scale(frame.width(), frame.height(), false, true);
+            image.draw(frame.x(), frame.y(), frame.width(), frame.height());
+        }
+        draw::set_color_rgb(255, 0, 0);
+        draw::draw_text(&screen.status, frame.x() + 10, frame.y() + 20);
+    });
+
+    let (tx, rx) = app::channel::<Control>();
+    wind.handle(move |_, ev| {
+        if ev != Event::KeyDown {
+            return false;
+        }
+        let key = app::event_key();
+        let control = if key == Key::from_char(' ') {
+            Control::Pause
+        } else if key == Key::Left {
+            Control::Prev
+        } else if key == Key::Right {
+            Control::Next
+        } else if key == Key::Home {
+            Control::Restart
+        } else {
+            return false;
+        };
+        tx.send(control);
+        true
+    });
+
+    let start = playback.summary().start;
+    let mut pending = playback.next()?;
+    // 录像中的时间与当前时间的对应, 暂停和跳转后重新对齐
+    let mut clock = (Instant::now(), start);
+    let mut paused = false;
+    while app::wait_for(WAIT).unwrap_or(false) {
+        let mut changed = false;
+        if let Some(control) = rx.recv() {
+            let seek = match control {
+                Control::Pause => {
+                    paused = !paused;
+                    None
+                }
+                Control::Prev => playback.prev_keyframe(),
+                Control::Next => playback.next_keyframe(),
+                Control::Restart => {
+                    playback.rewind()?;
+                    pending = playback.next()?;
+                    None
+                }
+            };
+            if let Some(index) = seek {
+                playback.seek(index)?;
+                pending = playback.next()?;
+            }
+            clock = (Instant::now(), playback.position());
+            changed = true;
+        }
+        if !paused {
+            let now = clock.1 + clock.0.elapsed().as_micros() as u64;
+            let mut drawn = false;
+            while let Some(record) = pending.as_ref().filter(|r| r.timestamp <= now) {
+                drawn |= playback.apply(record)?;
+                pending = playback.next()?;
+            }
+            if drawn {
+                let mut screen = screen.borrow_mut();
+                let decoder = playback.decoder();
+                let (w, h) = decoder.size();
+                screen.rgb.resize(w * h * 3, 0);
+                decoder.to_rgb(&mut screen.rgb);
+                (screen.w, screen.h) = (w as i32, h as i32);
+                changed = true;
+            }
+        }
+        if changed {
+            let secs = playback.position().saturating_sub(start) / 1_000_000;
+            let end = playback.summary().end.saturating_sub(start) / 1_000_000;
+            let state = if paused {
+                " (paused)"
+            } else if pending.is_none() {
+                " (end)"
+            } else {
+                ""
+            };
+            screen.borrow_mut().status = format!(
+                "{}:{:02} / {}:{:02}{}",
+                secs / 60,
+                secs % 60,
+                end / 60,
+                end % 60,
+                state
+            );
+            frame.redraw();
+        }
+    }
+    Ok(())
+}
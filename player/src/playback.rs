@@ -0,0 +1,262 @@
+use communication::decode::Decoded;
+use communication::decode::Decoder;
+use communication::frame::FrameHeader;
+use communication::mux;
+use communication::record::Record;
+use communication::record::RecordReader;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+
+/// 录像中的一个关键帧, 从这里开始可以独立解码
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keyframe {
+    // 关键帧记录的位置
+    offset: u64,
+    // 之前最近的 VIDEO_DISPLAY 记录的位置
+    display: u64,
+    pub timestamp: u64,
+}
+
+/// 扫描整个录像得到的概况
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub start: u64,
+    pub end: u64,
+    pub frames: usize,
+    pub inputs: usize,
+}
+
+/**
+ * 录像回放
+ * 打开时扫描一遍录像, 记下每个关键帧的位置, 之后顺序读取记录或跳到某个关键帧
+ * 画面用与client相同的 Decoder 还原
+ */
+pub struct Playback<R: Read + Seek> {
+    reader: RecordReader<R>,
+    decoder: Decoder,
+    keyframes: Vec<Keyframe>,
+    summary: Summary,
+    // 第一条记录的位置
+    begin: u64,
+    // 最近处理的记录的时间
+    position: u64,
+}
+
+impl<R: Read + Seek> Playback<R> {
+    /// 解压后一帧最长 max_frame
+    pub fn open(inner: R, max_frame: usize) -> io::Result<Self> {
+        let mut reader = RecordReader::new(inner)?;
+        let begin = reader.offset();
+        let mut keyframes = Vec::new();
+        let mut summary = Summary::default();
+        let mut display = None;
+        loop {
+            let offset = reader.offset();
+            let record = match reader.read()? {
+                Some(record) => record,
+                None => break,
+            };
+            if offset == begin {
+                summary.start = record.timestamp;
+            }
+            summary.end = record.timestamp;
+            match (record.channel, record.data.first()) {
+                (mux::INPUT, _) => summary.inputs += 1,
+                (mux::VIDEO, Some(&communication::VIDEO_DISPLAY)) => display = Some(offset),
+                (mux::VIDEO, Some(&communication::VIDEO_FRAME)) => {
+                    summary.frames += 1;
+                    let header = FrameHeader::decode(&record.data).map(|(h, _)| h);
+                    if let (Some(header), Some(display)) = (header, display) {
+                        if header.keyframe() {
+                            keyframes.push(Keyframe {
+                                offset,
+                                display,
+                                timestamp: record.timestamp,
+                            });
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        reader.seek(begin)?;
+        Ok(Playback {
+            reader,
+            decoder: Decoder::new(0, 0, max_frame),
+            keyframes,
+            position: summary.start,
+            summary,
+            begin,
+        })
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+
+    pub fn summary(&self) -> &Summary {
+        &self.summary
+    }
+
+    pub fn decoder(&self) -> &Decoder {
+        &self.decoder
+    }
+
+    /// 最近处理的记录的时间
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// 读取下一条记录, 录像结束时返回 None
+    pub fn next(&mut self) -> io::Result<Option<Record>> {
+        self.reader.read()
+    }
+
+    /// 处理一条记录, 还原了新的一帧时返回 true
+    pub fn apply(&mut self, record: &Record) -> io::Result<bool> {
+        self.position = record.timestamp;
+        if record.channel != mux::VIDEO {
+            return Ok(false);
+        }
+        let valid = match record.data.first() {
+            Some(&communication::VIDEO_DISPLAY) => self.decoder.display(&record.data),
+            // 录像中的差异帧不会缺失, 校验失败时等待下一个关键帧
+            Some(&communication::VIDEO_FRAME) => match self.decoder.decode(&record.data) {
+                Some(Decoded::Frame) => return Ok(true),
+                Some(_) => true,
+                None => false,
+            },
+            _ => true,
+        };
+        if valid {
+            Ok(false)
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "broken frame"))
+        }
+    }
+
+    /// 回到开头
+    pub fn rewind(&mut self) -> io::Result<()> {
+        self.reader.seek(self.begin)?;
+        self.decoder.reset(0, 0);
+        self.position = self.summary.start;
+        Ok(())
+    }
+
+    /// 跳到第 index 个关键帧, 下一条读到的记录就是这个关键帧
+    pub fn seek(&mut self, index: usize) -> io::Result<()> {
+        let keyframe = self.keyframes[index];
+        self.reader.seek(keyframe.display)?;
+        if let Some(record) = self.reader.read()? {
+            self.apply(&record)?;
+        }
+        self.decoder.seek();
+        self.reader.seek(keyframe.offset)?;
+        self.position = keyframe.timestamp;
+        Ok(())
+    }
+
+    /// 当前位置之前的关键帧
+    pub fn prev_keyframe(&self) -> Option<usize> {
+        self.keyframes
+            .iter()
+            .rposition(|k| k.timestamp < self.position)
+    }
+
+    /// 当前位置之后的关键帧
+    pub fn next_keyframe(&self) -> Option<usize> {
+        self.keyframes
+            .iter()
+            .position(|k| k.timestamp > self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use communication::frame;
+    use communication::frame::FrameKind;
+    use communication::record::RecordWriter;
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use std::io::Cursor;
+    use std::io::Write;
+
+    /// 单个条带的 RGB 帧
+    fn frame_msg(kind: FrameKind, seq: u32, data: &[u8], frame: &[u8]) -> Vec<u8> {
+        let header = FrameHeader {
+            kind,
+            seq,
+            timestamp: 0,
+            length: data.len() as u32,
+            checksum: frame::checksum(frame),
+            stripes: 1,
+        };
+        let mut e = DeflateEncoder::new(Vec::new(), Compression::default());
+        e.write_all(data).unwrap();
+        let mut msg = Vec::new();
+        header.encode(&mut msg);
+        frame::encode_stripes(&[e.finish().unwrap()], &mut msg);
+        msg
+    }
+
+    #[test]
+    fn test_playback() {
+        // 2x1 的 RGB 画面: 关键帧, 差异帧, 输入, 关键帧
+        let display = [communication::VIDEO_DISPLAY, 0, 2, 0, 1, 0, 1];
+        let a = [1u8, 2, 3, 4, 5, 6];
+        let b = [6u8, 5, 4, 3, 2, 1];
+        let xor: Vec<u8> = a.iter().zip(&b).map(|(x, y)| x ^ y).collect();
+        let mut writer = RecordWriter::new(Vec::new()).unwrap();
+        writer.write(mux::VIDEO, 100, &display).unwrap();
+        writer
+            .write(mux::VIDEO, 100, &frame_msg(FrameKind::Key, 0, &a, &a))
+            .unwrap();
+        writer
+            .write(mux::VIDEO, 200, &frame_msg(FrameKind::Delta, 1, &xor, &b))
+            .unwrap();
+        writer.write(mux::INPUT, 250, &[0; 6]).unwrap();
+        writer
+            .write(mux::VIDEO, 300, &frame_msg(FrameKind::Key, 2, &a, &a))
+            .unwrap();
+        let data = writer.into_inner();
+
+        let mut playback = Playback::open(Cursor::new(data), frame::DEFAULT_MAX_FRAME).unwrap();
+        let summary = Summary {
+            start: 100,
+            end: 300,
+            frames: 3,
+            inputs: 1,
+        };
+        assert_eq!(playback.summary(), &summary);
+        let times: Vec<u64> = playback.keyframes().iter().map(|k| k.timestamp).collect();
+        assert_eq!(times, [100, 300]);
+
+        // 顺序播放
+        let mut frames = Vec::new();
+        while let Some(record) = playback.next().unwrap() {
+            if playback.apply(&record).unwrap() {
+                frames.push(playback.decoder().frame().to_vec());
+            }
+        }
+        assert_eq!(frames, [a.to_vec(), b.to_vec(), a.to_vec()]);
+        assert_eq!(playback.position(), 300);
+        assert_eq!(playback.prev_keyframe(), Some(0));
+        assert_eq!(playback.next_keyframe(), None);
+
+        // 跳到第一个关键帧后继续播放
+        playback.seek(0).unwrap();
+        assert_eq!(playback.next_keyframe(), Some(1));
+        let record = playback.next().unwrap().unwrap();
+        assert!(playback.apply(&record).unwrap());
+        let record = playback.next().unwrap().unwrap();
+        assert!(playback.apply(&record).unwrap());
+        assert_eq!(playback.decoder().frame(), &b[..]);
+
+        // 回到开头
+        playback.rewind().unwrap();
+        let record = playback.next().unwrap().unwrap();
+        assert_eq!(record.data, display);
+    }
+}
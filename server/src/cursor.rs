@@ -0,0 +1,287 @@
+use communication::cursor::CursorPos;
+use communication::cursor::CursorShape;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+/**
+ * 鼠标光标
+ * scrap 截取的画面不包含光标, 单独获取位置和形状发送给client
+ */
+pub struct Cursor {
+    inner: imp::Cursor,
+}
+
+impl Cursor {
+    pub fn new() -> Cursor {
+        Cursor {
+            inner: imp::Cursor::new(),
+        }
+    }
+
+    /// 获取光标位置, 形状第一次出现时一并返回
+    pub fn poll(&mut self) -> Option<(CursorPos, Option<CursorShape>)> {
+        self.inner.poll()
+    }
+}
+
+/// 根据像素和热点计算形状 id, 相同的形状得到相同的 id
+fn shape_id(w: u16, h: u16, hotx: u16, hoty: u16, rgba: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u16(w);
+    hasher.write_u16(h);
+    hasher.write_u16(hotx);
+    hasher.write_u16(hoty);
+    hasher.write(rgba);
+    // 0 表示光标隐藏
+    hasher.finish().max(1)
+}
+
+fn to_pos(x: i32, y: i32, id: u64) -> CursorPos {
+    CursorPos {
+        x: x.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+        y: y.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+        id,
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use super::shape_id;
+    use super::to_pos;
+    use communication::cursor::CursorPos;
+    use communication::cursor::CursorShape;
+    use std::collections::HashMap;
+    use std::mem::size_of;
+    use std::mem::zeroed;
+    use std::ptr::null_mut;
+    use winapi::shared::windef::HBITMAP;
+    use winapi::shared::windef::HDC;
+    use winapi::um::wingdi::DeleteObject;
+    use winapi::um::wingdi::GetDIBits;
+    use winapi::um::wingdi::GetObjectW;
+    use winapi::um::wingdi::BITMAP;
+    use winapi::um::wingdi::BITMAPINFO;
+    use winapi::um::wingdi::BITMAPINFOHEADER;
+    use winapi::um::wingdi::BI_RGB;
+    use winapi::um::wingdi::DIB_RGB_COLORS;
+    use winapi::um::winuser::GetCursorInfo;
+    use winapi::um::winuser::GetDC;
+    use winapi::um::winuser::GetIconInfo;
+    use winapi::um::winuser::ReleaseDC;
+    use winapi::um::winuser::CURSORINFO;
+    use winapi::um::winuser::CURSOR_SHOWING;
+    use winapi::um::winuser::ICONINFO;
+
+    pub struct Cursor {
+        // 光标句柄 -> 形状 id
+        handles: HashMap<usize, u64>,
+    }
+
+    impl Cursor {
+        pub fn new() -> Cursor {
+            Cursor {
+                handles: HashMap::new(),
+            }
+        }
+
+        pub fn poll(&mut self) -> Option<(CursorPos, Option<CursorShape>)> {
+            let mut info: CURSORINFO = unsafe { zeroed() };
+            info.cbSize = size_of::<CURSORINFO>() as u32;
+            if unsafe { GetCursorInfo(&mut info) } == 0 {
+                return None;
+            }
+            let (x, y) = (info.ptScreenPos.x, info.ptScreenPos.y);
+            if info.flags & CURSOR_SHOWING == 0 || info.hCursor.is_null() {
+                return Some((to_pos(x, y, 0), None));
+            }
+            let handle = info.hCursor as usize;
+            if let Some(id) = self.handles.get(&handle) {
+                return Some((to_pos(x, y, *id), None));
+            }
+            let shape = unsafe { load_shape(info.hCursor) }?;
+            self.handles.insert(handle, shape.id);
+            Some((to_pos(x, y, shape.id), Some(shape)))
+        }
+    }
+
+    unsafe fn load_shape(cursor: winapi::shared::windef::HCURSOR) -> Option<CursorShape> {
+        let mut info: ICONINFO = zeroed();
+        if GetIconInfo(cursor, &mut info) == 0 {
+            return None;
+        }
+        let dc = GetDC(null_mut());
+        let shape = read_shape(dc, &info);
+        ReleaseDC(null_mut(), dc);
+        if !info.hbmMask.is_null() {
+            DeleteObject(info.hbmMask as _);
+        }
+        if !info.hbmColor.is_null() {
+            DeleteObject(info.hbmColor as _);
+        }
+        shape
+    }
+
+    unsafe fn read_shape(dc: HDC, info: &ICONINFO) -> Option<CursorShape> {
+        let (w, h, rgba) = if !info.hbmColor.is_null() {
+            // 彩色光标
+            let (w, h, mut bgra) = dib_bits(dc, info.hbmColor)?;
+            let mask = dib_bits(dc, info.hbmMask).map(|m| m.2);
+            let has_alpha = bgra.chunks(4).any(|p| p[3] != 0);
+            for (i, p) in bgra.chunks_mut(4).enumerate() {
+                p.swap(0, 2);
+                if !has_alpha {
+                    // 没有 alpha 通道时由 AND 掩码决定透明度
+                    p[3] = match &mask {
+                        Some(mask) if mask[i * 4] != 0 => 0,
+                        _ => 255,
+                    };
+                }
+            }
+            (w, h, bgra)
+        } else {
+            // 单色光标, 掩码高度为两倍: 上半为 AND 掩码, 下半为 XOR 掩码
+            let (w, h2, mask) = dib_bits(dc, info.hbmMask)?;
+            let h = h2 / 2;
+            let mut rgba = vec![0u8; w * h * 4];
+            for i in 0..w * h {
+                let and = mask[i * 4] != 0;
+                let xor = mask[(i + w * h) * 4] != 0;
+                let (c, a) = match (and, xor) {
+                    (false, false) => (0, 255),
+                    (false, true) => (255, 255),
+                    (true, false) => (0, 0),
+                    // 反色无法表示, 用黑色近似
+                    (true, true) => (0, 255),
+                };
+                rgba[i * 4..i * 4 + 4].copy_from_slice(&[c, c, c, a]);
+            }
+            (w, h, rgba)
+        };
+        let (w, h) = (w as u16, h as u16);
+        let (hotx, hoty) = (info.xHotspot as u16, info.yHotspot as u16);
+        Some(CursorShape {
+            id: shape_id(w, h, hotx, hoty, &rgba),
+            width: w,
+            height: h,
+            hotx,
+            hoty,
+            rgba,
+        })
+    }
+
+    /// 读取位图为自上而下的 32 位像素
+    unsafe fn dib_bits(dc: HDC, bmp: HBITMAP) -> Option<(usize, usize, Vec<u8>)> {
+        if bmp.is_null() {
+            return None;
+        }
+        let mut bm: BITMAP = zeroed();
+        if GetObjectW(
+            bmp as _,
+            size_of::<BITMAP>() as i32,
+            &mut bm as *mut BITMAP as _,
+        ) == 0
+        {
+            return None;
+        }
+        let (w, h) = (bm.bmWidth as usize, bm.bmHeight as usize);
+        let mut bi: BITMAPINFO = zeroed();
+        bi.bmiHeader.biSize = size_of::<BITMAPINFOHEADER>() as u32;
+        bi.bmiHeader.biWidth = w as i32;
+        bi.bmiHeader.biHeight = -(h as i32);
+        bi.bmiHeader.biPlanes = 1;
+        bi.bmiHeader.biBitCount = 32;
+        bi.bmiHeader.biCompression = BI_RGB;
+        let mut buf = vec![0u8; w * h * 4];
+        if GetDIBits(
+            dc,
+            bmp,
+            0,
+            h as u32,
+            buf.as_mut_ptr() as _,
+            &mut bi,
+            DIB_RGB_COLORS,
+        ) == 0
+        {
+            return None;
+        }
+        Some((w, h, buf))
+    }
+}
+
+#[cfg(not(windows))]
+mod imp {
+    use super::shape_id;
+    use super::to_pos;
+    use communication::cursor::CursorPos;
+    use communication::cursor::CursorShape;
+    use enigo::Enigo;
+    use enigo::MouseControllable;
+
+    // 无法获取系统光标形状, 使用一个默认箭头
+    const ARROW: [&str; 16] = [
+        "X           ",
+        "XX          ",
+        "X.X         ",
+        "X..X        ",
+        "X...X       ",
+        "X....X      ",
+        "X.....X     ",
+        "X......X    ",
+        "X.......X   ",
+        "X........X  ",
+        "X.....XXXXX ",
+        "X..X..X     ",
+        "X.X X..X    ",
+        "XX  X..X    ",
+        "X    X..X   ",
+        "     XXXX   ",
+    ];
+
+    pub struct Cursor {
+        enigo: Enigo,
+        shape: CursorShape,
+        sent: bool,
+    }
+
+    impl Cursor {
+        pub fn new() -> Cursor {
+            Cursor {
+                enigo: Enigo::new(),
+                shape: arrow(),
+                sent: false,
+            }
+        }
+
+        pub fn poll(&mut self) -> Option<(CursorPos, Option<CursorShape>)> {
+            let (x, y) = self.enigo.mouse_location();
+            let pos = to_pos(x, y, self.shape.id);
+            if self.sent {
+                return Some((pos, None));
+            }
+            self.sent = true;
+            Some((pos, Some(self.shape.clone())))
+        }
+    }
+
+    fn arrow() -> CursorShape {
+        let (w, h) = (ARROW[0].len() as u16, ARROW.len() as u16);
+        let mut rgba = Vec::with_capacity(w as usize * h as usize * 4);
+        for row in ARROW.iter() {
+            for c in row.bytes() {
+                rgba.extend_from_slice(match c {
+                    b'X' => &[0, 0, 0, 255],
+                    b'.' => &[255, 255, 255, 255],
+                    _ => &[0, 0, 0, 0],
+                });
+            }
+        }
+        CursorShape {
+            id: shape_id(w, h, 0, 0, &rgba),
+            width: w,
+            height: h,
+            hotx: 0,
+            hoty: 0,
+            rgba,
+        }
+    }
+}
@@ -0,0 +1,499 @@
+use communication::file;
+use communication::file::DirEntry;
+use communication::file::FileMsg;
+use std::collections::HashMap;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+// 一次列出的目录项上限, 避免消息过大
+const MAX_DIR_ENTRIES: usize = 10000;
+
+struct Upload {
+    file: File,
+    part: PathBuf,
+    dest: PathBuf,
+    size: u64,
+    offset: u64,
+    sha256: [u8; 32],
+}
+
+struct Download {
+    file: File,
+    size: u64,
+    // 未收到 Resume 前为 None
+    offset: Option<u64>,
+}
+
+// 在其他线程计算摘要的下载, 完成后才回复 DownloadInfo
+struct Hashing {
+    cancel: Arc<AtomicBool>,
+    handle: JoinHandle<io::Result<(File, u64, [u8; 32])>>,
+}
+
+// 取消后读取返回错误, 让摘要计算尽快结束
+struct Cancellable<R> {
+    inner: R,
+    cancel: Arc<AtomicBool>,
+}
+
+impl<R: Read> Read for Cancellable<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cancel.load(Ordering::Relaxed) {
+            return Err(io::Error::other("cancelled"));
+        }
+        self.inner.read(buf)
+    }
+}
+
+/**
+ * 文件传输
+ * 只允许访问启动时指定的根目录, 上传先写入 .part 文件, 校验 SHA-256 后再改名
+ * .part 文件在连接断开后保留, 下次上传同一文件时从已有的长度继续
+ * 下载文件的摘要在其他线程计算, 大文件不会阻塞其他传输和目录浏览
+ */
+pub struct FileService {
+    roots: Vec<PathBuf>,
+    uploads: HashMap<u32, Upload>,
+    downloads: HashMap<u32, Download>,
+    hashing: HashMap<u32, Hashing>,
+    // 轮流发送各个下载的数据块
+    turn: usize,
+}
+
+impl FileService {
+    pub fn new(roots: Vec<PathBuf>) -> FileService {
+        FileService {
+            roots,
+            uploads: HashMap::new(),
+            downloads: HashMap::new(),
+            hashing: HashMap::new(),
+            turn: 0,
+        }
+    }
+
+    /// 是否有需要发送数据的下载
+    pub fn sending(&self) -> bool {
+        self.downloads.values().any(|d| d.offset.is_some())
+    }
+
+    /// 是否有正在计算摘要的下载
+    pub fn hashing(&self) -> bool {
+        !self.hashing.is_empty()
+    }
+
+    /// 摘要计算完成的下载, 返回需要回复的 DownloadInfo 或错误
+    pub fn hashed(&mut self) -> Vec<FileMsg> {
+        let done: Vec<u32> = self
+            .hashing
+            .iter()
+            .filter(|(_, h)| h.handle.is_finished())
+            .map(|(id, _)| *id)
+            .collect();
+        let mut replies = Vec::new();
+        for id in done {
+            let hashing = self.hashing.remove(&id).unwrap();
+            let reply = match hashing.handle.join() {
+                Ok(Ok((file, size, sha256))) => {
+                    self.downloads.insert(
+                        id,
+                        Download {
+                            file,
+                            size,
+                            offset: None,
+                        },
+                    );
+                    FileMsg::DownloadInfo { id, size, sha256 }
+                }
+                Ok(Err(e)) => error(id, &e.to_string()),
+                Err(_) => error(id, "hash failed"),
+            };
+            replies.push(reply);
+        }
+        replies
+    }
+
+    /// 处理client发来的消息, 返回需要回复的消息
+    pub fn handle(&mut self, msg: FileMsg) -> Option<FileMsg> {
+        match msg {
+            FileMsg::ListRoots => Some(FileMsg::Roots {
+                names: self.roots.iter().map(|r| r.display().to_string()).collect(),
+            }),
+            FileMsg::ListDir { path } => Some(self.list_dir(path)),
+            FileMsg::Upload {
+                id,
+                dir,
+                name,
+                size,
+                sha256,
+            } => Some(self.start_upload(id, &dir, &name, size, sha256)),
+            FileMsg::Chunk { id, offset, data } => self.write_chunk(id, offset, &data),
+            FileMsg::Download { id, path } => self.start_download(id, &path),
+            FileMsg::Resume { id, offset } => {
+                let download = self.downloads.get_mut(&id)?;
+                if offset > download.size {
+                    self.downloads.remove(&id);
+                    return Some(error(id, "invalid offset"));
+                }
+                if download.file.seek(SeekFrom::Start(offset)).is_err() {
+                    self.downloads.remove(&id);
+                    return Some(error(id, "seek failed"));
+                }
+                download.offset = Some(offset);
+                None
+            }
+            FileMsg::Cancel { id } => {
+                // 保留 .part 文件用于续传
+                self.uploads.remove(&id);
+                self.downloads.remove(&id);
+                if let Some(hashing) = self.hashing.remove(&id) {
+                    hashing.cancel.store(true, Ordering::Relaxed);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// 读取下一个下载数据块
+    pub fn next_chunk(&mut self) -> Option<FileMsg> {
+        let mut ids: Vec<u32> = self
+            .downloads
+            .iter()
+            .filter(|(_, d)| d.offset.is_some())
+            .map(|(id, _)| *id)
+            .collect();
+        if ids.is_empty() {
+            return None;
+        }
+        ids.sort_unstable();
+        self.turn = self.turn.wrapping_add(1);
+        let id = ids[self.turn % ids.len()];
+        let download = self.downloads.get_mut(&id)?;
+        let offset = download.offset?;
+        if offset >= download.size {
+            self.downloads.remove(&id);
+            return Some(FileMsg::Done { id });
+        }
+        let len = (download.size - offset).min(file::CHUNK_SIZE as u64) as usize;
+        let mut data = vec![0u8; len];
+        if download.file.read_exact(&mut data).is_err() {
+            self.downloads.remove(&id);
+            return Some(error(id, "read failed"));
+        }
+        download.offset = Some(offset + len as u64);
+        Some(FileMsg::Chunk { id, offset, data })
+    }
+
+    fn list_dir(&self, path: String) -> FileMsg {
+        let dir = match file::resolve(&self.roots, &path) {
+            Some(dir) => dir,
+            None => return error(0, "path not permitted"),
+        };
+        let read = match std::fs::read_dir(&dir) {
+            Ok(read) => read,
+            Err(e) => return error(0, &e.to_string()),
+        };
+        let mut entries: Vec<DirEntry> = read
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let meta = e.metadata().ok()?;
+                Some(DirEntry {
+                    name: e.file_name().into_string().ok()?,
+                    is_dir: meta.is_dir(),
+                    size: meta.len(),
+                })
+            })
+            .take(MAX_DIR_ENTRIES)
+            .collect();
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then(a.name.cmp(&b.name)));
+        FileMsg::DirList { path, entries }
+    }
+
+    fn start_upload(
+        &mut self,
+        id: u32,
+        dir: &str,
+        name: &str,
+        size: u64,
+        sha256: [u8; 32],
+    ) -> FileMsg {
+        let dir = match file::resolve(&self.roots, dir) {
+            Some(dir) if file::valid_name(name) => dir,
+            _ => return error(id, "path not permitted"),
+        };
+        let dest = dir.join(name);
+        let part = file::part_path(&dest, &sha256);
+        let file = match OpenOptions::new().create(true).append(true).open(&part) {
+            Ok(file) => file,
+            Err(e) => return error(id, &e.to_string()),
+        };
+        let mut offset = file.metadata().map(|m| m.len()).unwrap_or(0);
+        if offset > size {
+            // 与预期不符, 重新开始
+            if file.set_len(0).is_err() {
+                return error(id, "truncate failed");
+            }
+            offset = 0;
+        }
+        self.uploads.insert(
+            id,
+            Upload {
+                file,
+                part,
+                dest,
+                size,
+                offset,
+                sha256,
+            },
+        );
+        if offset == size {
+            // 上次已经传完, 只差校验
+            return self.finish_upload(id).unwrap_or(FileMsg::Done { id });
+        }
+        FileMsg::UploadReady { id, offset }
+    }
+
+    fn write_chunk(&mut self, id: u32, offset: u64, data: &[u8]) -> Option<FileMsg> {
+        let upload = self.uploads.get_mut(&id)?;
+        if offset != upload.offset || offset + data.len() as u64 > upload.size {
+            self.uploads.remove(&id);
+            return Some(error(id, "unexpected chunk"));
+        }
+        if upload.file.write_all(data).is_err() {
+            self.uploads.remove(&id);
+            return Some(error(id, "write failed"));
+        }
+        upload.offset += data.len() as u64;
+        if upload.offset < upload.size {
+            return None;
+        }
+        self.finish_upload(id)
+    }
+
+    /// 校验并改名
+    fn finish_upload(&mut self, id: u32) -> Option<FileMsg> {
+        let upload = self.uploads.remove(&id)?;
+        drop(upload.file);
+        match file::sha256_file(&upload.part) {
+            Ok(sha256) if sha256 == upload.sha256 => {}
+            _ => {
+                // 内容损坏, 不能再用于续传
+                let _ = std::fs::remove_file(&upload.part);
+                return Some(error(id, "checksum mismatch"));
+            }
+        }
+        if let Err(e) = std::fs::rename(&upload.part, &upload.dest) {
+            return Some(error(id, &e.to_string()));
+        }
+        Some(FileMsg::Done { id })
+    }
+
+    /// 打开文件后在其他线程计算摘要, 摘要与之后发送的内容来自同一个文件句柄
+    fn start_download(&mut self, id: u32, path: &str) -> Option<FileMsg> {
+        let path = match file::resolve(&self.roots, path) {
+            Some(path) if path.is_file() => path,
+            _ => return Some(error(id, "path not permitted")),
+        };
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) => return Some(error(id, &e.to_string())),
+        };
+        let size = match file.metadata() {
+            Ok(meta) => meta.len(),
+            Err(e) => return Some(error(id, &e.to_string())),
+        };
+        let cancel = Arc::new(AtomicBool::new(false));
+        let hash_cancel = cancel.clone();
+        let handle = std::thread::spawn(move || {
+            // 只计算打开时的长度, 与 DownloadInfo 中的大小一致
+            let sha256 = file::sha256_reader(Cancellable {
+                inner: (&mut file).take(size),
+                cancel: hash_cancel,
+            })?;
+            Ok((file, size, sha256))
+        });
+        self.hashing.insert(id, Hashing { cancel, handle });
+        None
+    }
+}
+
+impl Drop for FileService {
+    fn drop(&mut self) {
+        for hashing in self.hashing.values() {
+            hashing.cancel.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+fn error(id: u32, message: &str) -> FileMsg {
+    FileMsg::Error {
+        id,
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("diffscreen-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn upload(id: u32, data: &[u8]) -> FileMsg {
+        FileMsg::Upload {
+            id,
+            dir: "0".to_string(),
+            name: "a.bin".to_string(),
+            size: data.len() as u64,
+            sha256: file::sha256(data),
+        }
+    }
+
+    #[test]
+    fn test_upload_resume() {
+        let root = temp_root("upload");
+        let mut service = FileService::new(vec![root.clone()]);
+        let data: Vec<u8> = (0..200000u32).map(|i| i as u8).collect();
+
+        assert_eq!(
+            service.handle(upload(1, &data)),
+            Some(FileMsg::UploadReady { id: 1, offset: 0 })
+        );
+        let chunk = FileMsg::Chunk {
+            id: 1,
+            offset: 0,
+            data: data[..1000].to_vec(),
+        };
+        assert_eq!(service.handle(chunk), None);
+        // 断开后重新上传, 从已有的长度继续
+        assert_eq!(service.handle(FileMsg::Cancel { id: 1 }), None);
+        assert_eq!(
+            service.handle(upload(2, &data)),
+            Some(FileMsg::UploadReady {
+                id: 2,
+                offset: 1000
+            })
+        );
+        let chunk = FileMsg::Chunk {
+            id: 2,
+            offset: 1000,
+            data: data[1000..].to_vec(),
+        };
+        assert_eq!(service.handle(chunk), Some(FileMsg::Done { id: 2 }));
+        assert_eq!(std::fs::read(root.join("a.bin")).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_upload_checksum() {
+        let root = temp_root("checksum");
+        let mut service = FileService::new(vec![root.clone()]);
+        service.handle(upload(1, b"hello"));
+        let chunk = FileMsg::Chunk {
+            id: 1,
+            offset: 0,
+            data: b"jello".to_vec(),
+        };
+        assert!(matches!(
+            service.handle(chunk),
+            Some(FileMsg::Error { id: 1, .. })
+        ));
+        assert!(!root.join("a.bin").exists());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_download() {
+        let root = temp_root("download");
+        let data: Vec<u8> = (0..100000u32).map(|i| (i * 7) as u8).collect();
+        std::fs::write(root.join("b.log"), &data).unwrap();
+        let mut service = FileService::new(vec![root.clone()]);
+
+        let msg = FileMsg::Download {
+            id: 3,
+            path: "0/b.log".to_string(),
+        };
+        assert_eq!(service.handle(msg), None);
+        // 摘要在其他线程计算
+        let mut replies = service.hashed();
+        while service.hashing() {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            replies.extend(service.hashed());
+        }
+        assert_eq!(
+            replies,
+            [FileMsg::DownloadInfo {
+                id: 3,
+                size: data.len() as u64,
+                sha256: file::sha256(&data),
+            }]
+        );
+        assert!(!service.sending());
+        service.handle(FileMsg::Resume { id: 3, offset: 10 });
+        let mut received = data[..10].to_vec();
+        loop {
+            match service.next_chunk() {
+                Some(FileMsg::Chunk { offset, data, .. }) => {
+                    assert_eq!(offset as usize, received.len());
+                    received.extend_from_slice(&data);
+                }
+                Some(FileMsg::Done { id: 3 }) => break,
+                other => panic!("{:?}", other),
+            }
+        }
+        assert_eq!(received, data);
+        assert!(!service.sending());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_permitted_roots() {
+        let root = temp_root("roots");
+        std::fs::create_dir(root.join("sub")).unwrap();
+        std::fs::write(root.join("c.txt"), b"c").unwrap();
+        let mut service = FileService::new(vec![root.clone()]);
+
+        let msg = FileMsg::ListDir {
+            path: "0".to_string(),
+        };
+        match service.handle(msg) {
+            Some(FileMsg::DirList { entries, .. }) => {
+                let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+                assert_eq!(names, vec!["sub", "c.txt"]);
+            }
+            other => panic!("{:?}", other),
+        }
+        for path in ["0/../etc", "1", "/etc"] {
+            let msg = FileMsg::ListDir {
+                path: path.to_string(),
+            };
+            assert!(matches!(service.handle(msg), Some(FileMsg::Error { .. })));
+        }
+        let msg = FileMsg::Upload {
+            id: 4,
+            dir: "0".to_string(),
+            name: "../x".to_string(),
+            size: 0,
+            sha256: [0; 32],
+        };
+        assert!(matches!(
+            service.handle(msg),
+            Some(FileMsg::Error { id: 4, .. })
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
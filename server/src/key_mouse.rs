@@ -1,5 +1,3 @@
-
-
 pub fn mouse_to_engin(key: u8) -> Option<enigo::MouseButton> {
     match key {
         233 => Some(enigo::MouseButton::Left),
@@ -58,7 +56,9 @@ pub fn key_to_enigo(key: u8) -> Option<enigo::Key> {
         227 => Some(enigo::Key::Control), // ControlL
         233 => Some(enigo::Key::Alt),     // AltL
         32 => Some(enigo::Key::Space),
-        234 => Some(enigo::Key::Alt), // AltR
+        234 => Some(enigo::Key::Alt),  // AltR
+        231 => Some(enigo::Key::Meta), // MetaL
+        232 => Some(enigo::Key::Meta), // MetaR
         // 103 => Some(enigo::Key::Menu),
         228 => Some(enigo::Key::Control), // ControlR
         81 => Some(enigo::Key::LeftArrow),
@@ -71,4 +71,32 @@ pub fn key_to_enigo(key: u8) -> Option<enigo::Key> {
         a if a >= 97 && a <= 122 => Some(enigo::Key::Layout((a - 97 + ('a' as u8)) as char)),
         _ => None,
     }
-}
\ No newline at end of file
+}
+
+/// 是否为 Ctrl+Alt+Del
+#[cfg(windows)]
+pub fn is_sas(keys: &[u8]) -> bool {
+    let ctrl = keys.iter().any(|k| *k == 227 || *k == 228);
+    let alt = keys.iter().any(|k| *k == 233 || *k == 234);
+    keys.len() == 3 && ctrl && alt && keys.contains(&255)
+}
+
+/// 调用 sas.dll 中的 SendSAS
+/// 只有 server 以服务方式运行, 或者组策略允许软件生成 SAS 时才会生效
+#[cfg(windows)]
+pub fn send_sas() -> bool {
+    use winapi::um::libloaderapi::{GetProcAddress, LoadLibraryA};
+    unsafe {
+        let lib = LoadLibraryA(b"sas.dll\0".as_ptr() as _);
+        if lib.is_null() {
+            return false;
+        }
+        let proc = GetProcAddress(lib, b"SendSAS\0".as_ptr() as _);
+        if proc.is_null() {
+            return false;
+        }
+        let send_sas: extern "system" fn(i32) = std::mem::transmute(proc);
+        send_sas(0);
+    }
+    true
+}
@@ -1,6 +1,13 @@
+mod cursor;
+mod file;
 mod key_mouse;
+mod pacing;
+mod pipeline;
+mod region;
+mod scale;
 mod screen;
 mod server;
+mod session;
 fn main() {
     let args: Vec<String> = std::env::args().collect();
 
@@ -16,7 +23,15 @@ fn main() {
         port = args[2].parse::<u16>().unwrap();
     }
 
+    // 允许文件传输访问的根目录, 默认为当前目录下的 diffscreen_files
+    let mut roots: Vec<std::path::PathBuf> = args.iter().skip(3).map(|a| a.into()).collect();
+    if roots.is_empty() {
+        let root = std::path::PathBuf::from("diffscreen_files");
+        let _ = std::fs::create_dir_all(&root);
+        roots.push(root);
+    }
+
     // run forever
-    let server = server::Server::new(port, pwd);
+    let server = server::Server::new(port, pwd, roots);
     server.run();
 }
@@ -0,0 +1,128 @@
+use std::time::Duration;
+use std::time::Instant;
+
+/// 最大帧率可以通过环境变量修改
+pub const MAX_FPS_ENV: &str = "DIFFSCREEN_MAX_FPS";
+pub const DEFAULT_MAX_FPS: u32 = 30;
+// 允许设置的最大帧率
+const FPS_LIMIT: u32 = 120;
+/// 画面长时间不变时, 截屏间隔最多增加到这个值
+pub const IDLE_MAX: Duration = Duration::from_millis(250);
+/// 关键帧间隔(秒)可以通过环境变量修改, 0 为不定期发送
+pub const KEYFRAME_ENV: &str = "DIFFSCREEN_KEYFRAME_INTERVAL";
+pub const DEFAULT_KEYFRAME_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 读取最大帧率设置, 无效时使用默认值
+pub fn max_fps() -> u32 {
+    parse_fps(std::env::var(MAX_FPS_ENV).ok().as_deref())
+}
+
+fn parse_fps(value: Option<&str>) -> u32 {
+    match value.and_then(|v| v.trim().parse::<u32>().ok()) {
+        Some(fps) if (1..=FPS_LIMIT).contains(&fps) => fps,
+        _ => DEFAULT_MAX_FPS,
+    }
+}
+
+/// 读取关键帧间隔, None 为只在需要时发送关键帧
+pub fn keyframe_interval() -> Option<Duration> {
+    parse_keyframe_interval(std::env::var(KEYFRAME_ENV).ok().as_deref())
+}
+
+fn parse_keyframe_interval(value: Option<&str>) -> Option<Duration> {
+    match value.and_then(|v| v.trim().parse::<u64>().ok()) {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => Some(DEFAULT_KEYFRAME_INTERVAL),
+    }
+}
+
+/**
+ * 截屏节奏
+ * 两次截屏至少间隔 1/max_fps, 画面不变时间隔逐次翻倍到 IDLE_MAX, 变化后恢复
+ */
+pub struct Pacer {
+    interval: Duration,
+    idle: Duration,
+    last: Instant,
+}
+
+impl Pacer {
+    pub fn new(max_fps: u32) -> Self {
+        let interval = Duration::from_secs(1) / max_fps.max(1);
+        Pacer {
+            interval,
+            idle: interval,
+            last: Instant::now(),
+        }
+    }
+
+    /// 距离上次截屏应该等待的时间
+    pub fn delay(&self) -> Duration {
+        self.idle
+    }
+
+    /// 等到下一次截屏的时间
+    pub fn wait(&mut self) {
+        let (elapsed, delay) = (self.last.elapsed(), self.delay());
+        if elapsed < delay {
+            std::thread::sleep(delay - elapsed);
+        }
+        self.last = Instant::now();
+    }
+
+    /// 画面没有变化, 放慢截屏
+    pub fn unchanged(&mut self) {
+        self.idle = (self.idle * 2).min(IDLE_MAX.max(self.interval));
+    }
+
+    /// 画面变化或client有输入, 恢复最大帧率
+    pub fn changed(&mut self) {
+        self.idle = self.interval;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fps() {
+        assert_eq!(parse_fps(None), DEFAULT_MAX_FPS);
+        assert_eq!(parse_fps(Some(" 60 ")), 60);
+        assert_eq!(parse_fps(Some("0")), DEFAULT_MAX_FPS);
+        assert_eq!(parse_fps(Some("1000")), DEFAULT_MAX_FPS);
+        assert_eq!(parse_fps(Some("abc")), DEFAULT_MAX_FPS);
+    }
+
+    #[test]
+    fn test_keyframe_interval() {
+        let default = Some(DEFAULT_KEYFRAME_INTERVAL);
+        assert_eq!(parse_keyframe_interval(None), default);
+        assert_eq!(
+            parse_keyframe_interval(Some(" 3 ")),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(parse_keyframe_interval(Some("0")), None);
+        assert_eq!(parse_keyframe_interval(Some("-1")), default);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut pacer = Pacer::new(50);
+        assert_eq!(pacer.delay(), Duration::from_millis(20));
+        pacer.unchanged();
+        assert_eq!(pacer.delay(), Duration::from_millis(40));
+        for _ in 0..10 {
+            pacer.unchanged();
+        }
+        assert_eq!(pacer.delay(), IDLE_MAX);
+        pacer.changed();
+        assert_eq!(pacer.delay(), Duration::from_millis(20));
+
+        // 帧率很低时不会因为空闲而加快
+        let mut pacer = Pacer::new(1);
+        pacer.unchanged();
+        assert_eq!(pacer.delay(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,614 @@
+use crate::session::SessionError;
+use communication::convert;
+use communication::convert::ColorSpace;
+use communication::convert::PixelFormat;
+use communication::frame;
+use communication::frame::FrameHeader;
+use communication::frame::FrameKind;
+use communication::heartbeat;
+use communication::mux;
+use communication::mux::MuxSender;
+use communication::pool::Buffer;
+use communication::pool::BufferPool;
+use communication::record;
+use communication::record::Recorder;
+use flate2::Compress;
+use flate2::Compression;
+use flate2::FlushCompress;
+use flate2::Status;
+use rayon::prelude::*;
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::SyncSender;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+// 相邻阶段之间最多排队的任务数, 排满时上一阶段阻塞
+const DEPTH: usize = 1;
+// 每个阶段保留的空闲缓冲区, 足够排队中和处理中的帧轮换
+const POOL_IDLE: usize = 4;
+/// 打印各阶段耗时的间隔
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 流水线的阶段
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    Capture,
+    Convert,
+    Diff,
+    Compress,
+    // 等待发送队列
+    Send,
+}
+
+const STAGES: [&str; 5] = ["capture", "convert", "diff", "compress", "send"];
+
+/// 各阶段之间传递的任务
+pub enum Task<T> {
+    /// 画面配置消息, 在之前的帧之后发送, 之后是关键帧
+    Display(Vec<u8>),
+    Frame(T),
+    /// 画面没有变化时把上一帧作为关键帧重新发送, 由比较阶段处理
+    Repeat,
+}
+
+/// 截屏线程交给流水线的任务
+pub type Job = Task<Captured>;
+
+/// 开始截屏的时间
+#[derive(Debug, Clone, Copy)]
+pub struct Stamp {
+    start: Instant,
+    // 写入消息头的时间戳, 微秒
+    timestamp: u64,
+}
+
+impl Stamp {
+    pub fn now() -> Self {
+        Stamp {
+            start: Instant::now(),
+            timestamp: heartbeat::now(),
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// 截屏的 BGRA 数据, 已经缩小到发送的尺寸
+pub struct Captured {
+    pub bgra: Buffer,
+    pub w: usize,
+    pub h: usize,
+    pub stride: usize,
+    pub space: ColorSpace,
+    pub format: PixelFormat,
+    // 发送完整的一帧
+    pub key: bool,
+    pub stamp: Stamp,
+}
+
+// 转换后的一帧
+struct Converted {
+    data: Buffer,
+    // 判断画面是否变化时比较的长度, I420 只比较亮度
+    compare: usize,
+    key: bool,
+    stamp: Stamp,
+}
+
+// 交给压缩的一帧, data 为完整的一帧或与上一帧的异或
+struct Diffed {
+    kind: FrameKind,
+    data: Buffer,
+    // 还原后一帧的校验和
+    checksum: u32,
+    stamp: Stamp,
+}
+
+/// 各阶段的平均耗时, 和从开始截屏到交给发送队列的延迟
+pub struct Metrics {
+    totals: Mutex<Totals>,
+}
+
+struct Totals {
+    stages: [(Duration, u32); STAGES.len()],
+    latency: (Duration, u32),
+    since: Instant,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            totals: Mutex::new(Totals {
+                stages: Default::default(),
+                latency: Default::default(),
+                since: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn record(&self, stage: Stage, elapsed: Duration) {
+        let (total, n) = &mut self.totals.lock().unwrap().stages[stage as usize];
+        *total += elapsed;
+        *n += 1;
+    }
+
+    /// 一帧交给了发送队列
+    fn sent(&self, latency: Duration) {
+        let (total, n) = &mut self.totals.lock().unwrap().latency;
+        *total += latency;
+        *n += 1;
+    }
+
+    /// 距离上次超过 interval 并且发送过画面时, 返回平均耗时并重新统计
+    pub fn report(&self, interval: Duration) -> Option<String> {
+        let mut totals = self.totals.lock().unwrap();
+        if totals.since.elapsed() < interval || totals.latency.1 == 0 {
+            return None;
+        }
+        let ms = |(total, n): (Duration, u32)| total.as_secs_f64() * 1000.0 / n.max(1) as f64;
+        let stages: Vec<String> = STAGES
+            .iter()
+            .zip(totals.stages)
+            .map(|(name, total)| format!("{} {:.2}", name, ms(total)))
+            .collect();
+        let report = format!(
+            "Pipeline (avg ms): {}, latency {:.2} over {} frames",
+            stages.join(", "),
+            ms(totals.latency),
+            totals.latency.1
+        );
+        *totals = Totals {
+            stages: Default::default(),
+            latency: Default::default(),
+            since: Instant::now(),
+        };
+        Some(report)
+    }
+}
+
+/// 截屏线程一端
+pub struct Feed<'a> {
+    tx: SyncSender<Job>,
+    // 存放截屏的缓冲区, 转换后回到这里
+    pool: BufferPool,
+    metrics: &'a Metrics,
+    idle: &'a AtomicBool,
+}
+
+impl Feed<'_> {
+    pub fn buffer(&self) -> Buffer {
+        self.pool.get()
+    }
+
+    /// 交给转换阶段, 流水线已经停止时返回 false
+    pub fn send(&self, job: Job) -> bool {
+        self.tx.send(job).is_ok()
+    }
+
+    pub fn record(&self, stage: Stage, elapsed: Duration) {
+        self.metrics.record(stage, elapsed);
+    }
+
+    /// 上次检查之后是否丢弃过没有变化的画面
+    pub fn unchanged(&self) -> bool {
+        self.idle.swap(false, Ordering::Relaxed)
+    }
+}
+
+/**
+ * 截屏 → 转换 → 比较 → 压缩 → 发送 的流水线
+ * capture 在当前线程截屏, 其他阶段各一个线程, 之间用有界的通道连接, 帧率取决于最慢的阶段
+ * capture 返回后处理完排队的任务再结束; dropped 在丢弃没有变化的画面时调用
+ * 有 recorder 时记录发送的每个画面消息
+ */
+pub fn run<C>(
+    mux: &MuxSender,
+    recorder: Option<&Recorder>,
+    dropped: &(dyn Fn() + Sync),
+    capture: C,
+) -> Result<(), SessionError>
+where
+    C: FnOnce(Feed) -> Result<(), SessionError>,
+{
+    let metrics = &Metrics::new();
+    let idle = &AtomicBool::new(false);
+    std::thread::scope(|s| {
+        let (tx, captured) = sync_channel(DEPTH);
+        let (converted_tx, converted) = sync_channel(DEPTH);
+        let (diffed_tx, diffed) = sync_channel(DEPTH);
+        let stages = [
+            s.spawn(move || {
+                convert_stage(captured, converted_tx, metrics);
+                Ok(())
+            }),
+            s.spawn(move || {
+                diff_stage(converted, diffed_tx, metrics, idle, dropped);
+                Ok(())
+            }),
+            s.spawn(move || compress_stage(diffed, mux, recorder, metrics)),
+        ];
+        let feed = Feed {
+            tx,
+            pool: BufferPool::new(POOL_IDLE),
+            metrics,
+            idle,
+        };
+        // feed 在这里释放, 后面的阶段依次结束
+        let mut result = capture(feed);
+        for stage in stages {
+            let stage = stage
+                .join()
+                .unwrap_or_else(|e| Err(SessionError::from_panic(e)));
+            result = result.and(stage);
+        }
+        result
+    })
+}
+
+fn convert_stage(rx: Receiver<Job>, tx: SyncSender<Task<Converted>>, metrics: &Metrics) {
+    let pool = BufferPool::new(POOL_IDLE);
+    for job in rx {
+        let task = match job {
+            Task::Frame(c) => {
+                let start = Instant::now();
+                let mut data = pool.get();
+                let compare = match c.format {
+                    PixelFormat::I420 => {
+                        convert::bgra_to_i420(c.space, c.w, c.h, c.stride, &c.bgra, &mut data);
+                        c.w * c.h
+                    }
+                    PixelFormat::Rgb => {
+                        convert::bgra_to_rgb(c.w, c.h, c.stride, &c.bgra, &mut data);
+                        data.len()
+                    }
+                };
+                metrics.record(Stage::Convert, start.elapsed());
+                Task::Frame(Converted {
+                    data,
+                    compare,
+                    key: c.key,
+                    stamp: c.stamp,
+                })
+            }
+            Task::Display(msg) => Task::Display(msg),
+            Task::Repeat => Task::Repeat,
+        };
+        if tx.send(task).is_err() {
+            break;
+        }
+    }
+}
+
+/// 与上一帧比较, 没有变化时丢弃, 否则输出完整的一帧或异或
+fn diff_stage(
+    rx: Receiver<Task<Converted>>,
+    tx: SyncSender<Task<Diffed>>,
+    metrics: &Metrics,
+    idle: &AtomicBool,
+    dropped: &(dyn Fn() + Sync),
+) {
+    let pool = BufferPool::new(POOL_IDLE);
+    // 上一帧, 画面配置变化后清空
+    let mut last: Option<Buffer> = None;
+    let key = |frame: &Buffer, stamp| {
+        let mut data = pool.get();
+        data.extend_from_slice(frame);
+        Diffed {
+            kind: FrameKind::Key,
+            data,
+            checksum: frame::checksum(frame),
+            stamp,
+        }
+    };
+    for task in rx {
+        let start = Instant::now();
+        let diffed = match task {
+            Task::Display(msg) => {
+                last = None;
+                if tx.send(Task::Display(msg)).is_err() {
+                    break;
+                }
+                continue;
+            }
+            Task::Repeat => match &last {
+                Some(frame) => key(frame, Stamp::now()),
+                None => {
+                    dropped();
+                    continue;
+                }
+            },
+            Task::Frame(c) => match last.take() {
+                Some(mut prev) if !c.key && prev.len() == c.data.len() => {
+                    if c.data[..c.compare] == prev[..c.compare] {
+                        last = Some(prev);
+                        metrics.record(Stage::Diff, start.elapsed());
+                        idle.store(true, Ordering::Relaxed);
+                        dropped();
+                        continue;
+                    }
+                    // 异或写入上一帧的缓冲区, 新的一帧作为下次比较的上一帧
+                    prev.par_iter_mut()
+                        .zip(c.data.par_iter())
+                        .for_each(|(a, b)| *a ^= *b);
+                    let checksum = frame::checksum(&c.data);
+                    last = Some(c.data);
+                    Diffed {
+                        kind: FrameKind::Delta,
+                        data: prev,
+                        checksum,
+                        stamp: c.stamp,
+                    }
+                }
+                _ => {
+                    let diffed = key(&c.data, c.stamp);
+                    last = Some(c.data);
+                    diffed
+                }
+            },
+        };
+        metrics.record(Stage::Diff, start.elapsed());
+        if tx.send(Task::Frame(diffed)).is_err() {
+            break;
+        }
+    }
+}
+
+fn compress_stage(
+    rx: Receiver<Task<Diffed>>,
+    mux: &MuxSender,
+    recorder: Option<&Recorder>,
+    metrics: &Metrics,
+) -> Result<(), SessionError> {
+    let mut encoder = FrameEncoder::new();
+    for task in rx {
+        let frame = match task {
+            Task::Frame(frame) => frame,
+            Task::Display(msg) => {
+                if let Err(e) = record::record(recorder, mux::VIDEO, &msg) {
+                    println!("{}", e);
+                }
+                mux.send(mux::VIDEO, &msg)?;
+                continue;
+            }
+            // 比较阶段已经处理
+            Task::Repeat => continue,
+        };
+        let start = Instant::now();
+        let msg = encoder.encode(&frame)?;
+        metrics.record(Stage::Compress, start.elapsed());
+        if let Err(e) = record::record(recorder, mux::VIDEO, &msg) {
+            println!("{}", e);
+        }
+        let start = Instant::now();
+        mux.send_buffer(mux::VIDEO, msg)?;
+        metrics.record(Stage::Send, start.elapsed());
+        metrics.sent(frame.stamp.elapsed());
+        if let Some(report) = metrics.report(REPORT_INTERVAL) {
+            println!("{}", report);
+        }
+    }
+    Ok(())
+}
+
+/// 分条带并行压缩图像帧, 给每一帧编号
+struct FrameEncoder {
+    // 每个条带一个压缩器和输出缓冲区, 重复使用
+    compressors: Vec<Compress>,
+    stripes: Vec<Vec<u8>>,
+    // 压缩后的消息直接交给发送队列, 发送完后回到这里
+    pool: BufferPool,
+    seq: u32,
+}
+
+impl FrameEncoder {
+    fn new() -> Self {
+        FrameEncoder {
+            compressors: Vec::new(),
+            stripes: Vec::new(),
+            pool: BufferPool::new(POOL_IDLE),
+            seq: 0,
+        }
+    }
+
+    /// 压缩一帧, 返回完整的消息
+    fn encode(&mut self, frame: &Diffed) -> Result<Buffer, SessionError> {
+        let data = &frame.data[..];
+        let length = u32::try_from(data.len())
+            .map_err(|_| SessionError::Protocol("frame too large".to_string()))?;
+        let count = frame::stripe_count(data.len(), rayon::current_num_threads());
+        while self.compressors.len() < count {
+            self.compressors
+                .push(Compress::new(Compression::default(), false));
+            self.stripes.push(Vec::new());
+        }
+        self.compressors[..count]
+            .par_iter_mut()
+            .zip(self.stripes[..count].par_iter_mut())
+            .enumerate()
+            .try_for_each(|(i, (c, out))| {
+                deflate(c, &data[frame::stripe(data.len(), count, i)], out)
+            })?;
+
+        let header = FrameHeader {
+            kind: frame.kind,
+            seq: self.seq,
+            timestamp: frame.stamp.timestamp,
+            length,
+            checksum: frame.checksum,
+            stripes: count as u8,
+        };
+        self.seq = self.seq.wrapping_add(1);
+        let mut msg = self.pool.get();
+        header.encode(&mut msg);
+        frame::encode_stripes(&self.stripes[..count], &mut msg);
+        Ok(msg)
+    }
+}
+
+/// 把一个条带压缩为完整的 deflate 流
+fn deflate(c: &mut Compress, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    c.reset();
+    out.clear();
+    loop {
+        // 输出缓冲区满了时扩大再继续
+        out.reserve(input.len() / 4 + 64);
+        let consumed = c.total_in() as usize;
+        if c.compress_vec(&input[consumed..], out, FlushCompress::Finish)? == Status::StreamEnd {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use communication::mux::MuxReader;
+    use flate2::Decompress;
+    use flate2::FlushDecompress;
+    use std::io::Write;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    /// 发送端写入, 测试读出
+    #[derive(Clone, Default)]
+    struct Pipe(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn inflate(header: &FrameHeader, data: &[u8]) -> Vec<u8> {
+        let stripes = frame::split_stripes(data, header.stripes as usize).unwrap();
+        let mut out = Vec::new();
+        for stripe in stripes {
+            let mut d = Decompress::new(false);
+            let mut part = vec![0u8; header.length as usize];
+            let status = d.decompress(stripe, &mut part, FlushDecompress::Finish);
+            assert_eq!(status.unwrap(), Status::StreamEnd);
+            out.extend_from_slice(&part[..d.total_out() as usize]);
+        }
+        out
+    }
+
+    #[test]
+    fn test_run() {
+        let pipe = Pipe::default();
+        let mux = MuxSender::start(pipe.clone());
+        let dropped = AtomicUsize::new(0);
+        let (w, h) = (64, 32);
+        let job = |value: u8, key: bool| {
+            Job::Frame(Captured {
+                bgra: vec![value; w * h * 4].into(),
+                w,
+                h,
+                stride: w * 4,
+                space: ColorSpace::default(),
+                format: PixelFormat::I420,
+                key,
+                stamp: Stamp::now(),
+            })
+        };
+        let on_drop = || {
+            dropped.fetch_add(1, Ordering::Relaxed);
+        };
+        run(&mux, None, &on_drop, |feed| {
+            // 还没有上一帧, 不能重新发送
+            assert!(feed.send(Job::Repeat));
+            assert!(feed.send(Job::Display(vec![communication::VIDEO_DISPLAY])));
+            assert!(feed.send(job(10, true)));
+            // 没有变化的画面被丢弃
+            assert!(feed.send(job(10, false)));
+            assert!(feed.send(job(200, false)));
+            assert!(feed.send(Job::Repeat));
+            Ok(())
+        })
+        .unwrap();
+        mux.flush().unwrap();
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+
+        let data = pipe.0.lock().unwrap().clone();
+        let mut reader = MuxReader::new(&data[..], 1 << 20);
+        let (_, msg) = reader.recv().unwrap();
+        assert_eq!(&msg[..], &[communication::VIDEO_DISPLAY]);
+        // 关键帧, 差异帧, 重新发送的关键帧, 按顺序编号
+        let mut last = Vec::new();
+        for (seq, kind) in [FrameKind::Key, FrameKind::Delta, FrameKind::Key]
+            .into_iter()
+            .enumerate()
+        {
+            let (channel, msg) = reader.recv().unwrap();
+            assert_eq!(channel, mux::VIDEO);
+            let (header, data) = FrameHeader::decode(&msg).unwrap();
+            assert_eq!((header.kind, header.seq), (kind, seq as u32));
+            let mut frame = inflate(&header, data);
+            if kind == FrameKind::Delta {
+                frame.iter_mut().zip(&last).for_each(|(a, b)| *a ^= *b);
+            }
+            assert_eq!(frame::checksum(&frame), header.checksum);
+            last = frame;
+        }
+        assert!(reader.recv().is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let frame: Vec<u8> = (0..(3 << 20)).map(|i| (i / 5 % 251) as u8).collect();
+        let mut encoder = FrameEncoder::new();
+        for seq in 0..2 {
+            let msg = encoder
+                .encode(&Diffed {
+                    kind: FrameKind::Key,
+                    data: frame.clone().into(),
+                    checksum: frame::checksum(&frame),
+                    stamp: Stamp::now(),
+                })
+                .unwrap();
+            let (header, data) = FrameHeader::decode(&msg).unwrap();
+            assert_eq!((header.seq, header.length as usize), (seq, frame.len()));
+            let count = header.stripes as usize;
+            assert_eq!(
+                count,
+                frame::stripe_count(frame.len(), rayon::current_num_threads())
+            );
+
+            // 每个条带单独解压
+            assert_eq!(inflate(&header, data), frame);
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let metrics = Metrics::new();
+        metrics.record(Stage::Convert, Duration::from_millis(3));
+        // 没有发送过画面时不报告
+        assert_eq!(metrics.report(Duration::ZERO), None);
+        metrics.record(Stage::Convert, Duration::from_millis(1));
+        metrics.sent(Duration::from_millis(10));
+        metrics.sent(Duration::from_millis(20));
+        let report = metrics.report(Duration::ZERO).unwrap();
+        assert!(report.contains("convert 2.00"), "{}", report);
+        assert!(report.contains("capture 0.00"), "{}", report);
+        assert!(
+            report.ends_with("latency 15.00 over 2 frames"),
+            "{}",
+            report
+        );
+        // 重新统计
+        assert_eq!(metrics.report(Duration::ZERO), None);
+        metrics.sent(Duration::ZERO);
+        assert_eq!(metrics.report(Duration::from_secs(60)), None);
+    }
+}
@@ -0,0 +1,179 @@
+use crate::screen::Rect;
+
+/// 只共享屏幕的一个矩形区域, 格式为 x,y,w,h, 坐标为虚拟桌面坐标
+pub const REGION_ENV: &str = "DIFFSCREEN_REGION";
+/// 只共享一个窗口, 值为窗口标题, 或者 #句柄 (十进制或 0x 开头的十六进制)
+pub const WINDOW_ENV: &str = "DIFFSCREEN_WINDOW";
+
+/// 共享的范围
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Region {
+    // 整个显示器或虚拟桌面
+    Full,
+    Rect(Rect),
+    // 窗口在屏幕上的区域, 窗口移动或改变大小时跟随
+    Window(String),
+}
+
+impl Region {
+    /// 读取环境变量, 同时设置时窗口优先, 无效时共享整个屏幕
+    pub fn from_env() -> Region {
+        if let Ok(window) = std::env::var(WINDOW_ENV) {
+            if !window.trim().is_empty() {
+                return Region::Window(window.trim().to_string());
+            }
+        }
+        match std::env::var(REGION_ENV)
+            .ok()
+            .as_deref()
+            .and_then(parse_rect)
+        {
+            Some(rect) => Region::Rect(rect),
+            None => Region::Full,
+        }
+    }
+
+    /// 当前的区域, 整个屏幕或找不到窗口时返回 None
+    pub fn resolve(&self) -> Option<Rect> {
+        match self {
+            Region::Full => None,
+            Region::Rect(rect) => Some(*rect),
+            Region::Window(window) => window_rect(window),
+        }
+    }
+}
+
+fn parse_rect(value: &str) -> Option<Rect> {
+    let parts: Vec<&str> = value.split(',').map(|p| p.trim()).collect();
+    match parts[..] {
+        [x, y, w, h] => {
+            let rect = Rect {
+                x: x.parse().ok()?,
+                y: y.parse().ok()?,
+                w: w.parse().ok()?,
+                h: h.parse().ok()?,
+            };
+            if rect.w == 0 || rect.h == 0 {
+                return None;
+            }
+            Some(rect)
+        }
+        _ => None,
+    }
+}
+
+/// 两个区域的交集, 不相交时返回 None
+pub fn intersect(a: Rect, b: Rect) -> Option<Rect> {
+    let x = a.x.max(b.x);
+    let y = a.y.max(b.y);
+    let right = (a.x + a.w as i32).min(b.x + b.w as i32);
+    let bottom = (a.y + a.h as i32).min(b.y + b.h as i32);
+    if right <= x || bottom <= y {
+        return None;
+    }
+    Some(Rect {
+        x,
+        y,
+        w: (right - x) as usize,
+        h: (bottom - y) as usize,
+    })
+}
+
+/// 从 BGRA 图像中复制 (x, y) 开始的 w*h 区域, 输出没有行填充
+pub fn crop_bgra(
+    src: &[u8],
+    stride: usize,
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+    dst: &mut Vec<u8>,
+) {
+    dst.clear();
+    for row in y..y + h {
+        let start = row * stride + x * 4;
+        dst.extend_from_slice(&src[start..start + w * 4]);
+    }
+}
+
+#[cfg(windows)]
+fn window_rect(window: &str) -> Option<Rect> {
+    use std::mem::zeroed;
+    use std::ptr::null;
+    use winapi::shared::windef::HWND;
+    use winapi::shared::windef::RECT;
+    use winapi::um::winuser::FindWindowW;
+    use winapi::um::winuser::GetWindowRect;
+    use winapi::um::winuser::IsIconic;
+    use winapi::um::winuser::IsWindow;
+
+    let hwnd = match window.strip_prefix('#') {
+        Some(id) => {
+            let id = match id.strip_prefix("0x") {
+                Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+                None => id.parse::<usize>().ok()?,
+            };
+            id as HWND
+        }
+        None => {
+            let title: Vec<u16> = window.encode_utf16().chain(std::iter::once(0)).collect();
+            unsafe { FindWindowW(null(), title.as_ptr()) }
+        }
+    };
+    // 最小化的窗口不在屏幕上
+    if hwnd.is_null() || unsafe { IsWindow(hwnd) == 0 || IsIconic(hwnd) != 0 } {
+        return None;
+    }
+    let mut rect: RECT = unsafe { zeroed() };
+    if unsafe { GetWindowRect(hwnd, &mut rect) } == 0 {
+        return None;
+    }
+    Some(Rect {
+        x: rect.left,
+        y: rect.top,
+        w: (rect.right - rect.left).max(0) as usize,
+        h: (rect.bottom - rect.top).max(0) as usize,
+    })
+}
+
+/// 其他平台暂不支持按窗口截取
+#[cfg(not(windows))]
+fn window_rect(_window: &str) -> Option<Rect> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_intersect() {
+        let rect = |x, y, w, h| Rect { x, y, w, h };
+        assert_eq!(
+            parse_rect("100, -20,640,480"),
+            Some(rect(100, -20, 640, 480))
+        );
+        assert_eq!(parse_rect("0,0,0,480"), None);
+        assert_eq!(parse_rect("0,0,640"), None);
+        assert_eq!(parse_rect("a,0,640,480"), None);
+
+        let screen = rect(0, 0, 1920, 1080);
+        assert_eq!(
+            intersect(rect(1800, -20, 640, 480), screen),
+            Some(rect(1800, 0, 120, 460))
+        );
+        assert_eq!(intersect(rect(1920, 0, 10, 10), screen), None);
+    }
+
+    #[test]
+    fn test_crop() {
+        // 4x3 的图像, 每行有 8 字节填充
+        let stride = 4 * 4 + 8;
+        let src: Vec<u8> = (0..stride * 3).map(|i| i as u8).collect();
+        let mut dst = vec![0xff; 3];
+        crop_bgra(&src, stride, 1, 1, 2, 2, &mut dst);
+        assert_eq!(dst.len(), 2 * 2 * 4);
+        assert_eq!(&dst[..8], &src[stride + 4..stride + 12]);
+        assert_eq!(&dst[8..], &src[stride * 2 + 4..stride * 2 + 12]);
+    }
+}
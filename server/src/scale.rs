@@ -0,0 +1,80 @@
+use rayon::prelude::*;
+
+/// 在 (max_w, max_h) 内保持宽高比的输出尺寸, 不放大, 宽高为偶数方便 I420 采样
+pub fn fit(w: usize, h: usize, max_w: usize, max_h: usize) -> (usize, usize) {
+    if max_w == 0 || max_h == 0 || (w <= max_w && h <= max_h) {
+        return (w, h);
+    }
+    // 按缩小得更多的一边计算
+    let (ow, oh) = if max_w * h <= max_h * w {
+        (max_w, h * max_w / w)
+    } else {
+        (w * max_h / h, max_h)
+    };
+    ((ow & !1).max(2), (oh & !1).max(2))
+}
+
+/// 用区域平均缩小 BGRA 图像, 每个输出像素为对应源区域所有像素的平均值
+/// stride 为源图像每行的字节数, 输出没有行填充
+pub fn downscale_bgra(
+    src: &[u8],
+    w: usize,
+    h: usize,
+    stride: usize,
+    dw: usize,
+    dh: usize,
+    dest: &mut Vec<u8>,
+) {
+    dest.clear();
+    dest.resize(dw * dh * 4, 0);
+    dest.par_chunks_mut(dw * 4)
+        .enumerate()
+        .for_each(|(dy, row)| {
+            let (y0, y1) = (dy * h / dh, ((dy + 1) * h / dh).max(dy * h / dh + 1));
+            for dx in 0..dw {
+                let (x0, x1) = (dx * w / dw, ((dx + 1) * w / dw).max(dx * w / dw + 1));
+                let mut sum = [0u32; 4];
+                for y in y0..y1 {
+                    let line = &src[y * stride + x0 * 4..y * stride + x1 * 4];
+                    for px in line.chunks_exact(4) {
+                        for (s, v) in sum.iter_mut().zip(px) {
+                            *s += *v as u32;
+                        }
+                    }
+                }
+                let n = ((y1 - y0) * (x1 - x0)) as u32;
+                for (o, s) in row[dx * 4..dx * 4 + 4].iter_mut().zip(sum) {
+                    *o = ((s + n / 2) / n) as u8;
+                }
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fit() {
+        assert_eq!(fit(3840, 2160, 0, 0), (3840, 2160));
+        assert_eq!(fit(1280, 720, 1920, 1080), (1280, 720));
+        assert_eq!(fit(3840, 2160, 1920, 1200), (1920, 1080));
+        assert_eq!(fit(3840, 2160, 1000, 1000), (1000, 562));
+        assert_eq!(fit(1000, 10, 3, 3), (2, 2));
+    }
+
+    #[test]
+    fn test_downscale() {
+        // 4x2 缩小为 2x1, 每行有 4 字节填充
+        let stride = 4 * 4 + 4;
+        let mut src = vec![0u8; stride * 2];
+        for (i, v) in [10u8, 20, 30, 40].iter().enumerate() {
+            src[i * 4..i * 4 + 4].copy_from_slice(&[*v; 4]);
+            src[stride + i * 4..stride + i * 4 + 4].copy_from_slice(&[*v + 1; 4]);
+        }
+        let mut dest = Vec::new();
+        downscale_bgra(&src, 4, 2, stride, 2, 1, &mut dest);
+        // (10 + 20 + 11 + 21) / 4 = 15.5, (30 + 40 + 31 + 41) / 4 = 35.5
+        assert_eq!(dest, vec![16, 16, 16, 16, 36, 36, 36, 36]);
+    }
+}
@@ -1,78 +1,477 @@
+use crate::region;
+use crate::region::Region;
+use communication::display::DisplayInfo;
 use scrap::Capturer;
 use scrap::Display;
+use std::io;
 use std::io::ErrorKind::WouldBlock;
-use std::slice::from_raw_parts;
 use std::time::Duration;
+use std::time::Instant;
+
+/// 虚拟桌面中的一个区域
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub w: usize,
+    pub h: usize,
+}
 
 /**
  * 截屏
+ * 截取一个显示器, 或者把所有显示器拼成一个虚拟桌面
+ * 配置了共享范围时只输出其中的一部分
  */
 pub struct Cap {
-    w: usize,
-    h: usize,
-    capturer: Option<Capturer>,
-    sleep: Duration,
+    // 截取的显示器序号, None 为虚拟桌面
+    target: Option<usize>,
+    displays: Vec<DisplayInfo>,
+    // 当前截取的区域
+    rect: Rect,
+    // 共享范围, 与截取区域的交集为输出的区域
+    region: Region,
+    crop: Option<Rect>,
+    cropped: Vec<u8>,
+    // (显示器序号, 截屏)
+    capturers: Vec<(usize, Capturer)>,
+    // 虚拟桌面的拼接缓冲区
+    desktop: Vec<u8>,
+    // 上次检查显示器配置的时间
+    checked: Instant,
 }
+
+// 检查显示器配置的间隔, 有的平台分辨率变化后截屏不会出错
+const DISPLAY_CHECK: Duration = Duration::from_secs(1);
+
 impl Cap {
-    pub fn new() -> Cap {
-        let display = Display::primary().unwrap();
-        let capturer = Capturer::new(display).unwrap();
-        let (w, h) = (capturer.width(), capturer.height());
-        Cap {
-            w,
-            h,
-            capturer: Some(capturer),
-            sleep: Duration::new(1, 0) / 60,
+    /// 截取主显示器, 共享部分区域时截取整个虚拟桌面
+    pub fn new(region: Region) -> io::Result<Cap> {
+        let displays = layout()?;
+        let target = match region {
+            Region::Full => Some(primary(&displays)),
+            _ => None,
+        };
+        let mut cap = Cap {
+            target,
+            displays,
+            rect: Rect::default(),
+            region,
+            crop: None,
+            cropped: Vec::new(),
+            capturers: Vec::new(),
+            desktop: Vec::new(),
+            checked: Instant::now(),
+        };
+        cap.open()?;
+        Ok(cap)
+    }
+    /// 创建目标显示器的截屏
+    fn open(&mut self) -> io::Result<()> {
+        // 先释放旧的截屏, 有的平台同一个显示器只能有一个
+        self.capturers.clear();
+        let indexes: Vec<usize> = match self.target {
+            Some(i) => vec![i],
+            None => (0..self.displays.len()).collect(),
+        };
+        let mut all: Vec<Option<Display>> = Display::all()?.into_iter().map(Some).collect();
+        for i in indexes {
+            let display = all
+                .get_mut(i)
+                .and_then(|d| d.take())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "display not found"))?;
+            self.capturers.push((i, Capturer::new(display)?));
         }
+        self.rect = match self.target {
+            Some(i) => rect_of(&self.displays[i]),
+            None => bounding(&self.displays),
+        };
+        if self.capturers.len() > 1 {
+            self.desktop = vec![0u8; self.rect.w * self.rect.h * 4];
+        }
+        self.crop = None;
+        if self.region != Region::Full {
+            // 不能退回共享整个屏幕
+            self.crop = Some(self.region_crop().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "shared region is not on screen")
+            })?);
+        }
+        Ok(())
+    }
+    /// 共享范围与截取区域的交集
+    fn region_crop(&self) -> Option<Rect> {
+        self.region
+            .resolve()
+            .and_then(|r| region::intersect(r, self.rect))
     }
-    fn reload(&mut self) {
+    /// 重新创建截屏, 之后的第一次截屏返回完整的画面
+    pub fn reload(&mut self) {
         println!("Reload capturer");
-        drop(self.capturer.take());
-        let display = match Display::primary() {
-            Ok(display) => display,
+        self.capturers.clear();
+        let displays = match layout() {
+            Ok(displays) => displays,
             Err(_) => {
                 return;
             }
         };
-
-        let capturer = match Capturer::new(display) {
-            Ok(capturer) => capturer,
-            Err(_) => return,
-        };
-        self.capturer = Some(capturer);
+        // 显示器被移除时回到主显示器
+        if matches!(self.target, Some(i) if i >= displays.len()) {
+            self.target = Some(primary(&displays));
+        }
+        // 分辨率或方向可能已经改变
+        self.displays = displays;
+        if self.open().is_err() {
+            self.capturers.clear();
+        }
+    }
+    /// 切换显示器, None 为虚拟桌面, 序号无效时忽略
+    pub fn select(&mut self, target: Option<usize>) {
+        if target == self.target || matches!(target, Some(i) if i >= self.displays.len()) {
+            return;
+        }
+        let last = self.target;
+        self.target = target;
+        // 选择的显示器不包含共享范围时保持不变
+        if self.open().is_err() {
+            self.target = last;
+            if self.open().is_err() {
+                self.capturers.clear();
+            }
+        }
+    }
+    pub fn displays(&self) -> &[DisplayInfo] {
+        &self.displays
+    }
+    /// 当前输出的区域, 重新创建截屏或共享的窗口移动后可能改变
+    pub fn rect(&self) -> Rect {
+        self.crop.unwrap_or(self.rect)
     }
+    /// 当前的宽高
     pub fn wh(&self) -> (usize, usize) {
-        (self.w, self.h)
+        let rect = self.rect();
+        (rect.w, rect.h)
     }
+    /// 返回 BGRA 数据和每行的字节数
+    /// 暂时没有新的画面时立即返回 None, 由调用方决定等待多久; 出错时等待一会儿后重新创建截屏
     #[inline]
-    pub fn cap(&mut self) -> &[u8] {
-        loop {
-            match &mut self.capturer {
-                Some(capturer) => {
-                    // Wait until there's a frame.
-                    let cp = capturer.frame();
-                    let buffer = match cp {
-                        Ok(buffer) => buffer,
-                        Err(error) => {
-                            std::thread::sleep(self.sleep);
-                            if error.kind() == WouldBlock {
-                                // Keep spinning.
-                                continue;
-                            } else {
-                                std::thread::sleep(std::time::Duration::from_millis(200));
-                                self.reload();
-                                continue;
-                            }
+    pub fn cap(&mut self) -> Option<(&[u8], usize)> {
+        if self.checked.elapsed() >= DISPLAY_CHECK {
+            self.checked = Instant::now();
+            if matches!(layout(), Ok(displays) if displays != self.displays) {
+                self.reload();
+                return None;
+            }
+            // 跟随窗口, 找不到窗口时保持原来的区域
+            if let (Region::Window(_), Some(crop)) = (&self.region, self.region_crop()) {
+                if self.crop != Some(crop) {
+                    self.crop = Some(crop);
+                    return None;
+                }
+            }
+        }
+        match self.capturers.len() {
+            0 => {
+                std::thread::sleep(std::time::Duration::from_millis(200));
+            }
+            1 => {
+                let (x, y, w, h) = output_area(self.rect, self.crop);
+                let mut copied = false;
+                match self.capturers[0].1.frame() {
+                    // 在画面的借用内复制出共享的区域
+                    // 数据比当前尺寸少, 说明分辨率已经改变
+                    Ok(buffer) => {
+                        if let Some(stride) = stride(buffer.len(), self.rect.w, self.rect.h) {
+                            region::crop_bgra(&buffer, stride, x, y, w, h, &mut self.cropped);
+                            copied = true;
                         }
-                    };
-                    return unsafe { from_raw_parts(buffer.as_ptr(), buffer.len()) };
+                    }
+                    Err(error) if error.kind() == WouldBlock => {
+                        return None;
+                    }
+                    Err(_) => {
+                        std::thread::sleep(std::time::Duration::from_millis(200));
+                    }
                 }
-                None => {
-                    std::thread::sleep(std::time::Duration::from_millis(200));
-                    self.reload();
-                    continue;
+                if copied {
+                    return Some((&self.cropped, w * 4));
                 }
-            };
+            }
+            _ => {
+                if let Some(updated) = self.composite() {
+                    if updated {
+                        let stride = self.rect.w * 4;
+                        return Some(output(
+                            &self.desktop,
+                            stride,
+                            self.rect,
+                            self.crop,
+                            &mut self.cropped,
+                        ));
+                    }
+                    return None;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(200));
+            }
+        }
+        self.reload();
+        None
+    }
+    /// 把有新画面的显示器复制到拼接缓冲区, 返回是否有更新, 截屏出错时返回 None
+    fn composite(&mut self) -> Option<bool> {
+        let mut updated = false;
+        for (i, capturer) in self.capturers.iter_mut() {
+            let d = self.displays[*i];
+            let (w, h) = (d.w as usize, d.h as usize);
+            match capturer.frame() {
+                Ok(buffer) => {
+                    let stride = stride(buffer.len(), w, h)?;
+                    let ox = (d.x - self.rect.x) as usize;
+                    let oy = (d.y - self.rect.y) as usize;
+                    for row in 0..h {
+                        let dst = ((oy + row) * self.rect.w + ox) * 4;
+                        self.desktop[dst..dst + w * 4]
+                            .copy_from_slice(&buffer[row * stride..row * stride + w * 4]);
+                    }
+                    updated = true;
+                }
+                Err(error) if error.kind() == WouldBlock => {}
+                Err(_) => return None,
+            }
+        }
+        Some(updated)
+    }
+}
+
+/// 从截取的画面中裁剪出共享的区域, 返回数据和每行的字节数
+fn output<'a>(
+    frame: &'a [u8],
+    stride: usize,
+    rect: Rect,
+    crop: Option<Rect>,
+    cropped: &'a mut Vec<u8>,
+) -> (&'a [u8], usize) {
+    if crop.is_none() {
+        return (frame, stride);
+    }
+    let (x, y, w, h) = output_area(rect, crop);
+    region::crop_bgra(frame, stride, x, y, w, h, cropped);
+    (cropped, w * 4)
+}
+
+/// 输出的区域在截取区域中的位置和大小
+fn output_area(rect: Rect, crop: Option<Rect>) -> (usize, usize, usize, usize) {
+    match crop {
+        Some(crop) => (
+            (crop.x - rect.x) as usize,
+            (crop.y - rect.y) as usize,
+            crop.w,
+            crop.h,
+        ),
+        None => (0, 0, rect.w, rect.h),
+    }
+}
+
+/// scrap 的一帧是 h 行, 每行字节数相同, 由长度得到每行的字节数
+/// 高度为 0 或者一行放不下 w 个像素时返回 None, 通常是分辨率已经改变
+fn stride(len: usize, w: usize, h: usize) -> Option<usize> {
+    let stride = len.checked_div(h)?;
+    (stride >= w * 4).then_some(stride)
+}
+
+/// 所有显示器的位置
+fn layout() -> io::Result<Vec<DisplayInfo>> {
+    let sizes: Vec<(usize, usize)> = Display::all()?
+        .iter()
+        .map(|d| (d.width(), d.height()))
+        .collect();
+    Ok(place(&sizes, &monitors()))
+}
+
+/// scrap 不提供显示器的位置, 按尺寸和主显示器标志与系统列出的显示器对应
+/// 两者的顺序不一定相同, scrap 的第一个显示器是主显示器, 尺寸相同时按系统列出的顺序
+/// 有显示器对应不上或没有位置信息时从左到右排列, 第一个是主显示器
+fn place(sizes: &[(usize, usize)], monitors: &[DisplayInfo]) -> Vec<DisplayInfo> {
+    let mut used = vec![false; monitors.len()];
+    let matched: Option<Vec<DisplayInfo>> = sizes
+        .iter()
+        .enumerate()
+        .map(|(i, &(w, h))| {
+            let (j, _) = monitors
+                .iter()
+                .enumerate()
+                .filter(|(j, m)| !used[*j] && (m.w as usize, m.h as usize) == (w, h))
+                .min_by_key(|(_, m)| m.primary != (i == 0))?;
+            used[j] = true;
+            Some(monitors[j])
+        })
+        .collect();
+    if let Some(displays) = matched {
+        return displays;
+    }
+    let mut next = 0;
+    sizes
+        .iter()
+        .enumerate()
+        .map(|(i, &(w, h))| {
+            next += w as i32;
+            DisplayInfo {
+                x: next - w as i32,
+                y: 0,
+                w: w as u16,
+                h: h as u16,
+                primary: i == 0,
+            }
+        })
+        .collect()
+}
+
+fn primary(displays: &[DisplayInfo]) -> usize {
+    displays.iter().position(|d| d.primary).unwrap_or(0)
+}
+
+fn rect_of(d: &DisplayInfo) -> Rect {
+    Rect {
+        x: d.x,
+        y: d.y,
+        w: d.w as usize,
+        h: d.h as usize,
+    }
+}
+
+/// 包含所有显示器的最小区域
+fn bounding(displays: &[DisplayInfo]) -> Rect {
+    let x = displays.iter().map(|d| d.x).min().unwrap_or(0);
+    let y = displays.iter().map(|d| d.y).min().unwrap_or(0);
+    let right = displays.iter().map(|d| d.x + d.w as i32).max().unwrap_or(0);
+    let bottom = displays.iter().map(|d| d.y + d.h as i32).max().unwrap_or(0);
+    Rect {
+        x,
+        y,
+        w: (right - x) as usize,
+        h: (bottom - y) as usize,
+    }
+}
+
+/// 系统列出的显示器及其在虚拟桌面中的位置
+/// 进程没有声明 DPI 感知时坐标经过缩放, 与截屏的尺寸对应不上, 此时退回从左到右排列
+#[cfg(windows)]
+fn monitors() -> Vec<DisplayInfo> {
+    use std::ptr::null_mut;
+    use winapi::shared::minwindef::{BOOL, DWORD, LPARAM, TRUE};
+    use winapi::shared::windef::{HDC, HMONITOR, LPRECT};
+    use winapi::um::winuser::{
+        EnumDisplayMonitors, GetMonitorInfoW, MONITORINFO, MONITORINFOF_PRIMARY,
+    };
+
+    unsafe extern "system" fn callback(monitor: HMONITOR, _: HDC, _: LPRECT, data: LPARAM) -> BOOL {
+        let monitors = &mut *(data as *mut Vec<DisplayInfo>);
+        let mut info: MONITORINFO = std::mem::zeroed();
+        info.cbSize = std::mem::size_of::<MONITORINFO>() as DWORD;
+        if GetMonitorInfoW(monitor, &mut info) != 0 {
+            let r = info.rcMonitor;
+            monitors.push(DisplayInfo {
+                x: r.left,
+                y: r.top,
+                w: (r.right - r.left) as u16,
+                h: (r.bottom - r.top) as u16,
+                primary: info.dwFlags & MONITORINFOF_PRIMARY != 0,
+            });
         }
+        TRUE
+    }
+
+    let mut monitors: Vec<DisplayInfo> = Vec::new();
+    unsafe {
+        EnumDisplayMonitors(
+            null_mut(),
+            null_mut(),
+            Some(callback),
+            &mut monitors as *mut _ as LPARAM,
+        );
+    }
+    monitors
+}
+
+/// 其他平台拿不到显示器的位置
+#[cfg(not(windows))]
+fn monitors() -> Vec<DisplayInfo> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(x: i32, y: i32, w: u16, h: u16, primary: bool) -> DisplayInfo {
+        DisplayInfo {
+            x,
+            y,
+            w,
+            h,
+            primary,
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        // 副显示器在主显示器左上方
+        let displays = place(
+            &[(1920, 1080), (1280, 1024)],
+            &[
+                monitor(0, 0, 1920, 1080, true),
+                monitor(-1280, -200, 1280, 1024, false),
+            ],
+        );
+        assert_eq!(primary(&displays), 0);
+        assert_eq!(
+            bounding(&displays),
+            Rect {
+                x: -1280,
+                y: -200,
+                w: 3200,
+                h: 1280
+            }
+        );
+
+        // 没有位置信息时从左到右排列
+        let displays = place(&[(1280, 1024), (1920, 1080)], &[]);
+        assert_eq!((displays[1].x, displays[1].y), (1280, 0));
+        assert!(displays[0].primary && !displays[1].primary);
+        assert_eq!(bounding(&displays).w, 3200);
+        assert_eq!(bounding(&[]), Rect::default());
+    }
+
+    #[test]
+    fn test_stride() {
+        // 每行末尾有填充
+        assert_eq!(stride(2 * 32, 7, 2), Some(32));
+        assert_eq!(stride(8 * 6, 2, 6), Some(8));
+        // 分辨率变大后的旧画面和高度为 0
+        assert_eq!(stride(8 * 6, 3, 6), None);
+        assert_eq!(stride(64, 2, 0), None);
+    }
+
+    #[test]
+    fn test_layout_order() {
+        // 系统列出的顺序与 scrap 不同, 按尺寸和主显示器对应
+        let monitors = [
+            monitor(1920, 0, 2560, 1440, false),
+            monitor(-1280, 56, 1280, 1024, false),
+            monitor(0, 0, 1920, 1080, true),
+        ];
+        let displays = place(&[(1920, 1080), (1280, 1024), (2560, 1440)], &monitors);
+        assert_eq!(displays, [monitors[2], monitors[1], monitors[0]]);
+
+        // 尺寸相同时主显示器对应 scrap 的第一个
+        let monitors = [
+            monitor(-1920, 0, 1920, 1080, false),
+            monitor(0, 0, 1920, 1080, true),
+        ];
+        let displays = place(&[(1920, 1080), (1920, 1080)], &monitors);
+        assert_eq!(displays, [monitors[1], monitors[0]]);
+
+        // 尺寸对应不上时从左到右排列
+        let displays = place(&[(1920, 1080), (1280, 1024)], &monitors);
+        assert_eq!((displays[1].x, displays[1].y), (1920, 0));
+        assert!(displays[0].primary && !displays[1].primary);
     }
 }
@@ -1,88 +1,306 @@
+use crate::cursor::Cursor;
+use crate::file::FileService;
 use crate::key_mouse;
+use crate::pacing;
+use crate::pacing::Pacer;
+use crate::pipeline;
+use crate::pipeline::Captured;
+use crate::pipeline::Feed;
+use crate::pipeline::Job;
+use crate::pipeline::Stage;
+use crate::pipeline::Stamp;
+use crate::region::Region;
+use crate::scale;
 use crate::screen::Cap;
+use crate::screen::Rect;
+use crate::session::Session;
+use crate::session::SessionError;
+use communication::clipboard;
+use communication::clipboard::ClipboardSync;
+use communication::clipboard::Policy;
+use communication::clipboard::Side;
+use communication::clipboard::SystemClipboard;
+use communication::convert::ColorSpace;
+use communication::convert::PixelFormat;
+use communication::display;
+use communication::file;
+use communication::file::FileMsg;
+use communication::heartbeat;
+use communication::mux;
+use communication::mux::MuxReader;
+use communication::mux::MuxSender;
+use communication::record;
+use communication::record::Recorder;
+use communication::snapshot;
+use communication::TOKEN_LEN;
 use enigo::Enigo;
 use enigo::KeyboardControllable;
 use enigo::MouseControllable;
-use flate2::write::DeflateEncoder;
-use flate2::Compression;
-use rayon::prelude::*;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
 use std::hash::Hasher;
 use std::io::Read;
 use std::io::Write;
 use std::net::TcpListener;
 use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc::channel;
+use std::sync::mpsc::sync_channel;
 use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::mpsc::Sender;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TryRecvError;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+
+// 光标轮询间隔
+const CURSOR_INTERVAL: Duration = Duration::from_millis(15);
+// 剪贴板轮询间隔
+const CLIPBOARD_INTERVAL: Duration = Duration::from_millis(500);
+// 没有下载时等待文件消息的超时
+const FILE_IDLE: Duration = Duration::from_millis(200);
+// 有下载在计算摘要时检查是否完成的间隔
+const HASH_POLL: Duration = Duration::from_millis(20);
+// 等待处理的文件消息数, 写盘慢时阻塞接收, 让client放慢上传
+const FILE_QUEUE: usize = 16;
+// 等待认证信息的超时
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// 断开后 token 的有效期
+const TOKEN_TTL: Duration = Duration::from_secs(60);
+
+type SharedClipboard = Arc<Mutex<ClipboardSync<SystemClipboard>>>;
+
+/// 接收线程和截屏线程共享的画面状态
+struct View {
+    // 配置的共享范围和最大帧率
+    region: Region,
+    max_fps: u32,
+    // 当前截取的区域和发给client的画面尺寸, 用于换算鼠标坐标
+    area: RwLock<(Rect, (usize, usize))>,
+    // client请求切换的显示器, 由截屏线程取走
+    select: Mutex<Option<u8>>,
+    // client请求的最大画面尺寸, (0, 0) 为原始分辨率
+    resolution: Mutex<Option<(usize, usize)>>,
+    // client请求的颜色空间和像素格式
+    color: Mutex<Option<ColorSpace>>,
+    format: Mutex<Option<PixelFormat>>,
+    // 按需发送时client还在等待的帧数, None 为持续发送
+    requests: Mutex<Option<usize>>,
+    requested: Condvar,
+    // client有输入, 画面可能马上变化
+    input: AtomicBool,
+    // 关键帧间隔, 和client请求的关键帧
+    keyframe: Option<Duration>,
+    refresh: AtomicBool,
+    // client请求的完整画面
+    snapshot: AtomicBool,
+    // 设置了录像目录时记录发送的画面和收到的键鼠指令
+    recorder: Option<Recorder>,
+}
+
+// 按需发送时最多累积的请求数
+const MAX_REQUESTS: usize = 4;
+// 截取完整画面时最多重新创建截屏的次数
+const SNAPSHOT_RELOADS: u32 = 3;
 
 pub struct Server {
-    port: u16,    // 默认端口为80
-    pwd: [u8; 8], // 存储密码的哈希值
+    port: u16,                  // 默认端口为80
+    pwd: [u8; 8],               // 存储密码的哈希值
+    roots: Vec<PathBuf>,        // 允许文件传输访问的根目录
+    timeout: Duration,          // 超过这个时间没有收到client的消息时断开
+    region: Region,             // 共享的屏幕范围
+    max_fps: u32,               // 最大帧率
+    keyframe: Option<Duration>, // 关键帧间隔
 }
 
 impl Server {
     // 创建一个新的 Server 实例
-    pub fn new(port: u16, pwd: String) -> Self {
+    pub fn new(port: u16, pwd: String, roots: Vec<PathBuf>) -> Self {
         // 直接计算密码哈希并存储
         let mut hasher = DefaultHasher::new();
         hasher.write(pwd.as_bytes());
         let pk = hasher.finish();
         let pwd = pk.to_be_bytes();
 
-        Self { port, pwd }
+        Self {
+            port,
+            pwd,
+            roots,
+            timeout: heartbeat::timeout(),
+            region: Region::from_env(),
+            max_fps: pacing::max_fps(),
+            keyframe: pacing::keyframe_interval(),
+        }
     }
 
     // 处理密码验证和处理连接的主要函数
     pub fn run(&self) {
         // 启动 TCP 监听器并获取用于从中接收 TCP 流的接收器
         let rx = self.run_tcp_listeners();
+        if self.region != Region::Full {
+            println!("Sharing {:?}", self.region);
+        }
+        // 当前或上一个会话的 token, 认证成功时立即更新
+        let resumable: Arc<Mutex<Option<Resumable>>> = Arc::new(Mutex::new(None));
+        // 正在进行的会话, 同一时间只有一个
+        let mut current: Option<JoinHandle<()>> = None;
 
         // 循环接收 TCP 流并处理
-        loop {
-            match rx.recv() {
-                Ok(mut stream) => {
-                    // 检查密码是否正确
-                    if let Err(_) = self.check_pwd(&mut stream) {
+        while let Ok(mut stream) = rx.recv() {
+            let auth = match self.read_auth(&mut stream) {
+                Some(auth) => auth,
+                None => continue,
+            };
+            match auth {
+                Auth::Password(check) => {
+                    // 检查密码与哈希是否匹配
+                    if check != self.pwd {
+                        println!("Password error");
+                        let _ = stream.write_all(&[communication::AUTH_BAD_PASSWORD]);
                         continue;
                     }
-
-                    // 发送成功信号给客户端
-                    if let Err(_) = stream.write_all(&[1]) {
-                        continue;
+                    // 新的登录等待当前会话结束
+                    if let Some(handle) = current.take() {
+                        let _ = handle.join();
                     }
+                }
+                Auth::Token(check) => {
+                    let session = match &*resumable.lock().unwrap() {
+                        Some(r) if r.token == check && r.valid() => r.session.clone(),
+                        _ => {
+                            println!("Session expired");
+                            let _ = stream.write_all(&[communication::AUTH_BAD_TOKEN]);
+                            continue;
+                        }
+                    };
+                    // 旧连接可能还没有超时, 直接关闭并接管, 不必等待心跳超时
+                    session.close();
+                    if let Some(handle) = current.take() {
+                        let _ = handle.join();
+                    }
+                    println!("Session resumed");
+                }
+            }
+            // 每次认证都换新的 token, 截获的旧 token 不能再使用
+            let token = match new_token() {
+                Ok(token) => token,
+                Err(e) => {
+                    eprintln!("Cannot generate token: {}", e);
+                    continue;
+                }
+            };
+            let _ = stream.set_read_timeout(None);
+            let mut reply = vec![communication::AUTH_OK];
+            reply.extend_from_slice(&token);
+            if stream.write_all(&reply).is_err() {
+                continue;
+            }
+            current = self.start_session(stream, token, resumable.clone());
+        }
+    }
 
-                    // 克隆一个 TCP 流以用于不同的线程
-                    let ss = stream.try_clone().unwrap();
+    // 在新线程中运行会话, 结束后一段时间内可以用 token 恢复
+    fn start_session(
+        &self,
+        stream: TcpStream,
+        token: [u8; TOKEN_LEN],
+        resumable: Arc<Mutex<Option<Resumable>>>,
+    ) -> Option<JoinHandle<()>> {
+        // 数据块较小, 不等待合并, 减少输入延迟
+        let _ = stream.set_nodelay(true);
 
-                    // 创建两个线程，一个用于处理屏幕流，另一个用于接收和播放事件
-                    let th1 = std::thread::spawn(move || {
-                        if let Err(e) = std::panic::catch_unwind(|| {
-                            screen_stream(ss);
-                        }) {
-                            eprintln!("{:?}", e);
-                        }
-                    });
+        // client断开而没有关闭连接时, 写操作也在超时后返回
+        let _ = stream.set_write_timeout(Some(self.timeout));
 
-                    let th2 = std::thread::spawn(move || {
-                        if let Err(e) = std::panic::catch_unwind(|| {
-                            recv_and_play_events(stream);
-                        }) {
-                            eprintln!("{:?}", e);
-                        }
-                    });
+        // 克隆 TCP 流以用于不同的线程
+        let (ss, cs) = match (stream.try_clone(), stream.try_clone()) {
+            (Ok(ss), Ok(cs)) => (ss, cs),
+            _ => return None,
+        };
+        // 两个线程共享会话状态, 一方出错时另一方随之结束
+        let session = Arc::new(Session::new(cs));
 
-                    // 等待两个线程结束
-                    th1.join().unwrap();
-                    th2.join().unwrap();
-                    println!("Break !");
-                }
-                // 接收器错误，停止运行
-                Err(_) => {
-                    return;
+        // 剪贴板同步, 在client发送策略之前不同步
+        let clipboard = Arc::new(Mutex::new(ClipboardSync::new(
+            SystemClipboard::new(),
+            Side::Server,
+            Policy::Off,
+            clipboard::DEFAULT_MAX_SIZE,
+        )));
+        let sc = clipboard.clone();
+
+        // 文件消息由接收线程转交给文件传输线程
+        let (file_tx, file_rx) = sync_channel::<FileMsg>(FILE_QUEUE);
+        let roots = self.roots.clone();
+        // 接收线程把 PING 转交给心跳线程回复
+        let (ping_tx, ping_rx) = channel::<u64>();
+        let timeout = self.timeout;
+        let view = Arc::new(View {
+            region: self.region.clone(),
+            max_fps: self.max_fps,
+            area: RwLock::new((Rect::default(), (0, 0))),
+            select: Mutex::new(None),
+            resolution: Mutex::new(None),
+            color: Mutex::new(None),
+            format: Mutex::new(None),
+            requests: Mutex::new(None),
+            requested: Condvar::new(),
+            input: AtomicBool::new(false),
+            keyframe: self.keyframe,
+            refresh: AtomicBool::new(false),
+            snapshot: AtomicBool::new(false),
+            recorder: record::start("server", |s| println!("{}", s)),
+        });
+        let v1 = view.clone();
+
+        // 创建两个线程，一个用于处理屏幕流，另一个用于接收和播放事件
+        let s1 = session.clone();
+        let th1 = std::thread::spawn(move || {
+            match std::panic::catch_unwind(|| {
+                screen_stream(ss, sc, file_rx, roots, ping_rx, &v1, &s1)
+            }) {
+                Ok(res) => s1.finish(res),
+                Err(e) => s1.end(SessionError::from_panic(e)),
+            }
+            // 已经通知过client, 关闭连接让接收线程结束
+            s1.close();
+        });
+
+        let s2 = session.clone();
+        let th2 = std::thread::spawn(move || {
+            match std::panic::catch_unwind(|| {
+                recv_and_play_events(stream, clipboard, file_tx, ping_tx, timeout, &view, &s2)
+            }) {
+                Ok(res) => s2.finish(res),
+                Err(e) => s2.end(SessionError::from_panic(e)),
+            }
+        });
+
+        *resumable.lock().unwrap() = Some(Resumable {
+            token,
+            expire: None,
+            session: session.clone(),
+        });
+        Some(std::thread::spawn(move || {
+            // 等待两个线程结束
+            let _ = th1.join();
+            let _ = th2.join();
+            println!("Session ended: {}", session.reason());
+            // 断开后一段时间内可以用 token 恢复会话, 已被新的会话替换时不变
+            if let Some(r) = resumable.lock().unwrap().as_mut() {
+                if r.token == token {
+                    r.expire = Some(Instant::now() + TOKEN_TTL);
                 }
             }
-        }
+        }))
     }
 
     // 启动 TCP 监听器并返回用于接收 TCP 流的接收器
@@ -122,100 +340,282 @@ impl Server {
         }
     }
 
-    // 检查密码是否正确
-    fn check_pwd(&self, stream: &mut TcpStream) -> Result<(), ()> {
-        let mut check = [0u8; 8];
-
-        if let Ok(_) = stream.read_exact(&mut check) {
-            // 检查密码与哈希是否匹配
-            if check != self.pwd {
-                println!("Password error");
-                let _ = stream.write_all(&[2]); // 发送错误消息给客户端
-                return Err(());
+    // 读取认证请求, 格式错误或超时时返回 None
+    fn read_auth(&self, stream: &mut TcpStream) -> Option<Auth> {
+        // 不发送认证信息的连接不能一直占用server
+        let _ = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT));
+        let mut kind = [0u8];
+        let auth = match stream.read_exact(&mut kind).map(|_| kind[0]) {
+            Ok(communication::AUTH_PASSWORD) => {
+                let mut check = [0u8; 8];
+                stream
+                    .read_exact(&mut check)
+                    .ok()
+                    .map(|_| Auth::Password(check))
             }
-        } else {
+            Ok(communication::AUTH_TOKEN) => {
+                let mut check = [0u8; TOKEN_LEN];
+                stream
+                    .read_exact(&mut check)
+                    .ok()
+                    .map(|_| Auth::Token(check))
+            }
+            _ => None,
+        };
+        if auth.is_none() {
             println!("Request error");
-            return Err(());
         }
+        auth
+    }
+}
 
-        Ok(())
+// client的认证请求
+enum Auth {
+    Password([u8; 8]),
+    Token([u8; TOKEN_LEN]),
+}
+
+// 可以恢复的会话
+struct Resumable {
+    token: [u8; TOKEN_LEN],
+    // 会话进行中为 None, 结束后开始计时
+    expire: Option<Instant>,
+    session: Arc<Session>,
+}
+
+impl Resumable {
+    fn valid(&self) -> bool {
+        self.expire.is_none_or(|expire| Instant::now() < expire)
     }
 }
 
+/// 随机的会话 token, 来自操作系统的安全随机数
+fn new_token() -> Result<[u8; TOKEN_LEN], getrandom::Error> {
+    let mut token = [0u8; TOKEN_LEN];
+    getrandom::getrandom(&mut token)?;
+    Ok(token)
+}
+
 /// 从接收的信息，来模拟client的键鼠移动
-fn recv_and_play_events(mut stream: TcpStream) {
-    let mut cmd = [0u8];
-    let mut move_cmd = [0u8; 4];
+fn recv_and_play_events(
+    stream: TcpStream,
+    clipboard: SharedClipboard,
+    files: SyncSender<FileMsg>,
+    pings: Sender<u64>,
+    timeout: Duration,
+    view: &View,
+    session: &Session,
+) -> Result<(), SessionError> {
+    // client每隔 heartbeat::INTERVAL 发送一次 PING, 超时说明连接已断开
+    stream.set_read_timeout(Some(timeout))?;
+    // 剪贴板是最大的client消息
+    let mut reader = MuxReader::new(stream, clipboard::DEFAULT_MAX_SIZE + 16);
     let mut enigo = Enigo::new();
-    while let Ok(_) = stream.read_exact(&mut cmd) {
-        match cmd[0] {
-            communication::KEY_UP => {
-                stream.read_exact(&mut cmd).unwrap();
-                if let Some(key) = key_mouse::key_to_enigo(cmd[0]) {
-                    enigo.key_up(key);
+    while !session.stopped() {
+        let (channel, data) = reader.recv()?;
+        match channel {
+            mux::INPUT => {
+                if let Err(e) = record::record(view.recorder.as_ref(), mux::INPUT, &data) {
+                    println!("{}", e);
                 }
+                play_input(&mut enigo, &data, &view.area.read().unwrap())?;
+                view.input.store(true, Ordering::Relaxed);
             }
-            communication::KEY_DOWN => {
-                stream.read_exact(&mut cmd).unwrap();
-                if let Some(key) = key_mouse::key_to_enigo(cmd[0]) {
-                    enigo.key_down(key);
+            mux::CONTROL => match heartbeat::decode(&data) {
+                Some((communication::PING, timestamp)) => {
+                    // 心跳线程已结束, 会话正在关闭
+                    if pings.send(timestamp).is_err() {
+                        return Ok(());
+                    }
                 }
+                _ => match data[..] {
+                    [communication::CLIPBOARD_POLICY, policy] => {
+                        let policy = Policy::from_u8(policy).ok_or_else(|| {
+                            SessionError::Protocol(format!("bad clipboard policy {}", policy))
+                        })?;
+                        clipboard.lock().unwrap().set_policy(policy);
+                    }
+                    [communication::SELECT_DISPLAY, n] => {
+                        *view.select.lock().unwrap() = Some(n);
+                    }
+                    [communication::SET_RESOLUTION, w1, w2, h1, h2] => {
+                        let w = u16::from_be_bytes([w1, w2]) as usize;
+                        let h = u16::from_be_bytes([h1, h2]) as usize;
+                        *view.resolution.lock().unwrap() = Some((w, h));
+                    }
+                    [communication::SET_COLOR_SPACE, space] => {
+                        let space = ColorSpace::from_u8(space).ok_or_else(|| {
+                            SessionError::Protocol(format!("bad color space {}", space))
+                        })?;
+                        *view.color.lock().unwrap() = Some(space);
+                    }
+                    [communication::SET_PIXEL_FORMAT, format] => {
+                        let format = PixelFormat::from_u8(format).ok_or_else(|| {
+                            SessionError::Protocol(format!("bad pixel format {}", format))
+                        })?;
+                        *view.format.lock().unwrap() = Some(format);
+                    }
+                    [communication::FRAME_MODE, mode] => {
+                        *view.requests.lock().unwrap() = match mode {
+                            communication::FRAME_PUSH => None,
+                            communication::FRAME_PULL => Some(0),
+                            _ => {
+                                return Err(SessionError::Protocol(format!(
+                                    "bad frame mode {}",
+                                    mode
+                                )))
+                            }
+                        };
+                        view.requested.notify_one();
+                    }
+                    [communication::REFRESH] => {
+                        view.refresh.store(true, Ordering::Relaxed);
+                    }
+                    [communication::SNAPSHOT] => {
+                        view.snapshot.store(true, Ordering::Relaxed);
+                        view.requested.notify_one();
+                    }
+                    [communication::FRAME_REQUEST] => {
+                        if let Some(n) = view.requests.lock().unwrap().as_mut() {
+                            *n = (*n + 1).min(MAX_REQUESTS);
+                        }
+                        view.requested.notify_one();
+                    }
+                    _ => {
+                        return Err(SessionError::Protocol(format!(
+                            "unknown control message {:?}",
+                            data.first()
+                        )))
+                    }
+                },
+            },
+            mux::CLIPBOARD => {
+                clipboard.lock().unwrap().apply(&data);
             }
-            communication::MOUSE_KEY_UP => {
-                stream.read_exact(&mut cmd).unwrap();
-                if let Some(key) = key_mouse::mouse_to_engin(cmd[0]) {
-                    enigo.mouse_up(key);
+            mux::FILE => {
+                if data.len() > file::MAX_MSG_SIZE {
+                    return Err(SessionError::Protocol("file message too large".to_string()));
                 }
-            }
-            communication::MOUSE_KEY_DOWN => {
-                stream.read_exact(&mut cmd).unwrap();
-                if let Some(key) = key_mouse::mouse_to_engin(cmd[0]) {
-                    enigo.mouse_down(key);
+                let msg = FileMsg::decode(&data)
+                    .ok_or_else(|| SessionError::Protocol("bad file message".to_string()))?;
+                // 文件线程已结束, 会话正在关闭
+                if files.send(msg).is_err() {
+                    return Ok(());
                 }
             }
-            communication::MOUSE_WHEEL_UP => {
-                enigo.mouse_scroll_y(-2);
+            _ => {
+                return Err(SessionError::Protocol(format!(
+                    "unexpected message on channel {}",
+                    channel
+                )))
             }
-            communication::MOUSE_WHEEL_DOWN => {
-                enigo.mouse_scroll_y(2);
+        }
+    }
+    Ok(())
+}
+
+/// 模拟一条键鼠指令, 指令无效时返回错误
+/// 鼠标坐标为client画面中的坐标, 换算为虚拟桌面坐标
+fn play_input(
+    enigo: &mut Enigo,
+    cmd: &[u8],
+    area: &(Rect, (usize, usize)),
+) -> Result<(), SessionError> {
+    match *cmd {
+        [communication::KEY_UP, key] => {
+            if let Some(key) = key_mouse::key_to_enigo(key) {
+                enigo.key_up(key);
             }
-            communication::MOVE => {
-                stream.read_exact(&mut move_cmd).unwrap();
-                let x = ((move_cmd[0] as i32) << 8) | (move_cmd[1] as i32);
-                let y = ((move_cmd[2] as i32) << 8) | (move_cmd[3] as i32);
-                enigo.mouse_move_to(x, y);
+        }
+        [communication::KEY_DOWN, key] => {
+            if let Some(key) = key_mouse::key_to_enigo(key) {
+                enigo.key_down(key);
             }
-            _ => {
-                return;
+        }
+        [communication::MOUSE_KEY_UP, key] => {
+            if let Some(key) = key_mouse::mouse_to_engin(key) {
+                enigo.mouse_up(key);
             }
         }
+        [communication::MOUSE_KEY_DOWN, key] => {
+            if let Some(key) = key_mouse::mouse_to_engin(key) {
+                enigo.mouse_down(key);
+            }
+        }
+        [communication::MOUSE_WHEEL_UP] => {
+            enigo.mouse_scroll_y(-2);
+        }
+        [communication::MOUSE_WHEEL_DOWN] => {
+            enigo.mouse_scroll_y(2);
+        }
+        [communication::MOVE, x1, x2, y1, y2] => {
+            let x = ((x1 as usize) << 8) | (x2 as usize);
+            let y = ((y1 as usize) << 8) | (y2 as usize);
+            let (x, y) = to_desktop(x, y, area);
+            enigo.mouse_move_to(x, y);
+        }
+        [communication::KEY_COMBO, n, ref keys @ ..]
+            if n as usize == keys.len() && keys.len() <= communication::MAX_COMBO_KEYS =>
+        {
+            play_combo(enigo, keys);
+        }
+        _ => {
+            return Err(SessionError::Protocol(format!(
+                "bad input command {:?}",
+                cmd.first()
+            )))
+        }
     }
+    Ok(())
 }
 
-/**
- * 编码数据header
- */
-#[inline]
-fn encode(data_len: usize, res: &mut [u8]) {
-    res[0] = (data_len >> 16) as u8;
-    res[1] = (data_len >> 8) as u8;
-    res[2] = data_len as u8;
+/// client画面坐标换算为虚拟桌面坐标, 限制在截取的区域内
+fn to_desktop(x: usize, y: usize, area: &(Rect, (usize, usize))) -> (i32, i32) {
+    let (rect, (w, h)) = *area;
+    let x = x.min(w.saturating_sub(1)) * rect.w / w.max(1);
+    let y = y.min(h.saturating_sub(1)) * rect.h / h.max(1);
+    (x as i32 + rect.x, y as i32 + rect.y)
 }
 
-/*
-图像字节序
-+------------+
-|     24     |
-+------------+
-|   length   |
-+------------+
-|   data     |
-+------------+
-length: 数据长度
-data: 数据
-*/
-fn screen_stream(mut stream: TcpStream) {
-    let mut cap = Cap::new();
+/// 虚拟桌面坐标换算为client画面坐标
+fn to_frame(x: i32, y: i32, area: &(Rect, (usize, usize))) -> (i32, i32) {
+    let (rect, (w, h)) = *area;
+    let x = (x - rect.x) as i64 * w as i64 / rect.w.max(1) as i64;
+    let y = (y - rect.y) as i64 * h as i64 / rect.h.max(1) as i64;
+    (x as i32, y as i32)
+}
+
+/// 原子地模拟一个组合键: 按顺序按下所有键, 再逆序放开
+fn play_combo(enigo: &mut Enigo, keys: &[u8]) {
+    // windows 上 Ctrl+Alt+Del 是安全注意序列, 模拟按键无法触发, 只能通过 SendSAS
+    #[cfg(windows)]
+    if key_mouse::is_sas(keys) && key_mouse::send_sas() {
+        return;
+    }
+    let keys: Vec<enigo::Key> = keys
+        .iter()
+        .filter_map(|k| key_mouse::key_to_enigo(*k))
+        .collect();
+    for key in keys.iter() {
+        enigo.key_down(*key);
+    }
+    for key in keys.iter().rev() {
+        enigo.key_up(*key);
+    }
+}
+
+fn screen_stream(
+    mut stream: TcpStream,
+    clipboard: SharedClipboard,
+    files: Receiver<FileMsg>,
+    roots: Vec<PathBuf>,
+    pings: Receiver<u64>,
+    view: &View,
+    session: &Session,
+) -> Result<(), SessionError> {
+    let mut cap =
+        Cap::new(view.region.clone()).map_err(|e| SessionError::Capture(e.to_string()))?;
+    *view.area.write().unwrap() = (cap.rect(), cap.wh());
 
     let (w, h) = cap.wh();
 
@@ -225,57 +625,355 @@ fn screen_stream(mut stream: TcpStream) {
     meta[1] = w as u8;
     meta[2] = (h >> 8) as u8;
     meta[3] = h as u8;
-    if let Err(_) = stream.write_all(&meta) {
-        return;
+    stream.write_all(&meta)?;
+
+    // 之后的消息都通过 mux 发送
+    let mux = MuxSender::start(stream);
+    let stop = session.stop_flag();
+    std::thread::scope(|s| {
+        s.spawn(|| session.finish(cursor_stream(mux.clone(), stop, view)));
+        s.spawn(|| session.finish(clipboard_stream(mux.clone(), stop, &clipboard)));
+        s.spawn(|| session.finish(file_stream(mux.clone(), stop, files, roots)));
+        s.spawn(|| session.finish(heartbeat_stream(mux.clone(), stop, pings)));
+        session.finish(frame_stream(&mut cap, &mux, stop, view));
+    });
+
+    // 告诉client会话结束的原因
+    let mut end = vec![communication::SESSION_END];
+    end.extend_from_slice(session.reason().as_bytes());
+    mux.send(mux::CONTROL, &end)?;
+    mux.flush()?;
+    Ok(())
+}
+
+/// 图像帧: 关键帧为完整的 I420 或 RGB 数据, 其他为与上一帧的异或, 均经过 deflate 压缩
+/// 第一帧, client请求刷新和每隔一段时间发送关键帧
+/// 分辨率变化, 切换显示器或client请求的尺寸变化时先发送新的尺寸, 再从完整的一帧开始
+/// 画面比请求的尺寸大时先缩小再编码
+/// 按需发送时只在client请求后截屏, 每次请求发送一帧变化的画面
+/// client请求完整画面时用截到的原始画面单独发送一次, 按需发送时不需要等待帧请求
+/// 截屏在当前线程, 转换, 比较和压缩由 pipeline 在其他线程进行
+fn frame_stream(
+    cap: &mut Cap,
+    mux: &MuxSender,
+    stop: &AtomicBool,
+    view: &View,
+) -> Result<(), SessionError> {
+    pipeline::run(mux, view.recorder.as_ref(), &|| unsent(view), |feed| {
+        capture_stream(cap, mux, feed, stop, view)
+    })
+}
+
+fn capture_stream(
+    cap: &mut Cap,
+    mux: &MuxSender,
+    feed: Feed,
+    stop: &AtomicBool,
+    view: &View,
+) -> Result<(), SessionError> {
+    let mut pacer = Pacer::new(view.max_fps);
+    let (mut w, mut h) = cap.wh();
+    let mut rect = cap.rect();
+    // 发送的尺寸和client请求的最大尺寸
+    let (mut ow, mut oh) = (w, h);
+    let mut limit = (0, 0);
+    // 编码使用的颜色空间和像素格式, 以及已经告诉client的
+    let mut space = ColorSpace::default();
+    let mut format = PixelFormat::I420;
+    let mut announced = None;
+    // 已经发给client的显示器列表
+    let mut listed = Vec::new();
+    let mut first = true;
+    // 上一个关键帧的时间
+    let mut keyed = Instant::now();
+    // 为了截取完整画面重新创建截屏的次数
+    let mut reloads = 0;
+    // 画面不变时不发送, 会话结束由 stop 通知
+    while !stop.load(Ordering::Relaxed) {
+        if !wait_request(view) {
+            continue;
+        }
+        if view.input.swap(false, Ordering::Relaxed) {
+            pacer.changed();
+        }
+        pacer.wait();
+        let select = view.select.lock().unwrap().take();
+        if let Some(n) = select {
+            cap.select(if n == display::ALL {
+                None
+            } else {
+                Some(n as usize)
+            });
+        }
+        if cap.displays() != listed {
+            listed = cap.displays().to_vec();
+            let mut msg = vec![communication::DISPLAY_LIST];
+            msg.extend_from_slice(&display::encode_list(&listed));
+            mux.send(mux::CONTROL, &msg)?;
+        }
+        let resolution = view.resolution.lock().unwrap().take();
+        if let Some(resolution) = resolution {
+            limit = resolution;
+        }
+        let color = view.color.lock().unwrap().take();
+        if let Some(color) = color {
+            space = color;
+        }
+        let requested = view.format.lock().unwrap().take();
+        if let Some(requested) = requested {
+            format = requested;
+        }
+        // 重新创建截屏后区域可能改变
+        let size = scale::fit(cap.rect().w, cap.rect().h, limit.0, limit.1);
+        if cap.rect() != rect || size != (ow, oh) || announced != Some((space, format)) {
+            rect = cap.rect();
+            (w, h) = cap.wh();
+            (ow, oh) = size;
+            *view.area.write().unwrap() = (rect, size);
+            println!(
+                "Display reconfigured: {}x{} at ({}, {}), sent as {}x{} {:?} {:?}",
+                w, h, rect.x, rect.y, ow, oh, format, space
+            );
+            // 经过流水线发送, 排在旧尺寸的帧之后
+            if !feed.send(Job::Display(display_msg(ow, oh, space, format))) {
+                break;
+            }
+            announced = Some((space, format));
+            first = true;
+        }
+        let stamp = Stamp::now();
+        let mut bgra = feed.buffer();
+        let captured = cap.cap();
+        match captured {
+            Some((frame, stride)) => {
+                if view.snapshot.swap(false, Ordering::Relaxed) {
+                    reloads = 0;
+                    mux.send(mux::VIDEO, &snapshot::encode(w, h, stride, frame))?;
+                }
+                // 只请求了完整画面
+                if *view.requests.lock().unwrap() == Some(0) {
+                    continue;
+                }
+            }
+            // 画面没有变化时截屏接口不返回数据, 重新创建截屏后取完整的画面
+            None if view.snapshot.load(Ordering::Relaxed) => {
+                if reloads < SNAPSHOT_RELOADS {
+                    reloads += 1;
+                    cap.reload();
+                } else {
+                    reloads = 0;
+                    view.snapshot.store(false, Ordering::Relaxed);
+                    mux.send(mux::VIDEO, &snapshot::failed())?;
+                }
+                continue;
+            }
+            None => {}
+        }
+        let keyframe = view.refresh.swap(false, Ordering::Relaxed)
+            || view.keyframe.is_some_and(|k| keyed.elapsed() >= k);
+        let stride = match captured {
+            Some((frame, stride)) if (ow, oh) != (w, h) => {
+                scale::downscale_bgra(frame, w, h, stride, ow, oh, &mut bgra);
+                ow * 4
+            }
+            Some((frame, stride)) => {
+                bgra.extend_from_slice(frame);
+                stride
+            }
+            None => {
+                // 画面没有变化, 把上一帧作为关键帧重新发送
+                if keyframe && !first {
+                    keyed = Instant::now();
+                    if !feed.send(Job::Repeat) {
+                        break;
+                    }
+                    sent(view);
+                }
+                pacer.unchanged();
+                continue;
+            }
+        };
+        feed.record(Stage::Capture, stamp.elapsed());
+        let key = first || keyframe;
+        if key {
+            first = false;
+            keyed = Instant::now();
+        }
+        let captured = Captured {
+            bgra,
+            w: ow,
+            h: oh,
+            stride,
+            space,
+            format,
+            key,
+            stamp,
+        };
+        if !feed.send(Job::Frame(captured)) {
+            break;
+        }
+        sent(view);
+        // 比较阶段丢弃过没有变化的画面时放慢截屏
+        if feed.unchanged() {
+            pacer.unchanged();
+        } else {
+            pacer.changed();
+        }
     }
-    let mut header = [0u8; 3];
-    let mut yuv = Vec::<u8>::new();
-    let mut last = Vec::<u8>::new();
-    // 第一帧
-    let bgra = cap.cap();
-    communication::convert::bgra_to_i420(w, h, bgra, &mut yuv);
-    // 压缩
-    let mut buf = Vec::<u8>::with_capacity(1024 * 4);
-    let mut e = DeflateEncoder::new(buf, Compression::default());
-    e.write_all(&yuv).unwrap();
-    buf = e.reset(Vec::new()).unwrap();
-    (last, yuv) = (yuv, last);
-
-    let clen = buf.len();
-    encode(clen, &mut header);
-    if let Err(_) = stream.write_all(&header) {
-        return;
+    Ok(())
+}
+
+/// 按需发送时已经回应了一个请求
+fn sent(view: &View) {
+    if let Some(n) = view.requests.lock().unwrap().as_mut() {
+        *n = n.saturating_sub(1);
     }
-    if let Err(_) = stream.write_all(&buf) {
-        return;
+}
+
+/// 画面没有变化没有发送, 请求仍然有效
+fn unsent(view: &View) {
+    if let Some(n) = view.requests.lock().unwrap().as_mut() {
+        *n += 1;
+        view.requested.notify_all();
+    }
+}
+
+/// 按需发送时等待client的请求或完整画面的请求, 超时返回 false 以便检查会话是否结束
+fn wait_request(view: &View) -> bool {
+    let requests = view.requests.lock().unwrap();
+    if *requests != Some(0) || view.snapshot.load(Ordering::Relaxed) {
+        return true;
+    }
+    let (requests, _) = view
+        .requested
+        .wait_timeout(requests, heartbeat::INTERVAL)
+        .unwrap();
+    *requests != Some(0) || view.snapshot.load(Ordering::Relaxed)
+}
+
+/// 显示配置变化: VIDEO_DISPLAY w h 颜色空间 像素格式
+fn display_msg(w: usize, h: usize, space: ColorSpace, format: PixelFormat) -> Vec<u8> {
+    let mut msg = vec![communication::VIDEO_DISPLAY];
+    msg.extend_from_slice(&(w as u16).to_be_bytes());
+    msg.extend_from_slice(&(h as u16).to_be_bytes());
+    msg.push(space.to_u8());
+    msg.push(format as u8);
+    msg
+}
+
+/// 回复client的 PING
+fn heartbeat_stream(
+    mux: MuxSender,
+    stop: &AtomicBool,
+    pings: Receiver<u64>,
+) -> Result<(), SessionError> {
+    while !stop.load(Ordering::Relaxed) {
+        match pings.recv_timeout(heartbeat::INTERVAL) {
+            Ok(timestamp) => mux.send(mux::CONTROL, &heartbeat::pong(timestamp))?,
+            Err(RecvTimeoutError::Timeout) => {}
+            // 接收线程已结束
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
     }
-    loop {
-        let bgra = cap.cap();
-        unsafe {
-            yuv.set_len(0);
+    Ok(())
+}
+
+/// 光标位置变化时发送给client, 每种形状只发送一次, 不必为了光标移动发送整帧
+/// 位置为client画面中的坐标
+fn cursor_stream(mux: MuxSender, stop: &AtomicBool, view: &View) -> Result<(), SessionError> {
+    let mut cursor = Cursor::new();
+    let mut sent = HashSet::new();
+    let mut last = None;
+    while !stop.load(Ordering::Relaxed) {
+        std::thread::sleep(CURSOR_INTERVAL);
+        let (mut pos, shape) = match cursor.poll() {
+            Some(state) => state,
+            None => continue,
+        };
+        let (x, y) = to_frame(pos.x as i32, pos.y as i32, &view.area.read().unwrap());
+        pos.x = x.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        pos.y = y.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        if let Some(shape) = shape {
+            if sent.insert(shape.id) {
+                mux.send(
+                    mux::CURSOR,
+                    &cursor_msg(communication::CURSOR_SHAPE, &shape.encode()),
+                )?;
+            }
         }
-        communication::convert::bgra_to_i420(w, h, bgra, &mut yuv);
-        if yuv[..w * h] == last[..w * h] {
+        if last == Some(pos) {
             continue;
         }
-        last.par_iter_mut().zip(yuv.par_iter()).for_each(|(a, b)| {
-            *a = *a ^ *b;
-        });
-        // 压缩
-        unsafe {
-            buf.set_len(0);
-        }
-        e.write_all(&last).unwrap();
-        buf = e.reset(buf).unwrap();
-        (last, yuv) = (yuv, last);
-        // 发送
-        let clen = buf.len();
-        encode(clen, &mut header);
-        if let Err(_) = stream.write_all(&header) {
-            return;
-        }
-        if let Err(_) = stream.write_all(&buf) {
-            return;
+        mux.send(
+            mux::CURSOR,
+            &cursor_msg(communication::CURSOR_POS, &pos.encode()),
+        )?;
+        last = Some(pos);
+    }
+    Ok(())
+}
+
+/// 光标消息: 类型(1) + 数据
+fn cursor_msg(kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(data.len() + 1);
+    msg.push(kind);
+    msg.extend_from_slice(data);
+    msg
+}
+
+/// 本地剪贴板变化时发送给client
+fn clipboard_stream(
+    mux: MuxSender,
+    stop: &AtomicBool,
+    clipboard: &SharedClipboard,
+) -> Result<(), SessionError> {
+    while !stop.load(Ordering::Relaxed) {
+        std::thread::sleep(CLIPBOARD_INTERVAL);
+        let data = clipboard.lock().unwrap().poll();
+        if let Some(data) = data {
+            mux.send(mux::CLIPBOARD, &data)?;
+        }
+    }
+    Ok(())
+}
+
+/// 处理文件消息, 空闲时轮流发送正在下载的数据块
+fn file_stream(
+    mux: MuxSender,
+    stop: &AtomicBool,
+    files: Receiver<FileMsg>,
+    roots: Vec<PathBuf>,
+) -> Result<(), SessionError> {
+    let mut service = FileService::new(roots);
+    while !stop.load(Ordering::Relaxed) {
+        for reply in service.hashed() {
+            mux.send(mux::FILE, &reply.encode())?;
+        }
+        let idle = if service.hashing() {
+            HASH_POLL
+        } else {
+            FILE_IDLE
+        };
+        let msg = if service.sending() {
+            match files.try_recv() {
+                Ok(msg) => Some(msg),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match files.recv_timeout(idle) {
+                Ok(msg) => Some(msg),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        };
+        let reply = match msg {
+            Some(msg) => service.handle(msg),
+            None => service.next_chunk(),
+        };
+        if let Some(reply) = reply {
+            mux.send(mux::FILE, &reply.encode())?;
         }
     }
+    Ok(())
 }
@@ -0,0 +1,177 @@
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::net::Shutdown;
+use std::net::TcpStream;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+/// 会话结束的原因
+#[derive(Debug)]
+pub enum SessionError {
+    // client断开了连接
+    Closed,
+    // 超时没有收到client的消息
+    Timeout,
+    // client发送了不符合协议的消息
+    Protocol(String),
+    // 截屏失败
+    Capture(String),
+    // 会话线程 panic
+    Internal(String),
+    // 其他读写错误
+    Io(io::Error),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Closed => write!(f, "connection closed"),
+            SessionError::Timeout => write!(f, "connection timed out"),
+            SessionError::Protocol(message) => write!(f, "protocol error: {}", message),
+            SessionError::Capture(message) => write!(f, "screen capture failed: {}", message),
+            SessionError::Internal(message) => write!(f, "internal error: {}", message),
+            SessionError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl SessionError {
+    /// catch_unwind 得到的 panic
+    pub fn from_panic(e: Box<dyn Any + Send>) -> Self {
+        let message = match e.downcast::<String>() {
+            Ok(message) => *message,
+            Err(e) => match e.downcast::<&str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "panic".to_string(),
+            },
+        };
+        SessionError::Internal(message)
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl From<io::Error> for SessionError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            // 设置了读超时, 不同平台返回的错误不同
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => SessionError::Timeout,
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => SessionError::Closed,
+            // MuxReader 收到错误的数据块
+            io::ErrorKind::InvalidData => SessionError::Protocol(e.to_string()),
+            _ => SessionError::Io(e),
+        }
+    }
+}
+
+/**
+ * 一个会话的接收线程和发送线程共享的状态
+ * 任何一方出错时记录原因并通知另一方, 只保留最先发生的错误
+ */
+pub struct Session {
+    stop: AtomicBool,
+    reason: Mutex<Option<SessionError>>,
+    stream: TcpStream,
+}
+
+impl Session {
+    pub fn new(stream: TcpStream) -> Self {
+        Session {
+            stop: AtomicBool::new(false),
+            reason: Mutex::new(None),
+            stream,
+        }
+    }
+
+    /// 各个发送线程循环检查的结束标志
+    pub fn stop_flag(&self) -> &AtomicBool {
+        &self.stop
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    /// 结束会话, 已经有原因时忽略, 后发生的错误通常只是结果
+    pub fn end(&self, reason: SessionError) {
+        let mut r = self.reason.lock().unwrap_or_else(|e| e.into_inner());
+        if r.is_none() {
+            *r = Some(reason);
+        }
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// 线程的结果, 出错时结束会话
+    pub fn finish(&self, res: Result<(), SessionError>) {
+        if let Err(e) = res {
+            self.end(e);
+        }
+    }
+
+    /// 结束的原因, 没有出错时为 client 断开
+    pub fn reason(&self) -> String {
+        match &*self.reason.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(reason) => reason.to_string(),
+            None => SessionError::Closed.to_string(),
+        }
+    }
+
+    /// 关闭连接, 阻塞在读写上的线程随之返回
+    pub fn close(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_first_reason_wins() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let session = Session::new(stream);
+        session.finish(Ok(()));
+        assert!(!session.stopped());
+        assert_eq!(session.reason(), "connection closed");
+
+        session.finish(Err(SessionError::Protocol("bad input".to_string())));
+        session.end(SessionError::Closed);
+        assert!(session.stopped());
+        assert_eq!(session.reason(), "protocol error: bad input");
+    }
+
+    #[test]
+    fn test_io_error_kind() {
+        let e = |kind: io::ErrorKind| SessionError::from(io::Error::from(kind));
+        assert!(matches!(e(io::ErrorKind::TimedOut), SessionError::Timeout));
+        assert!(matches!(
+            e(io::ErrorKind::WouldBlock),
+            SessionError::Timeout
+        ));
+        assert!(matches!(
+            e(io::ErrorKind::UnexpectedEof),
+            SessionError::Closed
+        ));
+        assert!(matches!(
+            e(io::ErrorKind::InvalidData),
+            SessionError::Protocol(_)
+        ));
+        assert!(matches!(
+            e(io::ErrorKind::PermissionDenied),
+            SessionError::Io(_)
+        ));
+        let panic = std::panic::catch_unwind(|| panic!("boom {}", 1)).unwrap_err();
+        assert_eq!(
+            SessionError::from_panic(panic).to_string(),
+            "internal error: boom 1"
+        );
+    }
+}